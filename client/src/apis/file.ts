@@ -12,13 +12,41 @@ export interface FileStat {
   created: number;
   modified: number;
   accessed: number;
+  // audio and video files probed by the index job
+  media?: MediaProbe;
+}
+
+export interface MediaStream {
+  index: number;
+  codec_type?: string;
+  codec_name?: string;
+  width?: number;
+  height?: number;
+  channels?: number;
+  bitrate?: number;
+  language?: string;
+  title?: string;
+  default: boolean;
+}
+
+export interface MediaProbe {
+  duration?: number;
+  container?: string;
+  video_codec?: string;
+  audio_codec?: string;
+  width?: number;
+  height?: number;
+  bitrate?: number;
+  streams: MediaStream[];
+  direct_play: boolean;
 }
 
 export async function read_dir(dir: string): Promise<FileStat[]> {
   let resp = await post('/file/read_dir', {
     file: dir
   });
-  return resp.data.files;
+  const media: Record<string, MediaProbe> = resp.data.media || {};
+  return resp.data.files.map((f: FileStat) => ({ ...f, media: media[f.name] }));
 }
 
 export async function delete_file(dir: string, file: string): Promise<boolean> {
@@ -35,6 +63,12 @@ export async function delete_files(dir: string, files: string[]): Promise<boolea
   return resp.status === 0;
 }
 
+// moves or renames `from` to `to`, both relative to the user root
+export async function rename_file(from: string, to: string): Promise<boolean> {
+  let resp = await post('/file/rename', { from, to });
+  return resp.status === 0;
+}
+
 export async function create_dir(dir: string, file: string): Promise<boolean> {
   let resp = await post('/file/create_dir', {
     file: path.join(dir, file)
@@ -96,12 +130,29 @@ export function create_thumbnail_link(dir: string, file: string) {
   const url = new URL('/file/read_image', window.location.origin);
   const file_path = path.join(dir, file);
   const dpr = window.devicePixelRatio || 1;
-  const size = dpr * 200;
+  const size = Math.round(dpr * 200);
   url.searchParams.set('file', file_path);
   url.searchParams.set('resize', size.toString());
   return url.toString();
 }
 
+export function create_video_poster_link(dir: string, file: string) {
+  const url = new URL('/file/video_preview', window.location.origin);
+  const dpr = window.devicePixelRatio || 1;
+  url.searchParams.set('file', path.join(dir, file));
+  url.searchParams.set('kind', 'poster');
+  url.searchParams.set('resize', Math.round(dpr * 200).toString());
+  return url.toString();
+}
+
+// WebVTT track of seek preview thumbnails, its cues point into the sprite sheet
+export function create_video_thumbnails_track_link(dir: string, file: string) {
+  const url = new URL('/file/video_preview', window.location.origin);
+  url.searchParams.set('file', path.join(dir, file));
+  url.searchParams.set('kind', 'vtt');
+  return url.toString();
+}
+
 export function create_compression_download_link(dir: string, file: string) {
   const url = new URL('/file/read_compression', window.location.origin);
   const file_path = path.join(dir, file);
@@ -130,9 +181,40 @@ export async function search_files(keyword: string) {
   return resp.data;
 }
 
-export async function search_files_content(keyword: string) {
+export interface SearchContentFilters {
+  offset?: number;
+  limit?: number;
+  mimes?: string[];
+  min_size?: number;
+  max_size?: number;
+  modified_after?: number;
+  modified_before?: number;
+  folder?: string;
+}
+
+export async function search_files_content(keyword: string, filters: SearchContentFilters = {}) {
   const url = new URL('/file/search_content', window.location.origin);
-  let resp = await post(url.toString(), { keyword }, 'search_files_content');
+  let resp = await post(url.toString(), { keyword, ...filters }, 'search_files_content');
+  return resp.data;
+}
+
+export interface DuplicateGroup {
+  hash: string;
+  size: number;
+  files: string[];
+  wasted: number;
+}
+
+export async function find_duplicates(folder = '', min_size = 0): Promise<{ groups: DuplicateGroup[], total_wasted: number }> {
+  const url = new URL('/file/duplicates', window.location.origin);
+  let resp = await post(url.toString(), { folder, min_size }, 'find_duplicates');
+  return resp.data;
+}
+
+// moves `files` to the trash, files no longer identical to `keep` are skipped
+export async function dedupe(keep: string, files: string[]): Promise<{ trashed: string[], skipped: string[] }> {
+  const url = new URL('/file/dedupe', window.location.origin);
+  let resp = await post(url.toString(), { keep, files }, 'dedupe');
   return resp.data;
 }
 
@@ -201,4 +283,110 @@ export async function upload(
     input.click();
     document.body.removeChild(input);
   });
-}
\ No newline at end of file
+}
+export interface SavedSearchQuery {
+  filename_keyword?: string;
+  content_keyword?: string;
+  mimes?: string[];
+  min_size?: number;
+  max_size?: number;
+  modified_after?: number;
+  modified_before?: number;
+  modified_within_days?: number;
+  folder?: string;
+}
+
+export interface SavedSearch {
+  id: number;
+  username: string;
+  name: string;
+  query: SavedSearchQuery;
+  created_at: string;
+}
+
+export async function list_saved_searches(): Promise<SavedSearch[]> {
+  const url = new URL('/saved_search/list', window.location.origin);
+  let resp = await post(url.toString(), {}, 'list_saved_searches');
+  return resp.data;
+}
+
+// saving with an existing name replaces its query, the search shows up as `@saved/<name>`
+export async function save_search(name: string, query: SavedSearchQuery): Promise<SavedSearch> {
+  const url = new URL('/saved_search/save', window.location.origin);
+  let resp = await post(url.toString(), { name, query }, 'save_search');
+  return resp.data;
+}
+
+export async function delete_saved_search(id: number) {
+  const url = new URL('/saved_search/delete', window.location.origin);
+  let resp = await post(url.toString(), { id }, 'delete_saved_search');
+  return resp.data;
+}
+
+export async function run_saved_search(search: { name?: string, query?: SavedSearchQuery }) {
+  const url = new URL('/saved_search/run', window.location.origin);
+  let resp = await post(url.toString(), search, 'run_saved_search');
+  return resp.data;
+}
+
+export interface MediaInfo {
+  file_path: string;
+  size: number;
+  modified_at: string;
+  taken_at?: number;
+  camera_make?: string;
+  camera_model?: string;
+  lens?: string;
+  latitude?: number;
+  longitude?: number;
+  altitude?: number;
+  width?: number;
+  height?: number;
+  orientation?: number;
+}
+
+export async function get_media_info(file: string): Promise<MediaInfo> {
+  const url = new URL('/file/media_info', window.location.origin);
+  let resp = await post(url.toString(), { file }, 'get_media_info');
+  return resp.data;
+}
+
+export interface PlaybackInfo {
+  media: MediaProbe;
+  // the server can transcode files which can not be played directly
+  transcode: boolean;
+}
+
+export async function get_playback_info(file: string): Promise<PlaybackInfo> {
+  let resp = await post('/file/playback_info', { file }, 'get_playback_info');
+  return resp.data;
+}
+
+export interface SubtitleTrack {
+  // `sidecar:<file name>` or `embedded:<stream index>`
+  id: string;
+  label: string;
+  language?: string;
+  format: string;
+  default: boolean;
+}
+
+export async function list_subtitles(dir: string, file: string): Promise<SubtitleTrack[]> {
+  let resp = await post('/file/subtitles', { file: path.join(dir, file) }, 'list_subtitles');
+  return resp.data;
+}
+
+// WebVTT of a subtitle track, usable as src of a <track> element
+export function create_subtitle_link(dir: string, file: string, track: string) {
+  const url = new URL('/file/read_subtitle', window.location.origin);
+  url.searchParams.set('file', path.join(dir, file));
+  url.searchParams.set('track', track);
+  return url.toString();
+}
+
+// HLS master playlist of a video, the server transcodes segments on demand
+export function create_hls_link(dir: string, file: string) {
+  const url = new URL('/file/hls/master.m3u8', window.location.origin);
+  url.searchParams.set('file', path.join(dir, file));
+  return url.toString();
+}
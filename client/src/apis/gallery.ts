@@ -11,3 +11,80 @@ export function update_index() {
 export function get_job_status() {
   return post('/gallery/get_job_status', {}, 'get_job_status');
 }
+
+export function find_similar_images(file: string, threshold?: number) {
+  return post('/gallery/similar', { file, threshold }, 'find_similar_images');
+}
+
+export function find_near_duplicates(threshold?: number) {
+  return post('/gallery/near_duplicates', { threshold }, 'find_near_duplicates');
+}
+
+export interface TimelinePhoto {
+  file_path: string;
+  file_name: string;
+  size: number;
+  taken_at: number;
+  width?: number;
+  height?: number;
+}
+
+export function get_timeline(granularity: 'year' | 'month' | 'day' = 'month') {
+  return post('/gallery/timeline', { granularity }, 'get_timeline');
+}
+
+// pass `next_cursor` of the previous page to continue
+export function get_timeline_photos(params: { from?: number, to?: number, cursor?: string, limit?: number } = {}) {
+  return post('/gallery/timeline/photos', params, 'get_timeline_photos');
+}
+
+export type AlbumSortOrder = 'manual' | 'taken_at' | 'taken_at_desc' | 'added_at';
+
+export function list_albums() {
+  return post('/gallery/albums/list', {}, 'list_albums');
+}
+
+export function create_album(name: string, sort_order: AlbumSortOrder = 'manual') {
+  return post('/gallery/albums/create', { name, sort_order }, 'create_album');
+}
+
+export function update_album(id: number, changes: { name?: string, cover?: string, sort_order?: AlbumSortOrder }) {
+  return post('/gallery/albums/update', { id, ...changes }, 'update_album');
+}
+
+export function delete_album(id: number) {
+  return post('/gallery/albums/delete', { id }, 'delete_album');
+}
+
+export function add_album_items(id: number, files: string[]) {
+  return post('/gallery/albums/add', { id, files }, 'add_album_items');
+}
+
+export function remove_album_items(id: number, files: string[]) {
+  return post('/gallery/albums/remove', { id, files }, 'remove_album_items');
+}
+
+export function reorder_album_items(id: number, files: string[]) {
+  return post('/gallery/albums/reorder', { id, files }, 'reorder_album_items');
+}
+
+export function get_album_photos(id: number, offset = 0, limit = 100) {
+  return post('/gallery/albums/photos', { id, offset, limit }, 'get_album_photos');
+}
+
+export interface GeoCluster {
+  id: string;
+  count: number;
+  latitude: number;
+  longitude: number;
+  cover: string;
+}
+
+// clusters of geotagged photos in the visible map area, `west > east` crosses the antimeridian
+export function get_geo_clusters(bbox: { south: number, west: number, north: number, east: number }, zoom: number) {
+  return post('/gallery/geo', { ...bbox, zoom }, 'get_geo_clusters');
+}
+
+export function get_geo_cluster_photos(cluster: string, offset = 0, limit = 100) {
+  return post('/gallery/geo/photos', { cluster, offset, limit }, 'get_geo_cluster_photos');
+}
@@ -0,0 +1,68 @@
+import { post } from "./utils";
+
+export interface MusicArtist {
+  artist: string;
+  album_count: number;
+  track_count: number;
+}
+
+export interface MusicAlbum {
+  album: string;
+  artist: string;
+  year?: number;
+  track_count: number;
+  duration: number;
+  // a track of the album with cover art
+  cover?: string;
+}
+
+export interface MusicTrack {
+  file_path: string;
+  size: number;
+  modified_at: string;
+  title?: string;
+  artist?: string;
+  album?: string;
+  album_artist?: string;
+  track_number?: number;
+  disc_number?: number;
+  year?: number;
+  genre?: string;
+  duration?: number;
+  bitrate?: number;
+  has_cover: boolean;
+}
+
+export function list_artists() {
+  return post('/music/artists', {}, 'list_artists');
+}
+
+export function list_albums(artist?: string) {
+  return post('/music/albums', { artist }, 'list_albums');
+}
+
+// `artist` and `album` as listed by list_albums
+export function list_tracks(params: { artist?: string, album?: string, offset?: number, limit?: number } = {}) {
+  return post('/music/tracks', params, 'list_tracks');
+}
+
+export function search_music(keyword: string, limit?: number) {
+  return post('/music/search', { keyword, limit }, 'search_music');
+}
+
+export function create_cover_link(file: string) {
+  const url = new URL('/music/cover', window.location.origin);
+  const dpr = window.devicePixelRatio || 1;
+  url.searchParams.set('file', file);
+  url.searchParams.set('resize', Math.round(dpr * 300).toString());
+  return url.toString();
+}
+
+// transcoded by the server, the original file is read with create_download_link_from_file_path
+export function create_music_stream_link(file: string, format: 'opus' | 'mp3', bitrate = 128) {
+  const url = new URL('/music/stream', window.location.origin);
+  url.searchParams.set('file', file);
+  url.searchParams.set('format', format);
+  url.searchParams.set('bitrate', bitrate.toString());
+  return url.toString();
+}
@@ -0,0 +1,36 @@
+export type PushMessage =
+  | { type: 'file_added', files: string[] }
+  | { type: 'file_modified', files: string[] }
+  | { type: 'file_deleted', files: string[] }
+  | { type: 'upload_finished', files: string[] }
+  | { type: 'job_status', job: string, status: any }
+  | { type: 'resync' };
+
+const listeners = new Set<(msg: PushMessage) => void>();
+let source: EventSource | undefined;
+
+const eventNames = ['file_added', 'file_modified', 'file_deleted', 'upload_finished', 'job_status', 'resync'];
+
+function ensureSource() {
+  if (source) return;
+  source = new EventSource('/push/subscribe');
+  eventNames.forEach(name => {
+    source!.addEventListener(name, (e) => {
+      const msg = JSON.parse((e as MessageEvent).data) as PushMessage;
+      listeners.forEach(l => l(msg));
+    });
+  });
+}
+
+// share one EventSource between all listeners, closed when the last listener is removed
+export function subscribe(listener: (msg: PushMessage) => void) {
+  listeners.add(listener);
+  ensureSource();
+  return () => {
+    listeners.delete(listener);
+    if (listeners.size === 0 && source) {
+      source.close();
+      source = undefined;
+    }
+  };
+}
@@ -8,7 +8,7 @@ import { lazy, memo, Suspense, useCallback, useState } from 'react';
 import ZipPreview from './zip-viewer';
 import { setting } from '@store';
 
-function _Preview({ files, dir, file, onClose }: { files: FileStat[], dir: string, file: FileStat, onClose?: () => void }) {
+function _Preview({ files, dir, file, entry, onClose }: { files: FileStat[], dir: string, file: FileStat, entry?: string, onClose?: () => void }) {
   const [title, setTitle] = useState(file.name);
 
   const onPreviewingChange = useCallback((f: FileStat) => { setTitle(f?.name || '') }, []);
@@ -27,7 +27,7 @@ function _Preview({ files, dir, file, onClose }: { files: FileStat[], dir: strin
     inner = <Cmp dir={dir} file={file} />
     // inner = <PdfViewer dir={dir} file={file} />
   } else if (guess?.includes('zip')) {
-    inner = <ZipPreview dir={dir} file={file} />
+    inner = <ZipPreview dir={dir} file={file} entry={entry} />
   } else {
     inner = <div></div>
   }
@@ -1,8 +1,10 @@
 import { useEffect, useState } from "react"
+import classNames from "classnames";
 import { FileStat, read_zip_entries } from "@apis/file";
 import style from './zip-viewer.module.less';
 
-export default function ZipPreview({ dir, file }: { dir: string, file: FileStat }) {
+// `entry` is a path inside the archive to reveal, e.g. from a search result
+export default function ZipPreview({ dir, file, entry }: { dir: string, file: FileStat, entry?: string }) {
 
   const [tree, setTree] = useState<FileStatTree | null>(null);
 
@@ -17,7 +19,7 @@ export default function ZipPreview({ dir, file }: { dir: string, file: FileStat
       <div>Files in {file.name}</div>
       {
         tree &&
-        <FileTree tree={tree} deep={0} />
+        <FileTree tree={tree} deep={0} entry={entry} />
       }
     </div>
   </div>
@@ -28,17 +30,24 @@ interface FileStatTree {
   file: FileStat;
   children: FileStatTree[];
 }
-export function FileTree({ tree, deep }: { tree: FileStatTree, deep: number }) {
+// `entry` is relative to `tree`, directories on its path are expanded
+export function FileTree({ tree, deep, entry }: { tree: FileStatTree, deep: number, entry?: string }) {
+  const childEntry = (node: FileStatTree) => {
+    if (!entry) return undefined;
+    const [first, ...rest] = entry.split('/');
+    return first === node.file.name ? rest.join('/') : undefined;
+  };
+  const isTarget = deep > 0 && entry === '';
   if (tree.file.is_dir && deep > 0) {
-    return <details>
-      <summary className={style.item}>
+    return <details open={entry !== undefined}>
+      <summary className={classNames(style.item, { [style.target]: isTarget })}>
         {tree.file.name}
       </summary>
       {
         !!tree.children.length && <div className={style.tree}>
           {
             tree.children.map((node) => {
-              return <FileTree key={node.file.name} tree={node} deep={deep + 1} />
+              return <FileTree key={node.file.name} tree={node} deep={deep + 1} entry={childEntry(node)} />
             })
           }
         </div>
@@ -46,14 +55,14 @@ export function FileTree({ tree, deep }: { tree: FileStatTree, deep: number }) {
     </details>
   }
   return <div>
-    <div>
+    <div className={classNames({ [style.target]: isTarget })}>
       {tree.file.name}
     </div>
     {
       !!tree.children.length && <div className={style.tree}>
         {
           tree.children.map((node) => {
-            return <FileTree key={node.file.name} tree={node} deep={deep + 1} />
+            return <FileTree key={node.file.name} tree={node} deep={deep + 1} entry={childEntry(node)} />
           })
         }
       </div>
@@ -7,8 +7,28 @@ import { useDebounceValue } from '@hooks/common';
 import LoadingBar from './loading-bar';
 import style from './search-input.module.less';
 
+export interface SearchResultFile {
+  name: string;
+  dir: string;
+  is_dir: boolean;
+  // path inside the archive `name` when the result is an archive entry
+  entry?: string;
+}
+
 interface IProps {
-  onClick?: (file: { name: string, dir: string, is_dir: boolean }) => void;
+  onClick?: (file: SearchResultFile) => void;
+}
+
+interface ArchiveLink {
+  archive: string;
+  entry: string;
+}
+
+function toResultFile(file_name: string, file_path: string, is_dir: boolean, archive?: ArchiveLink): SearchResultFile {
+  if (archive) {
+    return { name: path.basename(archive.archive), dir: path.dirname(archive.archive), is_dir: false, entry: archive.entry };
+  }
+  return { name: file_name, dir: path.dirname(file_path), is_dir };
 }
 
 export default function SearchInput(props: IProps) {
@@ -35,13 +55,15 @@ export default function SearchInput(props: IProps) {
   async function searchContent(keyword: string) {
     setLoading(true);
     try {
-      const files = await search_files_content(keyword);
+      const result = await search_files_content(keyword);
       if (keyword) {
-        const ret = files.map((f: any) => {
+        const ret = result.hits.map((f: any) => {
           return {
-            file_name: f.field_values[0].value,
-            file_path: f.field_values[1].value,
-            content: highlightMaxLen(f.field_values[2].value, keyword),
+            file_name: f.name,
+            file_path: f.path,
+            archive: f.archive,
+            page: f.page,
+            content: renderSnippet(f.fragment, f.highlighted),
           }
         });
         setFilesContent(ret);
@@ -64,32 +86,20 @@ export default function SearchInput(props: IProps) {
     // eslint-disable-next-line
   }, [debouncedKeyword]);
 
-  function highlightMaxLen(name: string, kw: string, maxLen = 100) {
-    let idx = name.indexOf(kw);
-    console.log(idx)
-    if (idx !== -1) {
-      let half = Math.round(maxLen / 2);
-      let start = idx - half;
-      start = start < 0 ? 0 : start;
-      let end = idx + half;
-      end = end > (name.length - 1) ? name.length - 1 : end;
-      name = name.substring(start, end + 1);
-    } else {
-      name = name.substring(0, maxLen);
-    }
-
-    let segs = name.split(kw).map((v, idx) => {
-      return <span key={idx}>{v}</span>
-    });
-    let newSegs = [];
-    for (let i = 0; i < segs.length; i++) {
-      newSegs.push(segs[i]);
-      if (i !== segs.length - 1) {
-        const kwEl = <span key={'h-' + i} className={style.highlight}>{kw}</span>
-        newSegs.push(kwEl);
-      }
+  // highlighted ranges are utf-8 byte offsets into the fragment
+  function renderSnippet(fragment: string, highlighted: [number, number][]) {
+    const bytes = new TextEncoder().encode(fragment);
+    const decoder = new TextDecoder();
+    let segs = [];
+    let last = 0;
+    for (let i = 0; i < highlighted.length; i++) {
+      const [start, end] = highlighted[i];
+      segs.push(<span key={i}>{decoder.decode(bytes.slice(last, start))}</span>);
+      segs.push(<span key={'h-' + i} className={style.highlight}>{decoder.decode(bytes.slice(start, end))}</span>);
+      last = end;
     }
-    return newSegs;
+    segs.push(<span key="tail">{decoder.decode(bytes.slice(last))}</span>);
+    return segs;
   }
 
   function highlight(name: string, kw: string) {
@@ -116,7 +126,7 @@ export default function SearchInput(props: IProps) {
           files.map((file) => {
             return <div onClick={() => {
               if (props.onClick) {
-                props.onClick({ name: file.file_name, dir: path.dirname(file.file_path), is_dir: file.is_dir })
+                props.onClick(toResultFile(file.file_name, file.file_path, file.is_dir, file.archive))
               }
             }} className={style.item} key={file.file_path}>
               <span className={style.left}>
@@ -133,9 +143,9 @@ export default function SearchInput(props: IProps) {
           filesContent.map((file) => {
             return <div onClick={() => {
               if (props.onClick) {
-                props.onClick({ name: file.name, dir: path.dirname(file.file_path), is_dir: false })
+                props.onClick(toResultFile(file.file_name, file.file_path, false, file.archive))
               }
-            }} className={classNames(style.item, style['item-with-content'])} key={file.file_path}>
+            }} className={classNames(style.item, style['item-with-content'])} key={file.file_path + ':' + (file.page ?? '')}>
               <span className={style.left}>
                 <FileIcon className={style['file-icon']} size={14} file={{ name: file.file_name, is_dir: false }} />
                 <span title={file.file_name} className={style.name}>{highlight(file.file_name, keyword)}</span>
@@ -17,8 +17,9 @@ import Modal from "@components/modal";
 import { FileIcon } from "@components/icon/icon";
 import Checkbox from "@components/checkbox";
 import { setting } from "@store";
-import SearchInput from "./components/search-input";
+import SearchInput, { SearchResultFile } from "./components/search-input";
 import classNames from "classnames";
+import { subscribe } from "@apis/push";
 
 export default function FilePage() {
   let [files, setFiles] = useState<any[]>([]);
@@ -51,6 +52,16 @@ export default function FilePage() {
     history('/', { state: { previewing: file, currentDir: currentDir } });
   }
 
+  const openArchiveEntry = async (file: SearchResultFile) => {
+    const dir = file.dir === '.' ? '' : file.dir;
+    const archive = (await read_dir(dir)).find(f => f.name === file.name);
+    if (!archive) {
+      gotoDir(dir);
+      return;
+    }
+    history('/', { state: { previewing: archive, currentDir: dir, entry: file.entry } });
+  }
+
   useEffect(() => {
     gotoDir('');
     // eslint-disable-next-line
@@ -61,6 +72,18 @@ export default function FilePage() {
     // eslint-disable-next-line
   }, [signal]);
 
+  useEffect(() => {
+    return subscribe((msg) => {
+      if (msg.type === 'resync') {
+        reload();
+      } else if (msg.type === 'file_added' || msg.type === 'file_modified' || msg.type === 'file_deleted') {
+        const changed = msg.files.some(f => path.dirname(f) === (currentDir || '.'));
+        if (changed) reload();
+      }
+    });
+    // eslint-disable-next-line
+  }, [currentDir]);
+
   useEffect(() => {
     (async () => {
       const state = location.state;
@@ -158,7 +181,7 @@ export default function FilePage() {
           <div className={style['header-bar']}>
             <Breadcumb onJumpPath={(p) => gotoDir(p)} currentPath={currentPath} />
             <div className={style['header-actions']}>
-              <SearchInput onClick={file => gotoDir(file.dir)} />
+              <SearchInput onClick={file => file.entry ? openArchiveEntry(file) : gotoDir(file.dir)} />
               <Button onClick={async () => {
                 showAddFolder();
               }}>新建文件夹</Button>
@@ -203,7 +226,7 @@ export default function FilePage() {
             {content}
           </div>
         </div>
-        : <Preview file={previewing} files={files} dir={currentDir} onClose={gotoDir} />
+        : <Preview file={previewing} files={files} dir={currentDir} entry={location.state?.entry} onClose={gotoDir} />
     }
   </div>
 }
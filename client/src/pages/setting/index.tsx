@@ -3,6 +3,7 @@ import { useEffect, useMemo, useState } from "react";
 import { resetPassword } from "@apis/auth";
 import { get_file_index_updated_at, get_storage_info } from "@apis/file";
 import { update_index, get_job_status } from "@apis/gallery";
+import { subscribe } from "@apis/push";
 import Button from "@components/button";
 import Checkbox from "@components/checkbox";
 import { Popover } from "@components/popover";
@@ -53,7 +54,6 @@ const DownloadSetting = observer(({ setting }: IProps) => {
     let status = await get_job_status();
     if (status.data.Running !== undefined) {
       setUpdatedCount(status.data.Running);
-      setTimeout(updateIndexingStatus, 1000);
     } else {
       await updateIndexUpdatedAtTime();
       await updateStorageInfo();
@@ -74,6 +74,15 @@ const DownloadSetting = observer(({ setting }: IProps) => {
     updateIndexingStatus();
     updateIndexUpdatedAtTime();
     updateStorageInfo();
+    return subscribe((msg) => {
+      if (msg.type !== 'job_status') return;
+      if (msg.status.Running !== undefined) {
+        setUpdatedCount(msg.status.Running);
+      } else {
+        updateIndexUpdatedAtTime();
+        updateStorageInfo();
+      }
+    });
     // eslint-disable-next-line
   }, []);
 
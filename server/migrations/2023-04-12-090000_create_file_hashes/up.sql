@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE file_hashes (
+  file_path TEXT PRIMARY KEY NOT NULL,
+  size BIGINT NOT NULL,
+  modified_at TEXT NOT NULL,
+  hash TEXT NOT NULL
+);
+
+CREATE INDEX file_hashes_hash ON file_hashes (hash);
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE image_hashes (
+  file_path TEXT PRIMARY KEY NOT NULL,
+  size BIGINT NOT NULL,
+  modified_at TEXT NOT NULL,
+  dhash BIGINT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE saved_searches (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  username TEXT NOT NULL,
+  name TEXT NOT NULL,
+  query TEXT NOT NULL,
+  created_at TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX saved_searches_username_name ON saved_searches (username, name);
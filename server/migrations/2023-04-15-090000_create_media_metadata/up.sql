@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE TABLE media_metadata (
+  file_path TEXT PRIMARY KEY NOT NULL,
+  size BIGINT NOT NULL,
+  modified_at TEXT NOT NULL,
+  taken_at BIGINT,
+  camera_make TEXT,
+  camera_model TEXT,
+  lens TEXT,
+  latitude DOUBLE,
+  longitude DOUBLE,
+  altitude DOUBLE,
+  width INTEGER,
+  height INTEGER,
+  orientation INTEGER
+);
+
+CREATE INDEX media_metadata_taken_at ON media_metadata (taken_at);
@@ -0,0 +1,22 @@
+-- Your SQL goes here
+CREATE TABLE albums (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  username TEXT NOT NULL,
+  name TEXT NOT NULL,
+  cover TEXT,
+  sort_order TEXT NOT NULL DEFAULT 'manual',
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL
+);
+
+CREATE INDEX albums_username ON albums (username);
+
+CREATE TABLE album_items (
+  album_id INTEGER NOT NULL REFERENCES albums (id) ON DELETE CASCADE,
+  file_path TEXT NOT NULL,
+  position INTEGER NOT NULL,
+  added_at TEXT NOT NULL,
+  PRIMARY KEY (album_id, file_path)
+);
+
+CREATE INDEX album_items_file_path ON album_items (file_path);
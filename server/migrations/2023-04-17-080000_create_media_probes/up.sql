@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE media_probes (
+  file_path TEXT PRIMARY KEY NOT NULL,
+  size BIGINT NOT NULL,
+  modified_at TEXT NOT NULL,
+  duration DOUBLE,
+  container TEXT,
+  video_codec TEXT,
+  audio_codec TEXT,
+  width INTEGER,
+  height INTEGER,
+  bitrate BIGINT,
+  streams TEXT NOT NULL
+);
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+CREATE TABLE music_tracks (
+  file_path TEXT PRIMARY KEY NOT NULL,
+  size BIGINT NOT NULL,
+  modified_at TEXT NOT NULL,
+  title TEXT,
+  artist TEXT,
+  album TEXT,
+  album_artist TEXT,
+  track_number INTEGER,
+  disc_number INTEGER,
+  year INTEGER,
+  genre TEXT,
+  duration DOUBLE,
+  bitrate INTEGER,
+  has_cover BOOLEAN NOT NULL DEFAULT 0
+);
+
+CREATE INDEX music_tracks_artist ON music_tracks (artist);
+CREATE INDEX music_tracks_album ON music_tracks (album);
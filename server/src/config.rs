@@ -1,9 +1,11 @@
-use std::sync::Mutex;
+use std::{collections::HashMap, sync::Mutex};
 
 use clap::Parser;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
+use crate::utils::{image_hash, tokenizer::default_tokenizers};
+
 #[derive(Deserialize, Debug, Serialize)]
 pub struct AppConfig {
   pub host: Option<String>,
@@ -13,26 +15,75 @@ pub struct AppConfig {
   pub upload_temp_dir: Option<Option<String>>,
   pub use_ffmpeg_trancode: Option<bool>,
   pub ffmpeg_bin_path: Option<String>,
+  pub ffprobe_bin_path: Option<String>,
+  /// ffmpeg processes running at once, further transcodes wait in a queue
+  pub transcode_max_concurrent: Option<usize>,
+  /// ffmpeg processes one user may run at once
+  pub transcode_max_per_user: Option<usize>,
   pub indexing_follow_link: Option<bool>,
   pub search_index_path: Option<String>,
+  pub filename_index_path: Option<String>,
+  /// tokenizer per content index field, see `utils::tokenizer::TOKENIZERS`
+  pub content_tokenizers: Option<HashMap<String, String>>,
+  /// record entries of zip and tar files in the file index
+  pub index_archives: Option<bool>,
+  /// extract text of small text and pdf entries of archives for content search
+  pub index_archive_text: Option<bool>,
+  /// hash file contents during the index job to find duplicates
+  pub hash_files: Option<bool>,
+  /// compute perceptual hashes of images during the index job to find similar images
+  pub hash_images: Option<bool>,
+  /// max hamming distance between perceptual hashes of similar images (0-16)
+  pub similar_image_threshold: Option<u32>,
+  /// read EXIF metadata of images during the index job
+  pub extract_media_metadata: Option<bool>,
+  /// read duration, codecs and resolution of audio and video files with ffprobe during the index job
+  pub probe_media: Option<bool>,
+  /// read tags of audio files during the index job for the music library
+  pub index_music: Option<bool>,
+  /// directory of the thumbnail cache
+  pub thumbnail_cache_path: Option<String>,
+  /// max total bytes of cached thumbnails, the oldest are removed beyond it
+  pub thumbnail_cache_max_size: Option<u64>,
+  /// webp or jpeg
+  pub thumbnail_format: Option<String>,
+  /// directory of transcoded HLS segments
+  pub hls_cache_path: Option<String>,
+  /// seconds per HLS segment
+  pub hls_segment_duration: Option<u32>,
+  /// heights of the HLS renditions, the ones larger than the video are left out
+  pub hls_renditions: Option<Vec<u32>>,
+  /// seconds without segment requests after which a transcode is stopped
+  pub hls_idle_timeout: Option<u64>,
+  /// seconds after which segments of a video which is not watched are removed
+  pub hls_cache_max_age: Option<u64>,
+  /// thumbnails created in the background for new images found by the index job, empty to disable
+  pub thumbnail_pregenerate_sizes: Option<Vec<u32>>,
+  /// create posters and seek preview sprites of new videos during the index job, needs use_ffmpeg_trancode
+  pub video_preview_pregenerate: Option<bool>,
 }
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
+pub struct Args {
   #[arg(short, long)]
-  config: Option<String>,
+  pub config: Option<String>,
+  /// rebuild the search index with the configured tokenizers, then exit
+  #[arg(long)]
+  pub reindex: bool,
 }
 
 impl AppConfig {
-  pub fn init(&mut self) {
-    let args = Args::parse();
-    if let Some(config_file) = args.config {
+  pub fn init(&mut self, args: &Args) {
+    if let Some(config_file) = &args.config {
       let content = std::fs::read_to_string(config_file).unwrap();
       *self = toml::from_str(&content).unwrap();
       println!("app config:\n{}", serde_json::to_string_pretty(self).unwrap());
     }
+    if let Some(threshold) = self.similar_image_threshold {
+      image_hash::check_threshold(threshold).expect("invalid similar_image_threshold");
+    }
   }
 }
 
@@ -46,8 +97,31 @@ impl Default for AppConfig {
       upload_temp_dir: Some(Some("./files/upload_temp".to_owned())),
       use_ffmpeg_trancode: Some(false),
       ffmpeg_bin_path: Some("ffmpeg".to_owned()),
+      ffprobe_bin_path: Some("ffprobe".to_owned()),
+      transcode_max_concurrent: Some(2),
+      transcode_max_per_user: Some(1),
       indexing_follow_link: Some(true),
       search_index_path: Some("index".to_owned()),
+      filename_index_path: Some("filename_index".to_owned()),
+      content_tokenizers: Some(default_tokenizers()),
+      index_archives: Some(true),
+      index_archive_text: Some(true),
+      hash_files: Some(false),
+      hash_images: Some(true),
+      similar_image_threshold: Some(10),
+      extract_media_metadata: Some(true),
+      probe_media: Some(true),
+      index_music: Some(true),
+      thumbnail_cache_path: Some("thumbnail_cache".to_owned()),
+      thumbnail_cache_max_size: Some(1024 * 1024 * 1024),
+      thumbnail_format: Some("webp".to_owned()),
+      thumbnail_pregenerate_sizes: Some(vec![200, 400]),
+      video_preview_pregenerate: Some(true),
+      hls_cache_path: Some("hls_cache".to_owned()),
+      hls_segment_duration: Some(6),
+      hls_renditions: Some(vec![360, 720, 1080]),
+      hls_idle_timeout: Some(60),
+      hls_cache_max_age: Some(3600 * 24),
     }
   }
 }
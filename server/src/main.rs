@@ -1,7 +1,8 @@
 use crate::utils::error::AppError;
 use actix_web::{self, web, App, HttpServer};
 use chrono::NaiveTime;
-use config::APP_CONFIG;
+use clap::Parser;
+use config::{Args, APP_CONFIG};
 use schedulers::update_file_index::JOB_UPDATE_GALLERY;
 use serde::{Deserialize, Serialize};
 use std::{
@@ -13,7 +14,7 @@ use std::{
   sync::{Arc, RwLock},
 };
 use tokio::sync::Mutex;
-use tracing::log::info;
+use tracing::log::{info, warn};
 use utils::auth::auto_create_user;
 mod middlewares;
 pub mod models;
@@ -103,6 +104,11 @@ async fn main() -> Result<(), AppError> {
       .service(routers::fs::file_routers())
       .service(routers::auth::auth_routers())
       .service(routers::gallery::gallery_routers())
+      .service(routers::music::music_routers())
+      .service(routers::push::push_routers())
+      .service(routers::saved_search::saved_search_routers())
+      .service(routers::webhook::webhook_routers())
+      .service(routers::admin::admin_routers())
       .service(routers::index::index_routers())
       .wrap(middlewares::guard::guard_mw())
       .wrap(middlewares::static_server::static_server())
@@ -118,7 +124,8 @@ async fn main() -> Result<(), AppError> {
 }
 
 fn init() -> AppState {
-  APP_CONFIG.lock().unwrap().init();
+  let args = Args::parse();
+  APP_CONFIG.lock().unwrap().init(&args);
 
   let port: i32 = config!(port);
 
@@ -133,11 +140,18 @@ fn init() -> AppState {
 
   let mut conn = connect_db();
   run_migrations(&mut conn);
+  // before any index run, probing media files needs the result
+  utils::transcode::check_ffmpeg();
 
   JOB_UPDATE_GALLERY
     .lock()
     .unwrap()
     .set_file_root(&abs_file_root);
+
+  if args.reindex {
+    reindex();
+  }
+
   JOB_UPDATE_GALLERY
     .lock()
     .unwrap()
@@ -146,6 +160,18 @@ fn init() -> AppState {
 
   auto_create_user(&mut conn);
 
+  if utils::search_engine::needs_rebuild() {
+    warn!("search index is missing or was built with another schema, rebuilding");
+    JOB_UPDATE_GALLERY
+      .lock()
+      .unwrap()
+      .rebuild_search_index()
+      .unwrap_or_else(|err| warn!("fail to rebuild search index: {err}"));
+  }
+
+  utils::vfs::subscribe_fs_events();
+  utils::webhook::subscribe_webhooks();
+
   let state = AppState {
     config: AppConfig {
       file_root: abs_file_root,
@@ -162,6 +188,19 @@ fn init() -> AppState {
   state
 }
 
+/// Rebuild the content search index from scratch, needed after changing tokenizers.
+fn reindex() {
+  info!("rebuilding search index");
+  utils::search_engine::remove_index().unwrap();
+  JOB_UPDATE_GALLERY
+    .lock()
+    .unwrap()
+    .update_blocking()
+    .unwrap();
+  info!("search index rebuilt");
+  std::process::exit(0);
+}
+
 pub fn run_migrations(conn: &mut SqliteConnection) {
   info!("database is connected");
   info!("running migrations");
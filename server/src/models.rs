@@ -65,3 +65,281 @@ pub struct FileIndexSizeCount {
 pub struct FileIndexLastUpdatedAt {
   pub updated_at: String,
 }
+
+#[derive(Queryable, Debug, Serialize, Clone)]
+#[diesel(table_name = webhooks)]
+pub struct Webhook {
+  pub id: i32,
+  pub username: String,
+  pub url: String,
+  pub path_prefix: String,
+  pub event_types: String,
+  #[serde(skip_serializing)]
+  pub secret: String,
+  pub created_at: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webhooks)]
+pub struct NewWebhook<'a> {
+  pub username: &'a str,
+  pub url: &'a str,
+  pub path_prefix: &'a str,
+  pub event_types: &'a str,
+  pub secret: &'a str,
+  pub created_at: &'a str,
+}
+
+#[derive(Queryable, Debug, Serialize)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct WebhookDelivery {
+  pub id: i32,
+  pub webhook_id: i32,
+  pub event: String,
+  pub payload: String,
+  pub status_code: Option<i32>,
+  pub success: bool,
+  pub attempts: i32,
+  pub error: Option<String>,
+  pub created_at: String,
+}
+
+#[derive(Insertable, Debug, Clone, Serialize)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewWebhookDelivery {
+  pub webhook_id: i32,
+  pub event: String,
+  pub payload: String,
+  pub status_code: Option<i32>,
+  pub success: bool,
+  pub attempts: i32,
+  pub error: Option<String>,
+  pub created_at: String,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name = file_hashes)]
+pub struct FileHash {
+  pub file_path: String,
+  pub size: i64,
+  pub modified_at: String,
+  /// hex encoded sha256 of the content
+  pub hash: String,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name = image_hashes)]
+pub struct ImageHash {
+  pub file_path: String,
+  pub size: i64,
+  pub modified_at: String,
+  /// 64 bit difference hash, stored as i64 bit pattern
+  pub dhash: i64,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone, Default, Serialize)]
+#[diesel(table_name = media_metadata)]
+pub struct MediaMetadata {
+  pub file_path: String,
+  pub size: i64,
+  pub modified_at: String,
+  /// capture time in millis, camera local time when the offset is not recorded
+  pub taken_at: Option<i64>,
+  pub camera_make: Option<String>,
+  pub camera_model: Option<String>,
+  pub lens: Option<String>,
+  pub latitude: Option<f64>,
+  pub longitude: Option<f64>,
+  pub altitude: Option<f64>,
+  /// dimensions as displayed, after applying the orientation
+  pub width: Option<i32>,
+  pub height: Option<i32>,
+  /// EXIF orientation, 1-8
+  pub orientation: Option<i32>,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone, Default)]
+#[diesel(table_name = media_probes)]
+pub struct MediaProbe {
+  pub file_path: String,
+  pub size: i64,
+  pub modified_at: String,
+  /// seconds
+  pub duration: Option<f64>,
+  /// ffprobe format name, e.g. `mov,mp4,m4a,3gp,3g2,mj2`
+  pub container: Option<String>,
+  /// codec of the first video stream
+  pub video_codec: Option<String>,
+  /// codec of the first audio stream
+  pub audio_codec: Option<String>,
+  pub width: Option<i32>,
+  pub height: Option<i32>,
+  /// bits per second
+  pub bitrate: Option<i64>,
+  /// json of `Vec<utils::media_probe::StreamInfo>`
+  pub streams: String,
+}
+
+#[derive(Queryable, QueryableByName, Insertable, Debug, Clone, Default, Serialize)]
+#[diesel(table_name = music_tracks)]
+pub struct MusicTrack {
+  pub file_path: String,
+  pub size: i64,
+  pub modified_at: String,
+  pub title: Option<String>,
+  pub artist: Option<String>,
+  pub album: Option<String>,
+  pub album_artist: Option<String>,
+  pub track_number: Option<i32>,
+  pub disc_number: Option<i32>,
+  pub year: Option<i32>,
+  pub genre: Option<String>,
+  /// seconds
+  pub duration: Option<f64>,
+  /// kbps
+  pub bitrate: Option<i32>,
+  /// the file embeds cover art
+  pub has_cover: bool,
+}
+
+#[derive(QueryableByName, Debug, Serialize)]
+pub struct MusicArtist {
+  #[diesel(sql_type = diesel::sql_types::Text)]
+  pub artist: String,
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub album_count: i64,
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub track_count: i64,
+}
+
+#[derive(QueryableByName, Debug, Serialize)]
+pub struct MusicAlbum {
+  #[diesel(sql_type = diesel::sql_types::Text)]
+  pub album: String,
+  #[diesel(sql_type = diesel::sql_types::Text)]
+  pub artist: String,
+  #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Integer>)]
+  pub year: Option<i32>,
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub track_count: i64,
+  #[diesel(sql_type = diesel::sql_types::Double)]
+  pub duration: f64,
+  /// a track of the album with cover art, relative to file root
+  #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Text>)]
+  pub cover: Option<String>,
+}
+
+#[derive(Queryable, Debug, Serialize)]
+#[diesel(table_name = saved_searches)]
+pub struct SavedSearch {
+  pub id: i32,
+  pub username: String,
+  pub name: String,
+  /// json of `utils::saved_search::SavedSearchQuery`
+  #[serde(skip_serializing)]
+  pub query: String,
+  pub created_at: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = saved_searches)]
+pub struct NewSavedSearch<'a> {
+  pub username: &'a str,
+  pub name: &'a str,
+  pub query: &'a str,
+  pub created_at: &'a str,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+#[diesel(table_name = albums)]
+pub struct Album {
+  pub id: i32,
+  pub username: String,
+  pub name: String,
+  /// path of the cover image relative to file root, the first item is used when empty
+  pub cover: Option<String>,
+  /// manual, taken_at or added_at
+  pub sort_order: String,
+  pub created_at: String,
+  pub updated_at: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = albums)]
+pub struct NewAlbum<'a> {
+  pub username: &'a str,
+  pub name: &'a str,
+  pub cover: Option<&'a str>,
+  pub sort_order: &'a str,
+  pub created_at: &'a str,
+  pub updated_at: &'a str,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name = album_items)]
+pub struct AlbumItem {
+  pub album_id: i32,
+  pub file_path: String,
+  pub position: i32,
+  pub added_at: String,
+}
+
+#[derive(QueryableByName, Debug, Serialize)]
+pub struct TimelinePhoto {
+  #[diesel(sql_type = diesel::sql_types::Text)]
+  pub file_path: String,
+  #[diesel(sql_type = diesel::sql_types::Text)]
+  pub file_name: String,
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub size: i64,
+  /// capture time in millis, the modified time for images without EXIF
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub taken_at: i64,
+  #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Integer>)]
+  pub width: Option<i32>,
+  #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::Integer>)]
+  pub height: Option<i32>,
+}
+
+#[derive(QueryableByName, Debug, Serialize)]
+pub struct TimelineBucket {
+  /// `2023`, `2023-04` or `2023-04-16`
+  #[diesel(sql_type = diesel::sql_types::Text)]
+  pub bucket: String,
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub count: i64,
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub first_taken_at: i64,
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub last_taken_at: i64,
+}
+
+#[derive(QueryableByName, Debug, Serialize)]
+pub struct GeoCluster {
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub cell_x: i64,
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub cell_y: i64,
+  #[diesel(sql_type = diesel::sql_types::BigInt)]
+  pub count: i64,
+  /// mean position of the photos in the cluster
+  #[diesel(sql_type = diesel::sql_types::Double)]
+  pub latitude: f64,
+  #[diesel(sql_type = diesel::sql_types::Double)]
+  pub longitude: f64,
+  /// the most recent photo of the cluster
+  #[diesel(sql_type = diesel::sql_types::Text)]
+  pub cover: String,
+}
+
+#[derive(QueryableByName, Debug, Serialize)]
+pub struct GeoPhoto {
+  #[diesel(sql_type = diesel::sql_types::Text)]
+  pub file_path: String,
+  #[diesel(sql_type = diesel::sql_types::Nullable<diesel::sql_types::BigInt>)]
+  pub taken_at: Option<i64>,
+  #[diesel(sql_type = diesel::sql_types::Double)]
+  pub latitude: f64,
+  #[diesel(sql_type = diesel::sql_types::Double)]
+  pub longitude: f64,
+}
@@ -1,4 +1,9 @@
+pub mod admin;
 pub mod auth;
 pub mod fs;
 pub mod index;
-pub mod gallery;
\ No newline at end of file
+pub mod gallery;
+pub mod music;
+pub mod push;
+pub mod saved_search;
+pub mod webhook;
\ No newline at end of file
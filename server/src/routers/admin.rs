@@ -0,0 +1,46 @@
+use actix_session::Session;
+use actix_web::{http::StatusCode, web, HttpResponse, Scope};
+
+use crate::{
+  schedulers::update_file_index::JOB_UPDATE_GALLERY,
+  utils::{
+    auth::is_admin,
+    error::AppError,
+    response::{create_resp, EmptyResponseData},
+    search_engine,
+    session::SessionUtils,
+  },
+};
+
+fn ensure_admin(sess: &Session) -> Result<(), AppError> {
+  let username = sess.get_user_data()?.username;
+  if !is_admin(&username)? {
+    return Err(AppError::new("permission denied").with_status(StatusCode::FORBIDDEN));
+  }
+  Ok(())
+}
+
+pub async fn index_status(sess: Session) -> Result<HttpResponse, AppError> {
+  ensure_admin(&sess)?;
+  let status = web::block(search_engine::index_status).await??;
+  Ok(create_resp(true, status, "done"))
+}
+
+pub async fn rebuild_index(sess: Session) -> Result<HttpResponse, AppError> {
+  ensure_admin(&sess)?;
+  JOB_UPDATE_GALLERY.lock().unwrap().rebuild_search_index()?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+pub async fn optimize_index(sess: Session) -> Result<HttpResponse, AppError> {
+  ensure_admin(&sess)?;
+  web::block(search_engine::optimize).await??;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+pub fn admin_routers() -> Scope {
+  web::scope("/admin")
+    .route("/index_status", web::post().to(index_status))
+    .route("/rebuild_index", web::post().to(rebuild_index))
+    .route("/optimize_index", web::post().to(optimize_index))
+}
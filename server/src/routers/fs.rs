@@ -1,20 +1,32 @@
 use crate::utils::error::AppError;
 use crate::utils::parser::parse_range;
 use crate::utils::response::{
-  create_binary_resp, create_stream_resp, create_unsized_stream_resp, EmptyResponseData,
+  create_binary_resp, create_cacheable_binary_resp, create_not_modified_resp, create_stream_resp,
+  create_unsized_stream_resp, EmptyResponseData,
 };
+use crate::utils::push::{publish, PushMessage};
+use crate::utils::search_engine::SearchOptions;
 use crate::utils::session::SessionUtils;
 use crate::utils::vfs::{
-  ensure_parent_dir_sync, read_file_stream, read_to_zip_stream, FileStatWithName, FS_HOOK, FSHookType, FSHookPayload, rel_join,
+  ensure_parent_dir_sync, read_file_stream, read_to_zip_stream, FileStat, FileStatWithName, FS_EVENTS, FsEvent, rel_join,
 };
-use crate::utils::{response::create_resp, vfs};
+use crate::utils::hls::{self, HlsVideo};
+use crate::utils::media_probe::{self, MediaInfo};
+use crate::utils::subtitle;
+use crate::utils::transcode;
+use crate::utils::video_preview::{self, PreviewKind};
+use crate::utils::{duplicates, media_metadata, response::create_resp, saved_search, vfs};
 use crate::AppData;
 use actix_session::Session;
-use actix_web::http::StatusCode;
+use actix_web::http::{header, StatusCode};
 use actix_web::{web, HttpRequest, HttpResponse, Scope};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::path::{Component, Path};
 use tokio_util::io::ReaderStream;
+use tracing::warn;
 
 #[derive(Deserialize)]
 pub struct GetFilesOfDirReq {
@@ -24,6 +36,42 @@ pub struct GetFilesOfDirReq {
 #[derive(Serialize)]
 pub struct GetFilesOfDirResp {
   files: Vec<FileStatWithName>,
+  /// probe results of the audio and video files by name, only the ones already probed
+  #[serde(skip_serializing_if = "HashMap::is_empty")]
+  media: HashMap<String, MediaInfo>,
+}
+
+#[derive(Serialize)]
+pub struct StatResp {
+  #[serde(flatten)]
+  stat: FileStat,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  media: Option<MediaInfo>,
+}
+
+/// Stored probe results of the media files in a listing, `dir` is empty for smart
+/// folders whose names are paths relative to the user root.
+async fn listing_media_info(
+  user_root: &str,
+  dir: &str,
+  files: &[FileStatWithName],
+) -> Result<HashMap<String, MediaInfo>, AppError> {
+  let mut paths = HashMap::new();
+  for f in files.iter().filter(|f| f.is_file && media_probe::is_media(&f.name)) {
+    let rel = Path::new(dir).join(&f.name);
+    paths.insert(rel_join(user_root, &rel.to_string_lossy())?, f.name.clone());
+  }
+  if paths.is_empty() {
+    return Ok(HashMap::new());
+  }
+  let keys: Vec<String> = paths.keys().cloned().collect();
+  let stored = web::block(move || media_probe::stored_media_info(&keys)).await??;
+  Ok(
+    stored
+      .into_iter()
+      .filter_map(|(path, info)| Some((paths.get(&path)?.clone(), info)))
+      .collect(),
+  )
 }
 
 pub async fn fs_actions_get(
@@ -71,9 +119,34 @@ pub async fn fs_actions(
 
   match action.as_str() {
     "read_dir" => {
-      let files = vfs::read_dir(file_root, user_root, file).await.unwrap();
+      let username = sess.get_user_data()?.username;
+      let smart_folder = {
+        let (username, user_root, dir) = (username.clone(), user_root.clone(), file.to_owned());
+        web::block(move || saved_search::read_smart_folder(&username, &user_root, &dir)).await??
+      };
+      let (files, media_dir) = match smart_folder {
+        Some(files) => (files, ""),
+        None => {
+          let mut files = vfs::read_dir(file_root, user_root, file).await.unwrap();
+          let is_root = Path::new(file)
+            .components()
+            .all(|c| matches!(c, Component::CurDir | Component::RootDir));
+          if is_root {
+            if files.iter().any(|f| f.name == saved_search::SMART_FOLDER_ROOT) {
+              warn!(
+                "directory {} of {username} is hidden by the smart folders, rename it to reach its files",
+                saved_search::SMART_FOLDER_ROOT
+              );
+            }
+            files.retain(|f| f.name != saved_search::SMART_FOLDER_ROOT);
+            files.extend(saved_search::smart_folder_root_entry(&username)?);
+          }
+          (files, file)
+        }
+      };
+      let media = listing_media_info(user_root, media_dir, &files).await?;
 
-      let resp = GetFilesOfDirResp { files };
+      let resp = GetFilesOfDirResp { files, media };
 
       Ok(create_resp(true, resp, ""))
     }
@@ -106,6 +179,10 @@ pub async fn fs_actions(
       let file_stat = vfs::stat(file_root, user_root, file).await?;
       let (range_start, range_end, is_range) = parse_range(headers, file_stat.size)?;
       let stream = read_file_stream(file_root, user_root, file, (range_start, range_end)).await?;
+      let real_file = saved_search::resolve_virtual_path(file).unwrap_or_else(|| file.to_owned());
+      FS_EVENTS.emit(FsEvent::Read {
+        file: rel_join(user_root, &real_file)?,
+      });
       let mime = mime_guess::from_path(file.to_owned())
         .first()
         .map(|m| m.to_string());
@@ -137,8 +214,16 @@ pub async fn fs_actions(
     }
 
     "stat" => {
-      let file_stat = vfs::stat(file_root, user_root, file).await?;
-      Ok(create_resp(true, file_stat, ""))
+      let stat = vfs::stat(file_root, user_root, file).await?;
+      let mut media = None;
+      if stat.is_file && media_probe::is_media(file) {
+        let real_file = saved_search::resolve_virtual_path(file).unwrap_or_else(|| file.to_owned());
+        let rel = rel_join(user_root, &real_file)?;
+        // only the probe stored by the index job, ffprobe never runs on the request path
+        let mut stored = web::block(move || media_probe::stored_media_info(&[rel])).await??;
+        media = stored.drain().next().map(|(_, m)| m);
+      }
+      Ok(create_resp(true, StatResp { stat, media }, ""))
     }
     _ => Ok(create_resp(false, EmptyResponseData::new(), "error action")),
   }
@@ -177,19 +262,35 @@ pub async fn upload(
   let user_root = user_root.clone();
   web::block(move || -> Result<(), AppError> {
     let files = parts.files.into_inner();
-    let mut flist = vec![];
+    let mut created = vec![];
+    let mut modified = vec![];
     for (filename, file) in files {
+      saved_search::ensure_writable(&filename)?;
       if let Ok(file) = file {
         let file_path = file_root.join(&user_root).join(&filename);
         ensure_parent_dir_sync(&file_path)?;
+        let exists = file_path.exists();
         let parent_dir = file_path.parent();
         if let Some(parent_dir) = parent_dir {
           file.persist_in(parent_dir)?;
-          flist.push(rel_join(&user_root, &filename)?);
+          let rel_path = rel_join(&user_root, &filename)?;
+          if exists {
+            modified.push(rel_path);
+          } else {
+            created.push(rel_path);
+          }
         }
       }
     }
-    FS_HOOK.lock().unwrap().emit(FSHookType::AddFile, FSHookPayload(flist));
+    let mut flist = created.clone();
+    flist.extend(modified.iter().cloned());
+    if !created.is_empty() {
+      FS_EVENTS.emit(FsEvent::Create { files: created });
+    }
+    if !modified.is_empty() {
+      FS_EVENTS.emit(FsEvent::Modify { files: modified });
+    }
+    publish(PushMessage::UploadFinished { files: flist });
     Ok(())
   })
   .await??;
@@ -216,46 +317,118 @@ pub struct ReadVideoReq {
 
 pub async fn read_image_get(
   query: web::Query<ReadImageReq>,
+  req: HttpRequest,
   state: web::Data<AppData>,
   sess: Session,
 ) -> Result<HttpResponse, AppError> {
   let file = query.file.clone().ok_or(AppError::new("params error").with_status(StatusCode::BAD_REQUEST))?;
   let resize = query.resize;
-  read_image(&file, resize, state, sess).await
+  read_image(&file, resize, req, state, sess).await
 }
 
 pub async fn read_image_post(
   query: web::Json<ReadImageReq>,
+  req: HttpRequest,
   state: web::Data<AppData>,
   sess: Session,
 ) -> Result<HttpResponse, AppError> {
   let file = query.file.clone().ok_or(AppError::new("params error").with_status(StatusCode::BAD_REQUEST))?;
   let resize = query.resize;
-  read_image(&file, resize, state, sess).await
+  read_image(&file, resize, req, state, sess).await
 }
 
+/// thumbnails are keyed by the modified time of the image, so they can be cached for long
+const THUMBNAIL_MAX_AGE: u32 = 3600 * 24;
+
 pub async fn read_image(
   file: &str,
   resize: Option<u32>,
+  req: HttpRequest,
   state: web::Data<AppData>,
   sess: Session,
 ) -> Result<HttpResponse, AppError> {
-  let file_root = &state.read().unwrap().config.file_root;
+  let file_root = state.read().unwrap().config.file_root.clone();
   let user_root = sess.get_user_root()?;
 
-  let mime = mime_guess::from_path(file.to_owned())
-    .first()
-    .map(|m| m.to_string());
+  if let Some(resize) = resize {
+    let if_none_match = req
+      .headers()
+      .get(header::IF_NONE_MATCH)
+      .and_then(|v| v.to_str().ok());
+    if let Some(if_none_match) = if_none_match {
+      let etag =
+        vfs::thumbnail_etag(file_root.clone(), user_root.clone(), file.to_string(), resize).await?;
+      if if_none_match.split(',').any(|t| t.trim() == etag) {
+        return Ok(create_not_modified_resp(&etag, THUMBNAIL_MAX_AGE));
+      }
+    }
+  }
 
-  let img = vfs::read_image(
-    file_root.clone(),
-    user_root.clone(),
-    file.to_string(),
-    resize,
-  )
-  .await?;
+  let img = vfs::read_image(file_root, user_root, file.to_string(), resize).await?;
+  let resp = match &img.etag {
+    Some(etag) => create_cacheable_binary_resp(img.data, img.mime, etag, THUMBNAIL_MAX_AGE),
+    None => create_binary_resp(img.data, img.mime),
+  };
+  Ok(resp)
+}
 
-  Ok(create_binary_resp(img, mime))
+#[derive(Deserialize)]
+pub struct VideoPreviewReq {
+  file: String,
+  /// poster, sprite or vtt
+  kind: String,
+  resize: Option<u32>,
+}
+
+pub async fn video_preview_get(
+  query: web::Query<VideoPreviewReq>,
+  req: HttpRequest,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let user_root = sess.get_user_root()?;
+  let username = sess.get_user_data()?.username;
+  video_preview::ensure_video(&query.file)?;
+  let kind = match query.kind.as_str() {
+    "poster" => PreviewKind::Poster,
+    "sprite" | "vtt" => PreviewKind::Sprite,
+    _ => return Err(AppError::new("kind must be poster, sprite or vtt").with_status(StatusCode::BAD_REQUEST)),
+  };
+  let file = saved_search::resolve_virtual_path(&query.file).unwrap_or_else(|| query.file.clone());
+  let file = rel_join(&user_root, &file)?;
+  let dimension = query.resize.unwrap_or(video_preview::DEFAULT_POSTER_DIMENSION);
+  let entry = web::block(move || video_preview::cache_entry(&file_root, &file, kind, dimension)).await??;
+
+  if query.kind != "vtt" {
+    let if_none_match = req
+      .headers()
+      .get(header::IF_NONE_MATCH)
+      .and_then(|v| v.to_str().ok());
+    if if_none_match.is_some_and(|v| v.split(',').any(|t| t.trim() == entry.etag)) {
+      return Ok(create_not_modified_resp(&entry.etag, THUMBNAIL_MAX_AGE));
+    }
+  }
+  // creating previews runs ffmpeg, it counts against the transcode limits
+  let _permit = if video_preview::is_cached(&entry) {
+    None
+  } else {
+    transcode::ensure_enabled()?;
+    Some(transcode::acquire(&username).await?)
+  };
+
+  if query.kind == "vtt" {
+    let sprite_url = format!(
+      "video_preview?file={}&kind=sprite",
+      utf8_percent_encode(&query.file, NON_ALPHANUMERIC)
+    );
+    let vtt = web::block(move || video_preview::thumbnails_vtt(&entry, &sprite_url)).await??;
+    return Ok(create_binary_resp(vtt.into_bytes(), Some("text/vtt".to_owned())));
+  }
+  let etag = entry.etag.clone();
+  let mime = entry.mime.to_owned();
+  let data = web::block(move || video_preview::load(&entry)).await??;
+  Ok(create_cacheable_binary_resp(data, Some(mime), &etag, THUMBNAIL_MAX_AGE))
 }
 
 pub async fn read_video_transcode_get(
@@ -266,15 +439,17 @@ pub async fn read_video_transcode_get(
   let file = query.file.clone().ok_or(AppError::new("params error").with_status(StatusCode::BAD_REQUEST))?;
   let resize = query.resize.clone();
   let bitrate = query.bitrate.clone();
-  let file_root = &state.read().unwrap().config.file_root;
+  let file_root = state.read().unwrap().config.file_root.clone();
   let user_root = sess.get_user_root()?;
+  let username = sess.get_user_data()?.username;
 
   let video_stream = vfs::read_video_transform_stream(
-    file_root.clone(),
-    user_root.clone(),
+    file_root,
+    user_root,
     file.to_string(),
     resize,
     bitrate,
+    &username,
   )
   .await?;
 
@@ -291,20 +466,261 @@ pub struct SearchFilesReq {
   keyword: String,
 }
 
-pub async fn search(body: web::Json<SearchFilesReq>) -> Result<HttpResponse, AppError> {
+pub async fn search(body: web::Json<SearchFilesReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
   let kw = body.keyword.clone();
 
-  let r = vfs::search_in_index(&kw, 100).await?;
+  let r = vfs::search_in_index(&kw, &user_root, 100).await?;
 
   Ok(create_resp(true, r, "done"))
 }
 
-pub async fn search_content(body: web::Json<SearchFilesReq>) -> Result<HttpResponse, AppError> {
+#[derive(Deserialize)]
+pub struct SearchContentReq {
+  keyword: String,
+  offset: Option<usize>,
+  limit: Option<usize>,
+  mimes: Option<Vec<String>>,
+  min_size: Option<u64>,
+  max_size: Option<u64>,
+  modified_after: Option<u64>,
+  modified_before: Option<u64>,
+  /// directory relative to user root
+  folder: Option<String>,
+}
+
+pub async fn search_content(
+  body: web::Json<SearchContentReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let folder = rel_join(&user_root, body.folder.as_deref().unwrap_or(""))?;
+  let options = SearchOptions {
+    offset: body.offset.unwrap_or(0),
+    limit: body.limit.unwrap_or(10).clamp(1, 100),
+    mimes: body.mimes.clone().unwrap_or_default(),
+    min_size: body.min_size,
+    max_size: body.max_size,
+    modified_after: body.modified_after,
+    modified_before: body.modified_before,
+    folder: Some(folder),
+  };
   let kw = body.keyword.clone();
-  let r = vfs::search_in_tantivy(&kw)?;
+  let r = web::block(move || vfs::search_in_tantivy(&kw, &options)).await??;
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct DuplicatesReq {
+  /// directory relative to user root
+  folder: Option<String>,
+  min_size: Option<u64>,
+}
+
+pub async fn duplicates(
+  body: web::Json<DuplicatesReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let folder = rel_join(&user_root, body.folder.as_deref().unwrap_or(""))?;
+  let min_size = body.min_size.unwrap_or(0);
+  let r = web::block(move || duplicates::find_duplicates(&user_root, &folder, min_size)).await??;
   Ok(create_resp(true, r, "done"))
 }
 
+#[derive(Deserialize)]
+pub struct RenameReq {
+  /// relative to user root
+  from: String,
+  to: String,
+}
+
+pub async fn rename(
+  body: web::Json<RenameReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let user_root = sess.get_user_root()?;
+  vfs::rename(&file_root, &user_root, &body.from, &body.to).await?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct DedupeReq {
+  /// the copy to keep, relative to user root
+  keep: String,
+  /// copies to move to the trash
+  files: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct DedupeResp {
+  trashed: Vec<String>,
+  /// files not identical to `keep` anymore, they are left untouched
+  skipped: Vec<String>,
+}
+
+pub async fn dedupe(
+  body: web::Json<DedupeReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let user_root = sess.get_user_root()?;
+  let user_abs_root = file_root.join(&user_root);
+  let DedupeReq { keep, files } = body.into_inner();
+  let (trashed, skipped) =
+    web::block(move || duplicates::verify_duplicates(&user_abs_root, &keep, &files)).await??;
+  vfs::move_to_trash(&file_root, &user_root, &trashed).await?;
+  Ok(create_resp(true, DedupeResp { trashed, skipped }, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct HlsReq {
+  file: String,
+}
+
+async fn open_hls_video(
+  file: &str,
+  state: &web::Data<AppData>,
+  sess: &Session,
+) -> Result<HlsVideo, AppError> {
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let user_root = sess.get_user_root()?;
+  let file = saved_search::resolve_virtual_path(file).unwrap_or_else(|| file.to_owned());
+  let file = rel_join(&user_root, &file)?;
+  web::block(move || hls::open(&file_root, &file)).await?
+}
+
+const HLS_PLAYLIST_MIME: &str = "application/vnd.apple.mpegurl";
+
+pub async fn hls_master_playlist(
+  query: web::Query<HlsReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let video = open_hls_video(&query.file, &state, &sess).await?;
+  let m3u8 = video.master_playlist(&query.file);
+  Ok(create_binary_resp(m3u8.into_bytes(), Some(HLS_PLAYLIST_MIME.to_owned())))
+}
+
+pub async fn hls_media_playlist(
+  path: web::Path<(u32,)>,
+  query: web::Query<HlsReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let video = open_hls_video(&query.file, &state, &sess).await?;
+  let m3u8 = video.media_playlist(path.into_inner().0, &query.file)?;
+  Ok(create_binary_resp(m3u8.into_bytes(), Some(HLS_PLAYLIST_MIME.to_owned())))
+}
+
+pub async fn hls_segment(
+  path: web::Path<(u32, String)>,
+  query: web::Query<HlsReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let (height, segment) = path.into_inner();
+  let index = segment
+    .strip_suffix(".ts")
+    .and_then(|i| i.parse::<usize>().ok())
+    .ok_or(AppError::new("invalid segment").with_status(StatusCode::NOT_FOUND))?;
+  let username = sess.get_user_data()?.username;
+  let video = open_hls_video(&query.file, &state, &sess).await?;
+  let file = video.segment(height, index, &username).await?;
+  let data = tokio::fs::read(file).await?;
+  Ok(create_binary_resp(data, Some("video/mp2t".to_owned())))
+}
+
+#[derive(Deserialize)]
+pub struct MediaInfoReq {
+  file: String,
+}
+
+pub async fn media_info(
+  body: web::Json<MediaInfoReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let user_root = sess.get_user_root()?;
+  let file = saved_search::resolve_virtual_path(&body.file).unwrap_or_else(|| body.file.clone());
+  let file = rel_join(&user_root, &file)?;
+  let r = web::block(move || media_metadata::media_info(&file_root, &file)).await??;
+  Ok(create_resp(true, r, "done"))
+}
+
+pub async fn subtitles(
+  body: web::Json<MediaInfoReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let user_root = sess.get_user_root()?;
+  let file = saved_search::resolve_virtual_path(&body.file).unwrap_or_else(|| body.file.clone());
+  let file = rel_join(&user_root, &file)?;
+  let r = web::block(move || subtitle::list_tracks(&file_root, &file)).await??;
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct SubtitleReq {
+  file: String,
+  /// id from the track list
+  track: String,
+}
+
+pub async fn read_subtitle(
+  query: web::Query<SubtitleReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let user_root = sess.get_user_root()?;
+  let file = saved_search::resolve_virtual_path(&query.file).unwrap_or_else(|| query.file.clone());
+  let file = rel_join(&user_root, &file)?;
+  let track = query.track.clone();
+  let needs_ffmpeg = {
+    let (file_root, file, track) = (file_root.clone(), file.clone(), track.clone());
+    web::block(move || subtitle::needs_ffmpeg(&file_root, &file, &track)).await??
+  };
+  // conversions count against the transcode limits like videos
+  let permit = if needs_ffmpeg {
+    Some(transcode::acquire(&sess.get_user_data()?.username).await?)
+  } else {
+    None
+  };
+  let vtt = web::block(move || {
+    let _permit = permit;
+    subtitle::read_webvtt(&file_root, &file, &track)
+  })
+  .await??;
+  Ok(create_binary_resp(vtt.into_bytes(), Some("text/vtt; charset=utf-8".to_owned())))
+}
+
+#[derive(Serialize)]
+pub struct PlaybackInfoResp {
+  media: MediaInfo,
+  /// the server can transcode the file when it can not be played directly
+  transcode: bool,
+}
+
+pub async fn playback_info(
+  body: web::Json<MediaInfoReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let user_root = sess.get_user_root()?;
+  let file = saved_search::resolve_virtual_path(&body.file).unwrap_or_else(|| body.file.clone());
+  let file = rel_join(&user_root, &file)?;
+  let media = web::block(move || media_probe::media_info(&file_root, &file)).await??;
+  let transcode = transcode::ensure_enabled().is_ok();
+  Ok(create_resp(true, PlaybackInfoResp { media, transcode }, "done"))
+}
+
 pub async fn storage_info() -> Result<HttpResponse, AppError> {
   let r = vfs::storage_info_group_by_file_mime("").await?;
 
@@ -321,7 +737,14 @@ pub fn file_routers() -> Scope {
     .route("/upload", web::post().to(upload))
     .route("/search", web::post().to(search))
     .route("/search_content", web::post().to(search_content))
+    .route("/duplicates", web::post().to(duplicates))
+    .route("/dedupe", web::post().to(dedupe))
+    .route("/media_info", web::post().to(media_info))
+    .route("/playback_info", web::post().to(playback_info))
+    .route("/subtitles", web::post().to(subtitles))
+    .route("/read_subtitle", web::get().to(read_subtitle))
     .route("/delete_batch", web::post().to(delete_batch))
+    .route("/rename", web::post().to(rename))
     .route("/read_image", web::post().to(read_image_post))
     .route("/read_image", web::get().to(read_image_get))
     .route("/storage_info", web::post().to(storage_info))
@@ -330,6 +753,10 @@ pub fn file_routers() -> Scope {
       "/read_video_transcode",
       web::get().to(read_video_transcode_get),
     )
+    .route("/video_preview", web::get().to(video_preview_get))
+    .route("/hls/master.m3u8", web::get().to(hls_master_playlist))
+    .route("/hls/{height}/index.m3u8", web::get().to(hls_media_playlist))
+    .route("/hls/{height}/{segment}", web::get().to(hls_segment))
     .route("/{action}", web::get().to(fs_actions_get))
     .route("/{action}", web::post().to(fs_actions_post))
 }
@@ -1,10 +1,19 @@
+use crate::config;
+use crate::models::Album;
 use crate::schedulers::update_file_index::JOB_UPDATE_GALLERY;
+use crate::utils::album::{self, AlbumSummary};
 use crate::utils::error::AppError;
-use crate::utils::gallery;
+use crate::utils::gallery::{self, BoundingBox, TimelineGranularity};
+use crate::utils::image_hash;
 use crate::utils::response::create_resp;
 use crate::utils::response::EmptyResponseData;
+use crate::utils::session::SessionUtils;
+use crate::utils::vfs::rel_join;
 use crate::AppData;
+use actix_session::Session;
+use actix_web::http::StatusCode;
 use actix_web::{web, HttpResponse, Scope};
+use serde::Deserialize;
 use std::borrow::Borrow;
 
 pub async fn list(state: web::Data<AppData>) -> Result<HttpResponse, AppError> {
@@ -32,9 +41,291 @@ pub async fn get_job_status() -> Result<HttpResponse, AppError> {
   Ok(resp)
 }
 
+#[derive(Deserialize)]
+pub struct SimilarImagesReq {
+  /// image path relative to user root
+  file: String,
+  /// max hamming distance (0-16), defaults to `similar_image_threshold` in config
+  threshold: Option<u32>,
+}
+
+pub async fn similar_images(
+  body: web::Json<SimilarImagesReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let threshold = body.threshold.unwrap_or(config!(similar_image_threshold));
+  let file = rel_join(&user_root, &body.file)?;
+  let images =
+    web::block(move || image_hash::find_similar_images(&user_root, &file, threshold)).await??;
+  Ok(create_resp(true, images, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct NearDuplicatesReq {
+  threshold: Option<u32>,
+}
+
+pub async fn near_duplicates(
+  body: web::Json<NearDuplicatesReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let threshold = body.threshold.unwrap_or(config!(similar_image_threshold));
+  let groups =
+    web::block(move || image_hash::find_near_duplicate_groups(&user_root, threshold)).await??;
+  Ok(create_resp(true, groups, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct TimelineReq {
+  /// year, month or day
+  granularity: Option<String>,
+}
+
+pub async fn timeline(body: web::Json<TimelineReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let granularity = body.granularity.as_deref().unwrap_or("month");
+  let granularity = TimelineGranularity::parse(granularity)
+    .ok_or(AppError::new("granularity must be year, month or day").with_status(StatusCode::BAD_REQUEST))?;
+  let r = web::block(move || gallery::timeline_buckets(&user_root, granularity)).await??;
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct TimelinePhotosReq {
+  /// capture time range in millis, inclusive
+  from: Option<i64>,
+  to: Option<i64>,
+  cursor: Option<String>,
+  limit: Option<usize>,
+}
+
+pub async fn timeline_photos(
+  body: web::Json<TimelinePhotosReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let TimelinePhotosReq {
+    from,
+    to,
+    cursor,
+    limit,
+  } = body.into_inner();
+  let limit = limit.unwrap_or(100).min(500);
+  let r = web::block(move || {
+    gallery::timeline_photos(&user_root, from, to, cursor.as_deref(), limit)
+  })
+  .await??;
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct CreateAlbumReq {
+  name: String,
+  sort_order: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateAlbumReq {
+  id: i32,
+  name: Option<String>,
+  /// path relative to user root, empty to use the first item
+  cover: Option<String>,
+  sort_order: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AlbumIdReq {
+  id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct AlbumItemsReq {
+  id: i32,
+  /// paths relative to user root
+  files: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AlbumPhotosReq {
+  id: i32,
+  offset: Option<usize>,
+  limit: Option<usize>,
+}
+
+fn album_for_user(user_root: &str, mut album: Album) -> Album {
+  album.cover = album.cover.map(|c| gallery::strip_user_root(user_root, &c));
+  album
+}
+
+fn files_for_db(user_root: &str, files: &[String]) -> Result<Vec<String>, AppError> {
+  files.iter().map(|f| rel_join(user_root, f)).collect()
+}
+
+pub async fn list_albums(sess: Session) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let user_root = sess.get_user_root()?;
+  let albums: Vec<AlbumSummary> = album::list_albums(&username)?
+    .into_iter()
+    .map(|a| AlbumSummary {
+      album: album_for_user(&user_root, a.album),
+      count: a.count,
+    })
+    .collect();
+  Ok(create_resp(true, albums, "done"))
+}
+
+pub async fn create_album(
+  body: web::Json<CreateAlbumReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let sort_order = body.sort_order.as_deref().unwrap_or("manual");
+  let r = album::create_album(&username, body.name.trim(), sort_order)?;
+  Ok(create_resp(true, r, "done"))
+}
+
+pub async fn update_album(
+  body: web::Json<UpdateAlbumReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let user_root = sess.get_user_root()?;
+  let cover = match body.cover.as_deref() {
+    Some("") => Some("".to_owned()),
+    Some(c) => Some(rel_join(&user_root, c)?),
+    None => None,
+  };
+  let r = album::update_album(
+    &username,
+    body.id,
+    body.name.as_deref().map(|n| n.trim()),
+    cover.as_deref(),
+    body.sort_order.as_deref(),
+  )?;
+  Ok(create_resp(true, album_for_user(&user_root, r), "done"))
+}
+
+pub async fn delete_album(body: web::Json<AlbumIdReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  album::delete_album(&username, body.id)?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+pub async fn add_album_items(
+  body: web::Json<AlbumItemsReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let files = files_for_db(&sess.get_user_root()?, &body.files)?;
+  album::add_items(&username, body.id, &files)?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+pub async fn remove_album_items(
+  body: web::Json<AlbumItemsReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let files = files_for_db(&sess.get_user_root()?, &body.files)?;
+  album::remove_items(&username, body.id, &files)?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+pub async fn reorder_album_items(
+  body: web::Json<AlbumItemsReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let files = files_for_db(&sess.get_user_root()?, &body.files)?;
+  album::reorder_items(&username, body.id, &files)?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+pub async fn album_photos(
+  body: web::Json<AlbumPhotosReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let user_root = sess.get_user_root()?;
+  let AlbumPhotosReq { id, offset, limit } = body.into_inner();
+  let offset = offset.unwrap_or(0);
+  let limit = limit.unwrap_or(100).min(500);
+  let mut photos = web::block(move || album::album_photos(&username, id, offset, limit)).await??;
+  for p in photos.iter_mut() {
+    p.file_path = gallery::strip_user_root(&user_root, &p.file_path);
+  }
+  Ok(create_resp(true, photos, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct GeoReq {
+  south: f64,
+  west: f64,
+  north: f64,
+  east: f64,
+  zoom: u32,
+}
+
+pub async fn geo(body: web::Json<GeoReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let GeoReq {
+    south,
+    west,
+    north,
+    east,
+    zoom,
+  } = body.into_inner();
+  let bbox = BoundingBox {
+    south,
+    west,
+    north,
+    east,
+  };
+  let r = web::block(move || gallery::geo_clusters(&user_root, &bbox, zoom)).await??;
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct GeoPhotosReq {
+  /// cluster id returned by `/gallery/geo`
+  cluster: String,
+  offset: Option<usize>,
+  limit: Option<usize>,
+}
+
+pub async fn geo_photos(body: web::Json<GeoPhotosReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let GeoPhotosReq {
+    cluster,
+    offset,
+    limit,
+  } = body.into_inner();
+  let offset = offset.unwrap_or(0);
+  let limit = limit.unwrap_or(100).min(500);
+  let r =
+    web::block(move || gallery::geo_cluster_photos(&user_root, &cluster, offset, limit)).await??;
+  Ok(create_resp(true, r, "done"))
+}
+
 pub fn gallery_routers() -> Scope {
   web::scope("/gallery")
     .route("/list", web::post().to(list))
     .route("/update_index", web::post().to(update_immediate))
     .route("/get_job_status", web::post().to(get_job_status))
+    .route("/similar", web::post().to(similar_images))
+    .route("/near_duplicates", web::post().to(near_duplicates))
+    .route("/timeline", web::post().to(timeline))
+    .route("/timeline/photos", web::post().to(timeline_photos))
+    .route("/albums/list", web::post().to(list_albums))
+    .route("/albums/create", web::post().to(create_album))
+    .route("/albums/update", web::post().to(update_album))
+    .route("/albums/delete", web::post().to(delete_album))
+    .route("/albums/add", web::post().to(add_album_items))
+    .route("/albums/remove", web::post().to(remove_album_items))
+    .route("/albums/reorder", web::post().to(reorder_album_items))
+    .route("/albums/photos", web::post().to(album_photos))
+    .route("/geo", web::post().to(geo))
+    .route("/geo/photos", web::post().to(geo_photos))
 }
@@ -0,0 +1,172 @@
+use crate::models::MusicTrack;
+use crate::utils::error::AppError;
+use crate::utils::gallery::strip_user_root;
+use crate::utils::music;
+use crate::utils::saved_search;
+use crate::utils::response::{
+  create_cacheable_binary_resp, create_not_modified_resp, create_resp, create_unsized_stream_resp,
+};
+use crate::utils::session::SessionUtils;
+use crate::utils::transcode::{self, AudioCodec};
+use crate::utils::vfs::rel_join;
+use crate::AppData;
+use actix_session::Session;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, Scope};
+use serde::Deserialize;
+use tokio_util::io::ReaderStream;
+
+/// covers are keyed by the modified time of the track, so they can be cached for long
+const COVER_MAX_AGE: u32 = 3600 * 24;
+const DEFAULT_COVER_SIZE: u32 = 300;
+const DEFAULT_BITRATE: u32 = 128;
+
+fn tracks_for_user(user_root: &str, mut tracks: Vec<MusicTrack>) -> Vec<MusicTrack> {
+  for t in tracks.iter_mut() {
+    t.file_path = strip_user_root(user_root, &t.file_path);
+  }
+  tracks
+}
+
+pub async fn artists(sess: Session) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let r = web::block(move || music::list_artists(&user_root)).await??;
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct AlbumsReq {
+  artist: Option<String>,
+}
+
+pub async fn albums(body: web::Json<AlbumsReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let artist = body.into_inner().artist;
+  let mut r = {
+    let user_root = user_root.clone();
+    web::block(move || music::list_albums(&user_root, artist.as_deref())).await??
+  };
+  for a in r.iter_mut() {
+    a.cover = a.cover.as_ref().map(|c| strip_user_root(&user_root, c));
+  }
+  Ok(create_resp(true, r, "done"))
+}
+
+#[derive(Deserialize)]
+pub struct TracksReq {
+  artist: Option<String>,
+  album: Option<String>,
+  offset: Option<usize>,
+  limit: Option<usize>,
+}
+
+pub async fn tracks(body: web::Json<TracksReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let TracksReq {
+    artist,
+    album,
+    offset,
+    limit,
+  } = body.into_inner();
+  let offset = offset.unwrap_or(0);
+  let limit = limit.unwrap_or(500).min(2000);
+  let r = {
+    let user_root = user_root.clone();
+    web::block(move || {
+      music::list_tracks(&user_root, artist.as_deref(), album.as_deref(), offset, limit)
+    })
+    .await??
+  };
+  Ok(create_resp(true, tracks_for_user(&user_root, r), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct SearchReq {
+  keyword: String,
+  limit: Option<usize>,
+}
+
+pub async fn search(body: web::Json<SearchReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let SearchReq { keyword, limit } = body.into_inner();
+  if keyword.trim().is_empty() {
+    return Err(AppError::new("keyword is empty").with_status(StatusCode::BAD_REQUEST));
+  }
+  let limit = limit.unwrap_or(100).min(500);
+  let r = {
+    let user_root = user_root.clone();
+    web::block(move || music::search_tracks(&user_root, &keyword, limit)).await??
+  };
+  Ok(create_resp(true, tracks_for_user(&user_root, r), "done"))
+}
+
+#[derive(Deserialize)]
+pub struct CoverReq {
+  file: String,
+  resize: Option<u32>,
+}
+
+pub async fn cover(
+  query: web::Query<CoverReq>,
+  req: HttpRequest,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let file = saved_search::resolve_virtual_path(&query.file).unwrap_or_else(|| query.file.clone());
+  let file = rel_join(&sess.get_user_root()?, &file)?;
+  let dimension = query.resize.unwrap_or(DEFAULT_COVER_SIZE);
+  let entry = web::block(move || music::cover_entry(&file_root, &file, dimension)).await??;
+  let if_none_match = req
+    .headers()
+    .get(header::IF_NONE_MATCH)
+    .and_then(|v| v.to_str().ok());
+  if if_none_match.is_some_and(|v| v.split(',').any(|t| t.trim() == entry.etag)) {
+    return Ok(create_not_modified_resp(&entry.etag, COVER_MAX_AGE));
+  }
+  let etag = entry.etag.clone();
+  let mime = entry.mime.to_owned();
+  let data = web::block(move || music::load_cover(&entry)).await??;
+  Ok(create_cacheable_binary_resp(data, Some(mime), &etag, COVER_MAX_AGE))
+}
+
+#[derive(Deserialize)]
+pub struct StreamReq {
+  file: String,
+  /// opus or mp3
+  format: String,
+  /// kbps
+  bitrate: Option<u32>,
+}
+
+/// Transcoded audio for clients which can not play the original or need a smaller stream,
+/// the original is served by `/file/read`.
+pub async fn stream(
+  query: web::Query<StreamReq>,
+  state: web::Data<AppData>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let codec = AudioCodec::parse(&query.format)
+    .ok_or(AppError::new("format must be opus or mp3").with_status(StatusCode::BAD_REQUEST))?;
+  let file_root = state.read().unwrap().config.file_root.clone();
+  let file = saved_search::resolve_virtual_path(&query.file).unwrap_or_else(|| query.file.clone());
+  let file = rel_join(&sess.get_user_root()?, &file)?;
+  let username = sess.get_user_data()?.username;
+  let bitrate = query.bitrate.unwrap_or(DEFAULT_BITRATE);
+  let audio = transcode::audio(&file_root.join(file), codec, bitrate, &username).await?;
+  Ok(create_unsized_stream_resp(
+    ReaderStream::new(audio),
+    Some(codec.mime().to_owned()),
+    None,
+  ))
+}
+
+pub fn music_routers() -> Scope {
+  web::scope("/music")
+    .route("/artists", web::post().to(artists))
+    .route("/albums", web::post().to(albums))
+    .route("/tracks", web::post().to(tracks))
+    .route("/search", web::post().to(search))
+    .route("/cover", web::get().to(cover))
+    .route("/stream", web::get().to(stream))
+}
@@ -0,0 +1,20 @@
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Scope};
+
+use crate::utils::{error::AppError, push, session::SessionUtils};
+
+pub async fn subscribe(sess: Session) -> Result<HttpResponse, AppError> {
+  let user_root = sess.get_user_root()?;
+  let stream = push::subscribe(user_root);
+  Ok(
+    HttpResponse::Ok()
+      .content_type("text/event-stream")
+      .append_header(("Cache-Control", "no-cache"))
+      .append_header(("X-Accel-Buffering", "no"))
+      .streaming(stream),
+  )
+}
+
+pub fn push_routers() -> Scope {
+  web::scope("/push").route("/subscribe", web::get().to(subscribe))
+}
@@ -0,0 +1,87 @@
+use actix_session::Session;
+use actix_web::{web, HttpResponse, Scope};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  models::SavedSearch,
+  utils::{
+    error::AppError,
+    response::{create_resp, EmptyResponseData},
+    saved_search::{self, SavedSearchQuery},
+    session::SessionUtils,
+  },
+};
+
+#[derive(Deserialize)]
+pub struct SaveSearchReq {
+  name: String,
+  query: SavedSearchQuery,
+}
+
+#[derive(Deserialize)]
+pub struct SavedSearchIdReq {
+  id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct RunSavedSearchReq {
+  name: Option<String>,
+  query: Option<SavedSearchQuery>,
+}
+
+#[derive(Serialize)]
+pub struct SavedSearchResp {
+  #[serde(flatten)]
+  saved: SavedSearch,
+  query: SavedSearchQuery,
+}
+
+fn to_resp(saved: SavedSearch) -> Result<SavedSearchResp, AppError> {
+  let query = saved_search::parse_query(&saved)?;
+  Ok(SavedSearchResp { saved, query })
+}
+
+pub async fn list(sess: Session) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let searches = saved_search::list_saved_searches(&username)?
+    .into_iter()
+    .map(to_resp)
+    .collect::<Result<Vec<_>, _>>()?;
+  Ok(create_resp(true, searches, "done"))
+}
+
+pub async fn save(body: web::Json<SaveSearchReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let saved = saved_search::save_search(&username, body.name.trim(), &body.query)?;
+  Ok(create_resp(true, to_resp(saved)?, "done"))
+}
+
+pub async fn delete(
+  body: web::Json<SavedSearchIdReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  saved_search::delete_saved_search(&username, body.id)?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+/// Run a saved search by name, or an unsaved query to preview it before saving.
+pub async fn run(body: web::Json<RunSavedSearchReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let user_root = sess.get_user_root()?;
+  let query = match (&body.name, &body.query) {
+    (_, Some(query)) => query.clone(),
+    (Some(name), None) => saved_search::parse_query(&saved_search::get_saved_search(&username, name)?)?,
+    (None, None) => SavedSearchQuery::default(),
+  };
+  let files = web::block(move || saved_search::run_saved_search(&user_root, &query)).await??;
+  Ok(create_resp(true, files, "done"))
+}
+
+pub fn saved_search_routers() -> Scope {
+  web::scope("/saved_search")
+    .route("/list", web::post().to(list))
+    .route("/save", web::post().to(save))
+    .route("/delete", web::post().to(delete))
+    .route("/run", web::post().to(run))
+}
@@ -0,0 +1,103 @@
+use actix_session::Session;
+use actix_web::{http::StatusCode, web, HttpResponse, Scope};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+  models::{NewWebhook, Webhook},
+  utils::{
+    error::AppError,
+    response::{create_resp, EmptyResponseData},
+    session::SessionUtils,
+    webhook::{self, WEBHOOK_EVENT_TYPES},
+  },
+};
+
+#[derive(Deserialize)]
+pub struct CreateWebhookReq {
+  url: String,
+  path_prefix: Option<String>,
+  event_types: Option<Vec<String>>,
+  secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CreateWebhookResp {
+  webhook: Webhook,
+  secret: String,
+}
+
+#[derive(Deserialize)]
+pub struct WebhookIdReq {
+  id: i32,
+}
+
+pub async fn list(sess: Session) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let hooks = webhook::list_webhooks(&username)?;
+  Ok(create_resp(true, hooks, "done"))
+}
+
+pub async fn create(
+  body: web::Json<CreateWebhookReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  webhook::validate_url(&body.url).await?;
+  let event_types = body.event_types.clone().unwrap_or_default();
+  if let Some(t) = event_types
+    .iter()
+    .find(|t| !WEBHOOK_EVENT_TYPES.contains(&t.as_str()))
+  {
+    return Err(AppError::new(&format!("unknown event type: {t}")).with_status(StatusCode::BAD_REQUEST));
+  }
+  let secret = body
+    .secret
+    .clone()
+    .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+  let created_at = SystemTime::now()
+    .duration_since(UNIX_EPOCH)?
+    .as_millis()
+    .to_string();
+  let hook = webhook::create_webhook(NewWebhook {
+    username: &username,
+    url: &body.url,
+    path_prefix: body.path_prefix.as_deref().unwrap_or(""),
+    event_types: &event_types.join(","),
+    secret: &secret,
+    created_at: &created_at,
+  })?;
+  Ok(create_resp(true, CreateWebhookResp { webhook: hook, secret }, "done"))
+}
+
+pub async fn delete(body: web::Json<WebhookIdReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  webhook::delete_webhook(&username, body.id)?;
+  Ok(create_resp(true, EmptyResponseData::new(), "done"))
+}
+
+pub async fn deliveries(
+  body: web::Json<WebhookIdReq>,
+  sess: Session,
+) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let hook = webhook::get_webhook(&username, body.id)?;
+  let r = webhook::list_deliveries(hook.id, 100)?;
+  Ok(create_resp(true, r, "done"))
+}
+
+pub async fn test(body: web::Json<WebhookIdReq>, sess: Session) -> Result<HttpResponse, AppError> {
+  let username = sess.get_user_data()?.username;
+  let hook = webhook::get_webhook(&username, body.id)?;
+  let r = webhook::test_delivery(&hook).await?;
+  Ok(create_resp(r.success, r, "done"))
+}
+
+pub fn webhook_routers() -> Scope {
+  web::scope("/webhook")
+    .route("/list", web::post().to(list))
+    .route("/create", web::post().to(create))
+    .route("/delete", web::post().to(delete))
+    .route("/deliveries", web::post().to(deliveries))
+    .route("/test", web::post().to(test))
+}
@@ -3,12 +3,13 @@ use clokwerk::{Job, ScheduleHandle, Scheduler, TimeUnits};
 use lazy_static::lazy_static;
 use serde::Serialize;
 use std::{
-  collections::HashMap,
-  path::{PathBuf, StripPrefixError},
+  collections::{HashMap, HashSet},
+  path::{Path, PathBuf, StripPrefixError},
   sync::{Arc, Mutex, RwLock},
   thread::{self, sleep},
   time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tracing::{debug, warn};
 use walkdir::WalkDir;
 
 use crate::{
@@ -16,9 +17,23 @@ use crate::{
   db::SHARED_DB_CONN,
   models::{FileIndex, NewFileIndex},
   utils::{
+    album,
+    archive::{read_archive_entries, virtual_path, ArchiveFormat},
+    derived_table,
     doc_parser::try_parse_sync,
+    duplicates,
+    image_hash,
+    media_metadata,
+    media_probe,
+    music,
     error::AppError,
+    filename_index::{self, FilenameDoc},
+    path::{folder_like_pattern, like_escape},
+    push::{publish, PushMessage},
     search_engine::{self, insert_docs, Doc},
+    thumbnail,
+    video_preview,
+    vfs,
   }, conv_err,
 };
 
@@ -27,6 +42,11 @@ lazy_static! {
     Arc::new(Mutex::new(UpdateGalleryJob::new()));
 }
 
+/// paths deleted from the file index per statement
+const DELETE_BATCH_SIZE: usize = 500;
+/// files indexed per transaction after file system events
+const INSERT_BATCH_SIZE: usize = 25;
+
 conv_err!(StripPrefixError);
 conv_err!(walkdir::Error);
 
@@ -69,35 +89,96 @@ impl UpdateGalleryJob {
     use diesel::prelude::*;
     let mut conn = SHARED_DB_CONN.lock().unwrap();
     let conn = &mut *conn;
+    // documents of unchanged files are kept across runs, only those of files which are gone
+    // are deleted from the search index
+    let current: HashSet<String> = file_index
+      .filter(updated_at.eq(&updated_at_str))
+      .select(file_path)
+      .load::<String>(conn)?
+      .into_iter()
+      .collect();
+    let gone: Vec<String> = file_index
+      .filter(updated_at.is_not(&updated_at_str))
+      .select(file_path)
+      .distinct()
+      .load::<String>(conn)?
+      .into_iter()
+      .filter(|p| !current.contains(p))
+      .collect();
     let _ = diesel::delete(table.filter(updated_at.is_not(&updated_at_str)))
       .execute(conn)
       .unwrap();
-    search_engine::cleanup(&updated_at_str).unwrap();
+    search_engine::delete(&gone)?;
+    search_engine::commit()?;
+    filename_index::cleanup(updated_at_str.parse().unwrap_or(0))?;
     // let max_stale_secs = 3600 * 24 * 7;
     // search_engine::cleanup_stale_data(max_stale_secs).unwrap();
     Ok(())
   }
 
+  /// Forget `files` and everything stored below them, the children of a directory and the
+  /// entries of an archive.
   pub fn delete_file_indices(files: Vec<String>) -> Result<(), AppError> {
     use crate::schema::file_index::dsl::*;
     use crate::schema::file_index::table;
     use diesel::prelude::*;
     let mut conn = SHARED_DB_CONN.lock().unwrap();
     let conn = &mut *conn;
-    let effect = diesel::delete(table.filter(file_path.eq_any(&files)))
-      .execute(conn)
-      .unwrap();
-    println!("delete effect {effect} {files:?}");
-    search_engine::delete(&files).unwrap();
+    let mut paths = files.clone();
+    for f in files.iter().filter(|f| !f.is_empty()) {
+      let mut patterns = vec![folder_like_pattern(f)];
+      if ArchiveFormat::from_path(f).is_some() {
+        patterns.push(like_escape(&virtual_path(f, "")) + "%");
+      }
+      for pattern in patterns {
+        let below: Vec<String> = file_index
+          .filter(file_path.like(pattern).escape('\\'))
+          .select(file_path)
+          .distinct()
+          .load(conn)?;
+        paths.extend(below);
+      }
+    }
+    paths.sort();
+    paths.dedup();
+    let mut effect = 0;
+    for chunk in paths.chunks(DELETE_BATCH_SIZE) {
+      effect += diesel::delete(table.filter(file_path.eq_any(chunk))).execute(conn)?;
+    }
+    debug!("delete effect {effect} {files:?}");
+    search_engine::delete(&paths)?;
+    filename_index::delete(&paths)?;
+    duplicates::forget_hashes(conn, &paths)?;
+    image_hash::forget_image_hashes(conn, &paths)?;
+    media_metadata::forget_media_metadata(conn, &paths)?;
+    media_probe::forget_media_probes(conn, &paths)?;
+    music::forget_music_tracks(conn, &paths)?;
+    album::forget_album_items(conn, &paths)?;
     Ok(())
   }
 
+  /// Index `files` and, for directories, everything below them, e.g. after a directory was
+  /// renamed.
   pub fn update_file_indices(files: Vec<String>, file_root: &PathBuf) -> Result<(), AppError> {
     let now = SystemTime::now()
       .duration_since(UNIX_EPOCH)?
       .as_millis()
       .to_string();
-    Self::insert_files_into_db(files, now, file_root).unwrap();
+    let follow_link = config!(indexing_follow_link);
+    let mut paths = vec![];
+    for f in files {
+      let p = file_root.join(&f);
+      paths.push(f);
+      if p.is_dir() {
+        for entry in WalkDir::new(&p).follow_links(follow_link).min_depth(1) {
+          let entry = entry?;
+          paths.push(entry.path().strip_prefix(file_root)?.to_string_lossy().to_string());
+        }
+      }
+    }
+    for chunk in paths.chunks(INSERT_BATCH_SIZE) {
+      Self::insert_files_into_db(chunk.to_vec(), now.clone(), file_root)?;
+    }
     Ok(())
   }
 
@@ -116,12 +197,26 @@ impl UpdateGalleryJob {
       .filter(file_path.eq_any(&images))
       .load::<FileIndex>(conn)?;
 
-    let set: HashMap<String, FileIndex> = exists
-      .into_iter()
-      .map(|img| (img.file_path.clone(), img))
-      .collect();
+    // rows of previous runs are kept until the cleanup, the latest one describes the file
+    let mut set: HashMap<String, FileIndex> = HashMap::new();
+    for row in exists {
+      match set.get(&row.file_path) {
+        Some(r) if r.updated_at.parse::<u64>().unwrap_or(0) >= row.updated_at.parse().unwrap_or(0) => {}
+        _ => {
+          set.insert(row.file_path.clone(), row);
+        }
+      }
+    }
+    // a rebuild starts from an empty search index, every file has to be parsed again
+    let rebuilding = search_engine::is_rebuilding();
     let mut to_insert_docs = vec![];
     let mut to_insert: Vec<NewFileIndex> = vec![];
+    let mut filename_docs = vec![];
+    let mut changed_files = vec![];
+    // files and archive entries parsed again, their old documents are replaced
+    let mut reparsed = vec![];
+    let index_archives = config!(index_archives);
+    let index_archive_text = config!(index_archive_text);
 
     for f in images {
       let p = file_root.join(&f);
@@ -144,25 +239,121 @@ impl UpdateGalleryJob {
         .to_string();
       let file_name_ = p.file_name().unwrap().to_string_lossy().to_string();
 
-      let mut should_update = true;
-      if let Some(last) = last {
-        if last.modified_at != modified_at_ {
-          should_update = true;
-        }
-      } else {
-        should_update = true;
+      if meta.is_file() && last.is_none_or(|l| l.modified_at != modified_at_) {
+        changed_files.push(f.clone());
       }
+
+      let should_update = rebuilding
+        || last.is_none_or(|l| l.size != meta.len() as i64 || l.modified_at != modified_at_);
       if should_update {
-        let body = try_parse_sync(&path_str, &mime_joined, meta.len()).map_or(None, |v| v);
-        if let Some(body) = body {
+        reparsed.push(f.clone());
+        let pages = try_parse_sync(&path_str, &mime_joined, meta.len()).unwrap_or_else(|err| {
+          warn!("fail to parse content of {path_str}: {err}");
+          None
+        });
+        let parent_dir = Path::new(&f)
+          .parent()
+          .map_or("".to_owned(), |p| p.to_string_lossy().to_string());
+        for page in pages.unwrap_or_default() {
           to_insert_docs.push(Doc {
-            body,
+            body: page.body,
+            page: page.page,
             path: f.clone(),
             name: file_name_.clone(),
+            size: meta.len(),
+            modified: modified_at_.parse().unwrap_or(0),
+            mime: mime.first().cloned().unwrap_or_default(),
+            dir: parent_dir.clone(),
           })
         }
       }
 
+      let archive_format = ArchiveFormat::from_path(&f).filter(|_| index_archives && meta.is_file());
+      let stored_entries = match (should_update, last, archive_format) {
+        (false, Some(last), Some(_)) => file_index
+          .filter(file_path.like(like_escape(&virtual_path(&f, "")) + "%").escape('\\'))
+          .filter(updated_at.eq(&last.updated_at))
+          .load::<FileIndex>(conn)?,
+        _ => vec![],
+      };
+      // entries of an unchanged archive are taken over from the last run, archives without
+      // stored entries are read, e.g. after enabling `index_archives`
+      for entry in &stored_entries {
+        filename_docs.push(FilenameDoc {
+          path: entry.file_path.clone(),
+          name: entry.file_name.clone(),
+          is_dir: entry.is_dir,
+          modified: entry.modified_at.parse().unwrap_or(0),
+        });
+        to_insert.push(NewFileIndex {
+          file_name: entry.file_name.clone(),
+          file_path: entry.file_path.clone(),
+          size: entry.size,
+          format: entry.format.clone(),
+          username: entry.username.clone(),
+          created_at: entry.created_at.clone(),
+          modified_at: entry.modified_at.clone(),
+          updated_at: now.clone(),
+          is_dir: entry.is_dir,
+        });
+      }
+      if let (true, Some(archive_format)) = (stored_entries.is_empty(), archive_format) {
+        let entries = read_archive_entries(&path_str, archive_format, index_archive_text)
+          .unwrap_or_else(|err| {
+            warn!("fail to read entries of {path_str}: {err}");
+            vec![]
+          });
+        for entry in entries {
+          let entry_path = virtual_path(&f, &entry.path);
+          reparsed.push(entry_path.clone());
+          let entry_name = Path::new(&entry.path)
+            .file_name()
+            .map_or(entry.path.clone(), |n| n.to_string_lossy().to_string());
+          let entry_modified = entry.modified.map_or(modified_at_.clone(), |m| m.to_string());
+          let entry_mime = mime_guess::from_path(&entry.path)
+            .first()
+            .map(|m| m.to_string());
+          let entry_dir = Path::new(&entry_path)
+            .parent()
+            .map_or("".to_owned(), |p| p.to_string_lossy().to_string());
+          for page in entry.pages.unwrap_or_default() {
+            to_insert_docs.push(Doc {
+              body: page.body,
+              page: page.page,
+              path: entry_path.clone(),
+              name: entry_name.clone(),
+              size: entry.size,
+              modified: entry_modified.parse().unwrap_or(0),
+              mime: entry_mime.clone().unwrap_or_default(),
+              dir: entry_dir.clone(),
+            });
+          }
+          filename_docs.push(FilenameDoc {
+            path: entry_path.clone(),
+            name: entry_name.clone(),
+            is_dir: entry.is_dir,
+            modified: entry_modified.parse().unwrap_or(0),
+          });
+          to_insert.push(NewFileIndex {
+            file_name: entry_name,
+            file_path: entry_path,
+            size: entry.size as i64,
+            format: entry_mime,
+            username: "".to_owned(),
+            created_at: created_at_.clone(),
+            modified_at: entry_modified,
+            updated_at: now.clone(),
+            is_dir: entry.is_dir,
+          });
+        }
+      }
+
+      filename_docs.push(FilenameDoc {
+        path: f.clone(),
+        name: file_name_.clone(),
+        is_dir: meta.is_dir(),
+        modified: modified_at_.parse().unwrap_or(0),
+      });
       to_insert.push(NewFileIndex {
         file_name: file_name_.clone(),
         file_path: f,
@@ -175,11 +366,25 @@ impl UpdateGalleryJob {
         is_dir: meta.is_dir(),
       });
     }
+    search_engine::delete(&reparsed)?;
     insert_docs(to_insert_docs, &now)?;
-    diesel::insert_into(table).values(to_insert).execute(conn)?;
+    filename_index::upsert(filename_docs, now.parse().unwrap_or(0))?;
+    // archives add many rows, keep the bind parameters of one statement below sqlite's limit
+    for chunk in to_insert.chunks(100) {
+      diesel::insert_into(table).values(chunk).execute(conn)?;
+    }
+    video_preview::pregenerate(file_root, &changed_files);
+    thumbnail::pregenerate(file_root, changed_files);
     Ok(())
   }
 
+  fn publish_status(status: &JobStatus) {
+    publish(PushMessage::JobStatus {
+      job: "update_file_index".to_owned(),
+      status: status.clone(),
+    });
+  }
+
   pub fn get_status(&self) -> JobStatus {
     let status = self.status.read().unwrap().clone();
     status
@@ -190,30 +395,83 @@ impl UpdateGalleryJob {
     let file_root = self.file_root.clone();
     thread::spawn(move || {
       Self::update(status.clone(), file_root.as_ref().unwrap()).unwrap_or_else(|err| {
-        *status.write().unwrap() = JobStatus::Error(err.to_string());
+        let err = JobStatus::Error(err.to_string());
+        Self::publish_status(&err);
+        *status.write().unwrap() = err;
       });
     });
   }
 
-  fn update(status: Arc<RwLock<JobStatus>>, file_root: &PathBuf) -> Result<(), AppError> {
+  /// Rebuild the search index into a new directory in the background,
+  /// searches use the old index until the rebuild finished.
+  pub fn rebuild_search_index(&self) -> Result<(), AppError> {
+    // claimed before the new index is created, an update starting in between would
+    // otherwise make the rebuild return at once and switch to an almost empty index
+    if !Self::claim(&self.status) {
+      return Err(AppError::new("file index is updating, try again later"));
+    }
+    if let Err(err) = search_engine::begin_rebuild() {
+      *self.status.write().unwrap() = JobStatus::Idle;
+      Self::publish_status(&JobStatus::Idle);
+      return Err(err);
+    }
+    let status = self.status.clone();
+    let file_root = self.file_root.clone();
+    thread::spawn(move || {
+      Self::run_update(status.clone(), file_root.as_ref().unwrap())
+        .and_then(|_| search_engine::finish_rebuild())
+        .unwrap_or_else(|err| {
+          search_engine::abort_rebuild();
+          let err = JobStatus::Error(err.to_string());
+          Self::publish_status(&err);
+          *status.write().unwrap() = err;
+        });
+    });
+    Ok(())
+  }
+
+  /// run a full index update on the current thread
+  pub fn update_blocking(&self) -> Result<(), AppError> {
+    Self::update(self.status.clone(), self.file_root.as_ref().unwrap())
+  }
+
+  /// Mark the job as running, false when it already is.
+  fn claim(status: &RwLock<JobStatus>) -> bool {
     let mut status_lock = status.write().unwrap();
-    match *status_lock {
-      JobStatus::Idle => *status_lock = JobStatus::Running(0),
-      JobStatus::Running(_) => return Ok(()),
-      JobStatus::Error(_) => {
-        JobStatus::Running(0);
-      }
-    };
-    drop(status_lock);
+    if let JobStatus::Running(_) = *status_lock {
+      return false;
+    }
+    *status_lock = JobStatus::Running(0);
+    Self::publish_status(&status_lock);
+    true
+  }
+
+  fn update(status: Arc<RwLock<JobStatus>>, file_root: &Path) -> Result<(), AppError> {
+    if !Self::claim(&status) {
+      return Ok(());
+    }
+    Self::run_update(status, file_root)
+  }
 
-    let file_root = file_root.clone();
+  /// The full index update, the job must be claimed by the caller.
+  fn run_update(status: Arc<RwLock<JobStatus>>, file_root: &Path) -> Result<(), AppError> {
+    let file_root = file_root.to_path_buf();
     let now = SystemTime::now()
       .duration_since(UNIX_EPOCH)?
       .as_millis()
       .to_string();
     let mut images = vec![];
     let follow_link = config!(indexing_follow_link);
-    for entry in WalkDir::new(&file_root).follow_links(follow_link) {
+    let trash_dirs = vfs::trash_dirs()?;
+    let walker = WalkDir::new(&file_root)
+      .follow_links(follow_link)
+      .into_iter()
+      .filter_entry(|e| {
+        e.path()
+          .strip_prefix(&file_root)
+          .map_or(true, |p| !trash_dirs.contains(p))
+      });
+    for entry in walker {
       let entry = entry?;
       let dir = entry.path().strip_prefix(file_root.clone())?;
       images.push(dir.to_string_lossy().to_string());
@@ -224,6 +482,7 @@ impl UpdateGalleryJob {
         let mut status_lock = status.write().unwrap();
         if let JobStatus::Running(sum) = *status_lock {
           *status_lock = JobStatus::Running(sum + len);
+          Self::publish_status(&status_lock);
         }
         sleep(std::time::Duration::from_millis(200));
         drop(status_lock);
@@ -235,10 +494,27 @@ impl UpdateGalleryJob {
       let mut status_lock = status.write().unwrap();
       if let JobStatus::Running(sum) = *status_lock {
         *status_lock = JobStatus::Running(sum + len);
+        Self::publish_status(&status_lock);
       }
     }
     Self::cleanup_db(now.clone())?;
+    if config!(hash_files) {
+      derived_table::sync(&file_root, &duplicates::FILE_HASHES)?;
+    }
+    if config!(hash_images) {
+      derived_table::sync(&file_root, &image_hash::IMAGE_HASHES)?;
+    }
+    if config!(extract_media_metadata) {
+      derived_table::sync(&file_root, &media_metadata::MEDIA_METADATA)?;
+    }
+    if config!(probe_media) {
+      media_probe::update_media_probes(&file_root)?;
+    }
+    if config!(index_music) {
+      derived_table::sync(&file_root, &music::MUSIC_TRACKS)?;
+    }
     *status.write().unwrap() = JobStatus::Idle;
+    Self::publish_status(&JobStatus::Idle);
     Ok(())
   }
 
@@ -253,7 +529,9 @@ impl UpdateGalleryJob {
     let status = self.status.clone();
     let run = move || {
       Self::update(status.clone(), &file_root).unwrap_or_else(|err| {
-        *status.write().unwrap() = JobStatus::Error(err.to_string());
+        let err = JobStatus::Error(err.to_string());
+        Self::publish_status(&err);
+        *status.write().unwrap() = err;
       });
     };
     scheduler.every(1.days()).at_time(at_time).run(run);
@@ -1,5 +1,35 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    album_items (album_id, file_path) {
+        album_id -> Integer,
+        file_path -> Text,
+        position -> Integer,
+        added_at -> Text,
+    }
+}
+
+diesel::table! {
+    albums (id) {
+        id -> Integer,
+        username -> Text,
+        name -> Text,
+        cover -> Nullable<Text>,
+        sort_order -> Text,
+        created_at -> Text,
+        updated_at -> Text,
+    }
+}
+
+diesel::table! {
+    file_hashes (file_path) {
+        file_path -> Text,
+        size -> BigInt,
+        modified_at -> Text,
+        hash -> Text,
+    }
+}
+
 diesel::table! {
     file_index (file_path, updated_at) {
         file_name -> Text,
@@ -14,6 +44,78 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    image_hashes (file_path) {
+        file_path -> Text,
+        size -> BigInt,
+        modified_at -> Text,
+        dhash -> BigInt,
+    }
+}
+
+diesel::table! {
+    media_metadata (file_path) {
+        file_path -> Text,
+        size -> BigInt,
+        modified_at -> Text,
+        taken_at -> Nullable<BigInt>,
+        camera_make -> Nullable<Text>,
+        camera_model -> Nullable<Text>,
+        lens -> Nullable<Text>,
+        latitude -> Nullable<Double>,
+        longitude -> Nullable<Double>,
+        altitude -> Nullable<Double>,
+        width -> Nullable<Integer>,
+        height -> Nullable<Integer>,
+        orientation -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    media_probes (file_path) {
+        file_path -> Text,
+        size -> BigInt,
+        modified_at -> Text,
+        duration -> Nullable<Double>,
+        container -> Nullable<Text>,
+        video_codec -> Nullable<Text>,
+        audio_codec -> Nullable<Text>,
+        width -> Nullable<Integer>,
+        height -> Nullable<Integer>,
+        bitrate -> Nullable<BigInt>,
+        streams -> Text,
+    }
+}
+
+diesel::table! {
+    music_tracks (file_path) {
+        file_path -> Text,
+        size -> BigInt,
+        modified_at -> Text,
+        title -> Nullable<Text>,
+        artist -> Nullable<Text>,
+        album -> Nullable<Text>,
+        album_artist -> Nullable<Text>,
+        track_number -> Nullable<Integer>,
+        disc_number -> Nullable<Integer>,
+        year -> Nullable<Integer>,
+        genre -> Nullable<Text>,
+        duration -> Nullable<Double>,
+        bitrate -> Nullable<Integer>,
+        has_cover -> Bool,
+    }
+}
+
+diesel::table! {
+    saved_searches (id) {
+        id -> Integer,
+        username -> Text,
+        name -> Text,
+        query -> Text,
+        created_at -> Text,
+    }
+}
+
 diesel::table! {
     users (username) {
         username -> Text,
@@ -24,7 +126,43 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Integer,
+        webhook_id -> Integer,
+        event -> Text,
+        payload -> Text,
+        status_code -> Nullable<Integer>,
+        success -> Bool,
+        attempts -> Integer,
+        error -> Nullable<Text>,
+        created_at -> Text,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Integer,
+        username -> Text,
+        url -> Text,
+        path_prefix -> Text,
+        event_types -> Text,
+        secret -> Text,
+        created_at -> Text,
+    }
+}
+
 diesel::allow_tables_to_appear_in_same_query!(
+    album_items,
+    albums,
+    file_hashes,
     file_index,
+    image_hashes,
+    media_metadata,
+    media_probes,
+    music_tracks,
+    saved_searches,
     users,
+    webhook_deliveries,
+    webhooks,
 );
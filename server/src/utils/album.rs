@@ -0,0 +1,237 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::StatusCode;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer};
+use diesel::{sql_query, SqliteConnection};
+use serde::Serialize;
+
+use crate::{
+  db::SHARED_DB_CONN,
+  models::{Album, AlbumItem, NewAlbum, TimelinePhoto},
+};
+
+use super::error::AppError;
+
+pub const ALBUM_SORT_ORDERS: [&str; 4] = ["manual", "taken_at", "taken_at_desc", "added_at"];
+
+fn now_millis() -> Result<String, AppError> {
+  Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis().to_string())
+}
+
+fn check_sort_order(sort_order: &str) -> Result<(), AppError> {
+  if !ALBUM_SORT_ORDERS.contains(&sort_order) {
+    return Err(AppError::new(&format!("unknown sort order: {sort_order}")).with_status(StatusCode::BAD_REQUEST));
+  }
+  Ok(())
+}
+
+#[derive(Serialize)]
+pub struct AlbumSummary {
+  #[serde(flatten)]
+  pub album: Album,
+  pub count: i64,
+}
+
+pub fn list_albums(username_: &str) -> Result<Vec<AlbumSummary>, AppError> {
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let albums = {
+    use crate::schema::albums::dsl::*;
+    albums
+      .filter(username.eq(username_))
+      .order(name)
+      .load::<Album>(conn)?
+  };
+  let mut r = vec![];
+  for mut album in albums {
+    use crate::schema::album_items::dsl::*;
+    let count = album_items
+      .filter(album_id.eq(album.id))
+      .count()
+      .get_result::<i64>(conn)?;
+    if album.cover.is_none() {
+      album.cover = album_items
+        .filter(album_id.eq(album.id))
+        .order(position)
+        .select(file_path)
+        .first::<String>(conn)
+        .optional()?;
+    }
+    r.push(AlbumSummary { album, count });
+  }
+  Ok(r)
+}
+
+pub fn get_album(username_: &str, id_: i32) -> Result<Album, AppError> {
+  use crate::schema::albums::dsl::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = albums
+    .filter(username.eq(username_).and(id.eq(id_)))
+    .first::<Album>(conn)
+    .optional()?;
+  r.ok_or(AppError::new("album not found").with_status(StatusCode::NOT_FOUND))
+}
+
+pub fn create_album(username_: &str, name_: &str, sort_order_: &str) -> Result<Album, AppError> {
+  use crate::schema::albums::table;
+  check_sort_order(sort_order_)?;
+  let now = now_millis()?;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::insert_into(table)
+    .values(NewAlbum {
+      username: username_,
+      name: name_,
+      cover: None,
+      sort_order: sort_order_,
+      created_at: &now,
+      updated_at: &now,
+    })
+    .get_result::<Album>(conn)?;
+  Ok(r)
+}
+
+/// Change the name, cover or sort order of an album, `None` keeps the current value.
+/// `cover` is a path relative to file root, an empty string resets it to the first item.
+pub fn update_album(
+  username_: &str,
+  id_: i32,
+  name_: Option<&str>,
+  cover_: Option<&str>,
+  sort_order_: Option<&str>,
+) -> Result<Album, AppError> {
+  use crate::schema::albums::dsl::*;
+  let album = get_album(username_, id_)?;
+  if let Some(s) = sort_order_ {
+    check_sort_order(s)?;
+  }
+  let new_cover = match cover_ {
+    Some("") => None,
+    Some(c) => Some(c.to_owned()),
+    None => album.cover,
+  };
+  let now = now_millis()?;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::update(albums.filter(id.eq(id_)))
+    .set((
+      name.eq(name_.unwrap_or(&album.name)),
+      cover.eq(new_cover),
+      sort_order.eq(sort_order_.unwrap_or(&album.sort_order)),
+      updated_at.eq(now),
+    ))
+    .get_result::<Album>(conn)?;
+  Ok(r)
+}
+
+pub fn delete_album(username_: &str, id_: i32) -> Result<(), AppError> {
+  get_album(username_, id_)?;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  {
+    use crate::schema::album_items::dsl::*;
+    diesel::delete(album_items.filter(album_id.eq(id_))).execute(conn)?;
+  }
+  {
+    use crate::schema::albums::dsl::*;
+    diesel::delete(albums.filter(id.eq(id_))).execute(conn)?;
+  }
+  Ok(())
+}
+
+/// Append files (relative to file root) to an album, files already in it keep their position.
+pub fn add_items(username_: &str, id_: i32, files: &[String]) -> Result<(), AppError> {
+  use crate::schema::album_items::dsl::*;
+  use crate::schema::album_items::table;
+  get_album(username_, id_)?;
+  let now = now_millis()?;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let last = album_items
+    .filter(album_id.eq(id_))
+    .select(diesel::dsl::max(position))
+    .first::<Option<i32>>(conn)?
+    .unwrap_or(-1);
+  let items: Vec<AlbumItem> = files
+    .iter()
+    .enumerate()
+    .map(|(i, f)| AlbumItem {
+      album_id: id_,
+      file_path: f.clone(),
+      position: last + 1 + i as i32,
+      added_at: now.clone(),
+    })
+    .collect();
+  for chunk in items.chunks(100) {
+    diesel::insert_or_ignore_into(table).values(chunk).execute(conn)?;
+  }
+  Ok(())
+}
+
+pub fn remove_items(username_: &str, id_: i32, files: &[String]) -> Result<(), AppError> {
+  use crate::schema::album_items::dsl::*;
+  get_album(username_, id_)?;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  for chunk in files.chunks(100) {
+    diesel::delete(album_items.filter(album_id.eq(id_).and(file_path.eq_any(chunk)))).execute(conn)?;
+  }
+  Ok(())
+}
+
+/// Set the manual order, `files` first in the given order, the other items after them.
+pub fn reorder_items(username_: &str, id_: i32, files: &[String]) -> Result<(), AppError> {
+  use crate::schema::album_items::dsl::*;
+  get_album(username_, id_)?;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  conn.transaction::<_, AppError, _>(|conn| {
+    let rest: Vec<String> = album_items
+      .filter(album_id.eq(id_))
+      .filter(file_path.ne_all(files))
+      .order(position)
+      .select(file_path)
+      .load(conn)?;
+    for (i, f) in files.iter().chain(rest.iter()).enumerate() {
+      diesel::update(album_items.filter(album_id.eq(id_).and(file_path.eq(f))))
+        .set(position.eq(i as i32))
+        .execute(conn)?;
+    }
+    Ok(())
+  })
+}
+
+/// A page of album items in the sort order of the album, paths relative to file root.
+pub fn album_photos(
+  username_: &str,
+  id_: i32,
+  offset: usize,
+  limit: usize,
+) -> Result<Vec<TimelinePhoto>, AppError> {
+  let album = get_album(username_, id_)?;
+  let order = match album.sort_order.as_str() {
+    "taken_at" => "taken_at asc, file_path asc",
+    "taken_at_desc" => "taken_at desc, file_path desc",
+    "added_at" => "added_at desc, position asc",
+    _ => "position asc",
+  };
+  let sql = format!(
+    "select i.file_path as file_path, coalesce(max(f.file_name), '') as file_name, \
+    coalesce(max(f.size), 0) as size, \
+    coalesce(max(m.taken_at), cast(max(f.modified_at) as integer), 0) as taken_at, \
+    max(m.width) as width, max(m.height) as height, i.position as position, i.added_at as added_at \
+    from album_items i left join file_index f on f.file_path = i.file_path \
+    left join media_metadata m on m.file_path = i.file_path \
+    where i.album_id = ? group by i.file_path order by {order} limit ? offset ?"
+  );
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = sql_query(sql)
+    .bind::<Integer, _>(id_)
+    .bind::<BigInt, _>(limit as i64)
+    .bind::<BigInt, _>(offset as i64)
+    .load::<TimelinePhoto>(conn)?;
+  Ok(r)
+}
+
+/// drop deleted files from all albums
+pub fn forget_album_items(conn: &mut SqliteConnection, files: &[String]) -> Result<(), AppError> {
+  use crate::schema::album_items::dsl::*;
+  for chunk in files.chunks(100) {
+    diesel::delete(album_items.filter(file_path.eq_any(chunk))).execute(conn)?;
+  }
+  Ok(())
+}
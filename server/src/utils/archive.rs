@@ -0,0 +1,262 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use async_zip::read::seek::ZipFileReader;
+use async_zip::ZipEntry;
+use flate2::read::GzDecoder;
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+use tracing::warn;
+
+use super::doc_parser::{try_parse_bytes, DocPage};
+use super::error::AppError;
+
+/// separates the archive from the entry in virtual paths like `backup.zip!/docs/a.txt`
+pub const ARCHIVE_SEPARATOR: &str = "!/";
+/// entries after this are not indexed
+const MAX_ENTRIES: usize = 10000;
+/// tar files have no central directory and must be read through to list the entries, at most
+/// this many bytes of the tar stream are read, after decompression for gzipped tar files
+const MAX_TAR_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+/// text is only extracted from entries up to this size
+const MAX_ENTRY_TEXT_SIZE: u64 = 1024 * 1024 * 5;
+/// stop extracting text once this many bytes of an archive are read
+const MAX_ARCHIVE_TEXT_SIZE: u64 = 1024 * 1024 * 50;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ArchiveFormat {
+  Zip,
+  Tar,
+  TarGz,
+}
+
+impl ArchiveFormat {
+  pub fn from_path(file: &str) -> Option<Self> {
+    let file = file.to_lowercase();
+    if file.ends_with(".zip") {
+      Some(Self::Zip)
+    } else if file.ends_with(".tar") {
+      Some(Self::Tar)
+    } else if file.ends_with(".tar.gz") || file.ends_with(".tgz") {
+      Some(Self::TarGz)
+    } else {
+      None
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct ArchiveEntry {
+  /// path inside the archive without leading or trailing slash
+  pub path: String,
+  pub is_dir: bool,
+  pub size: u64,
+  /// modified time in millis, if recorded in the archive
+  pub modified: Option<u64>,
+  /// text of small text and pdf entries
+  pub pages: Option<Vec<DocPage>>,
+}
+
+/// Where a search result inside an archive lives, so the UI can open it in the archive viewer.
+#[derive(Debug, Serialize)]
+pub struct ArchiveLink {
+  pub archive: String,
+  pub entry: String,
+}
+
+impl ArchiveLink {
+  pub fn from_path(path: &str) -> Option<Self> {
+    let (archive, entry) = path.split_once(ARCHIVE_SEPARATOR)?;
+    Some(Self {
+      archive: archive.to_owned(),
+      entry: entry.to_owned(),
+    })
+  }
+}
+
+pub fn virtual_path(archive: &str, entry: &str) -> String {
+  format!("{archive}{ARCHIVE_SEPARATOR}{entry}")
+}
+
+/// List the entries of an archive, with `extract_text` the text of small entries is read too.
+pub fn read_archive_entries(
+  file: &str,
+  format: ArchiveFormat,
+  extract_text: bool,
+) -> Result<Vec<ArchiveEntry>, AppError> {
+  match format {
+    ArchiveFormat::Zip => read_zip_entries(file, extract_text),
+    ArchiveFormat::Tar => {
+      let f = BufReader::new(File::open(file)?);
+      read_tar_entries(file, f, extract_text)
+    }
+    ArchiveFormat::TarGz => {
+      let f = GzDecoder::new(BufReader::new(File::open(file)?));
+      read_tar_entries(file, f, extract_text)
+    }
+  }
+}
+
+/// Blocking reader of zip files for the indexer. async_zip, which also writes the zip
+/// downloads, only reads asynchronously, so each reader drives its own small runtime.
+pub struct ZipReader {
+  runtime: Runtime,
+  zip: ZipFileReader<tokio::io::BufReader<tokio::fs::File>>,
+}
+
+impl ZipReader {
+  pub fn open(file: &str) -> Result<Self, AppError> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()?;
+    let zip = runtime.block_on(async {
+      let f = tokio::fs::File::open(file).await?;
+      Ok::<_, AppError>(ZipFileReader::new(tokio::io::BufReader::new(f)).await?)
+    })?;
+    Ok(Self { runtime, zip })
+  }
+
+  /// entries of the central directory, in archive order
+  pub fn entries(&self) -> impl Iterator<Item = &ZipEntry> {
+    self.zip.file().entries().iter().map(|e| e.entry())
+  }
+
+  pub fn index_of(&self, name: &str) -> Option<usize> {
+    self.entries().position(|e| e.filename() == name)
+  }
+
+  /// Read at most `limit` bytes of the entry at `index`, fails for encrypted entries and
+  /// unsupported compression methods.
+  pub fn read(&mut self, index: usize, limit: u64) -> Result<Vec<u8>, AppError> {
+    let zip = &mut self.zip;
+    self.runtime.block_on(async move {
+      let entry = zip.entry(index).await?;
+      let mut buf = vec![];
+      entry.take(limit).read_to_end(&mut buf).await?;
+      Ok(buf)
+    })
+  }
+}
+
+fn normalize_entry_path(path: &str) -> String {
+  path.trim_start_matches("./").trim_matches('/').to_owned()
+}
+
+/// Text budget shared by all entries of one archive.
+struct TextExtractor {
+  enabled: bool,
+  remaining: u64,
+}
+
+impl TextExtractor {
+  fn new(enabled: bool) -> Self {
+    Self {
+      enabled,
+      remaining: MAX_ARCHIVE_TEXT_SIZE,
+    }
+  }
+
+  fn wants(&self, size: u64) -> bool {
+    self.enabled && size <= MAX_ENTRY_TEXT_SIZE && size <= self.remaining
+  }
+
+  fn extract(&mut self, name: &str, size: u64, reader: impl Read) -> Option<Vec<DocPage>> {
+    if !self.wants(size) {
+      return None;
+    }
+    let mut bytes = Vec::with_capacity(size as usize);
+    if let Err(err) = reader.take(size).read_to_end(&mut bytes) {
+      warn!("fail to read archive entry {name}: {err}");
+      return None;
+    }
+    self.remaining -= bytes.len() as u64;
+    try_parse_bytes(name, &bytes).unwrap_or_else(|err| {
+      warn!("fail to parse archive entry {name}: {err}");
+      None
+    })
+  }
+}
+
+fn read_zip_entries(file: &str, extract_text: bool) -> Result<Vec<ArchiveEntry>, AppError> {
+  let mut zip = ZipReader::open(file)?;
+  let mut text = TextExtractor::new(extract_text);
+  let mut entries = vec![];
+  // listed first, reading an entry needs the reader mutably
+  let listed: Vec<(String, bool, u64, u64)> = zip
+    .entries()
+    .take(MAX_ENTRIES)
+    .map(|e| {
+      let modified = e
+        .last_modification_date()
+        .as_chrono()
+        .single()
+        .map_or(0, |d| d.timestamp_millis().max(0) as u64);
+      (e.filename().to_owned(), e.dir(), e.uncompressed_size(), modified)
+    })
+    .collect();
+  for (i, (name, is_dir, size, modified)) in listed.into_iter().enumerate() {
+    let path = normalize_entry_path(&name);
+    if path.is_empty() {
+      continue;
+    }
+    let pages = if is_dir || !text.wants(size) {
+      None
+    } else {
+      match zip.read(i, size) {
+        Ok(bytes) => text.extract(&path, size, bytes.as_slice()),
+        Err(err) => {
+          warn!("fail to read entry {path} of {file}: {err}");
+          None
+        }
+      }
+    };
+    entries.push(ArchiveEntry {
+      path,
+      is_dir,
+      size,
+      modified: Some(modified),
+      pages,
+    });
+  }
+  Ok(entries)
+}
+
+fn read_tar_entries(
+  file: &str,
+  reader: impl Read,
+  extract_text: bool,
+) -> Result<Vec<ArchiveEntry>, AppError> {
+  if Path::new(file).metadata()?.len() > MAX_TAR_FILE_SIZE {
+    return Ok(vec![]);
+  }
+  // a small gzip file may inflate to far more, reading fails once the limit is reached
+  let mut archive = tar::Archive::new(reader.take(MAX_TAR_FILE_SIZE));
+  let mut text = TextExtractor::new(extract_text);
+  let mut entries = vec![];
+  for entry in archive.entries()?.take(MAX_ENTRIES) {
+    let entry = entry?;
+    let path = normalize_entry_path(&entry.path()?.to_string_lossy());
+    if path.is_empty() {
+      continue;
+    }
+    let header = entry.header();
+    let is_dir = header.entry_type().is_dir();
+    let modified = header.mtime().ok().map(|t| t * 1000);
+    let size = entry.size();
+    let pages = if is_dir {
+      None
+    } else {
+      text.extract(&path, size, entry)
+    };
+    entries.push(ArchiveEntry {
+      path,
+      is_dir,
+      size,
+      modified,
+      pages,
+    });
+  }
+  Ok(entries)
+}
@@ -1,6 +1,6 @@
 use std::{sync::{Arc, Mutex}, collections::HashMap, time::Duration};
 
-use diesel::{RunQueryDsl, SqliteConnection};
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
 use lazy_static::lazy_static;
 use serde::Serialize;
 
@@ -20,9 +20,13 @@ lazy_static!{
 }
 
 use crate::{
+  db::SHARED_DB_CONN,
   models::{NewUser, User},
   schema, utils::crypto::hash_pwd,
 };
+
+use super::error::AppError;
+
 pub fn auto_create_user(db: &mut SqliteConnection) {
   use crate::schema::users::dsl::*;
   let user = users.first::<User>(db);
@@ -42,6 +46,16 @@ pub fn auto_create_user(db: &mut SqliteConnection) {
   println!("create admin autmatically");
 }
 
+/// the user created automatically on first start (`user_type` 0) is the administrator
+pub fn is_admin(name: &str) -> Result<bool, AppError> {
+  use crate::schema::users::dsl::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let t = users
+    .filter(username.eq(name))
+    .select(user_type)
+    .first::<i32>(&mut *conn)?;
+  Ok(t == 0)
+}
 
 pub fn create_one_time_token(user: &str, module_prefix: &str, expire_secs: u64) -> OneTimeTokenInfo {
   let t = OneTimeTokenInfo {
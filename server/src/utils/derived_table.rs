@@ -0,0 +1,81 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+use tracing::warn;
+
+use crate::db::SHARED_DB_CONN;
+
+use super::archive::ARCHIVE_SEPARATOR;
+use super::error::AppError;
+
+/// rows are derived and written to the database in batches of this size
+pub const SYNC_BATCH_SIZE: usize = 100;
+
+/// path, size and modified time of a stored row
+pub type KnownRow = (String, i64, String);
+
+/// A table with one row per file of the file index, derived from the content of the file,
+/// e.g. content hashes or probe results. Rows remember the size and modified time of their
+/// file, so unchanged files are not read again.
+pub struct DerivedTable<T> {
+  /// what is derived, for logs
+  pub what: &'static str,
+  /// whether a file gets a row, by the mime types and size stored in the file index
+  pub wanted: fn(format: &str, size: i64) -> bool,
+  /// path, size and modified time of the stored rows
+  pub load_known: fn(&mut SqliteConnection) -> Result<Vec<KnownRow>, AppError>,
+  /// read the row of a file, relative to file root, from its content
+  pub derive: fn(file_root: &Path, file: &str, size: i64, modified_at: &str) -> Result<T, AppError>,
+  /// insert or replace the rows of `batch` and clear it
+  pub save: fn(batch: &mut Vec<T>) -> Result<(), AppError>,
+  /// delete the rows of `files`
+  pub forget: fn(&mut SqliteConnection, files: &[String]) -> Result<(), AppError>,
+}
+
+/// Bring `table` in line with the file index: rows of new and changed files are derived,
+/// rows of files which are gone are deleted. Must run after the file index is cleaned up.
+pub fn sync<T>(file_root: &Path, table: &DerivedTable<T>) -> Result<(), AppError> {
+  let (files, known) = {
+    use crate::schema::file_index::dsl::*;
+    let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+    let files: Vec<(String, i64, String, Option<String>)> = file_index
+      .filter(is_dir.eq(false))
+      .select((file_path, size, modified_at, format))
+      .load(conn)?;
+    let known: HashMap<String, (i64, String)> = (table.load_known)(conn)?
+      .into_iter()
+      .map(|(p, s, m)| (p, (s, m)))
+      .collect();
+    (files, known)
+  };
+
+  let mut batch = vec![];
+  let mut current = HashSet::new();
+  for (path, size, modified_at, format) in files {
+    // entries of archives are not real files
+    if path.contains(ARCHIVE_SEPARATOR) || !(table.wanted)(format.as_deref().unwrap_or(""), size) {
+      continue;
+    }
+    current.insert(path.clone());
+    if known.get(&path).is_some_and(|(s, m)| *s == size && *m == modified_at) {
+      continue;
+    }
+    match (table.derive)(file_root, &path, size, &modified_at) {
+      Ok(row) => batch.push(row),
+      Err(err) => warn!("fail to read the {} of {path}: {err}", table.what),
+    }
+    if batch.len() >= SYNC_BATCH_SIZE {
+      (table.save)(&mut batch)?;
+    }
+  }
+  (table.save)(&mut batch)?;
+
+  let stale: Vec<String> = known
+    .into_keys()
+    .filter(|p| !current.contains(p))
+    .collect();
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  (table.forget)(&mut conn, &stale)
+}
@@ -1,8 +1,39 @@
-use std::path::PathBuf;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use super::error::AppError;
-use pdf::file::File;
+use super::office_parser::{read_office_pages, OfficeFormat};
 use tokio::{fs, io::AsyncReadExt};
+use tracing::warn;
+
+use crate::conv_err;
+
+const MAX_TEXT_FILE_SIZE: u64 = 1024 * 1024 * 10;
+const MAX_PDF_FILE_SIZE: u64 = 1024 * 1024 * 100;
+const MAX_OFFICE_FILE_SIZE: u64 = 1024 * 1024 * 50;
+/// stop extracting pages of a pdf after this duration since loading started, pages read so
+/// far are kept
+const PDF_TIME_BUDGET: Duration = Duration::from_secs(20);
+/// a pdf still parsing this long after its budget, e.g. stuck in loading, is abandoned
+const PDF_ABANDON_GRACE: Duration = Duration::from_secs(10);
+/// abandoned parsers keep running in the background, no pdf is parsed while this many are
+const MAX_ABANDONED_PDF_PARSERS: usize = 2;
+/// stop extracting pages of a pdf once this many bytes of text are collected
+const PDF_MAX_TEXT_LEN: usize = 1024 * 1024 * 8;
+
+conv_err!(lopdf::Error);
+
+static ABANDONED_PDF_PARSERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Text extracted from a file. `page` is the 1-based page number for paged documents.
+#[derive(Debug, Clone)]
+pub struct DocPage {
+  pub page: Option<u64>,
+  pub body: String,
+}
 
 #[allow(unused)]
 pub async fn read_txt_to_string(file: &str) -> Result<String, AppError> {
@@ -17,43 +48,140 @@ pub fn read_txt_to_string_sync(file: &str) -> Result<String, AppError> {
   Ok(s)
 }
 
-pub fn read_pdf_to_string(file: &str) -> Result<String, AppError> {
-  let path = PathBuf::from(file);
-  let file = File::open(&path).unwrap();
+pub fn read_pdf_pages(file: &str) -> Result<Vec<DocPage>, AppError> {
+  let file = file.to_owned();
+  parse_pdf_in_thread(move |start| pdf_pages(lopdf::Document::load(&file)?, &file, start))
+}
 
-  let mut result = String::new();
-  if let Some(ref info) = file.trailer.info_dict {
-    info
-      .iter()
-      .filter(|(_, primitive)| primitive.to_string_lossy().is_ok())
-      .for_each(|(key, value)| {
-        let s = format!("{}: {}", key, value.to_string_lossy().unwrap());
-        result.push_str(&s);
-      });
+/// Run `parse` in its own thread, it gets the time parsing started. Panics are returned as
+/// errors, and a parse running past the budget is abandoned, since loading a pdf can not
+/// be interrupted.
+fn parse_pdf_in_thread<F>(parse: F) -> Result<Vec<DocPage>, AppError>
+where
+  F: FnOnce(Instant) -> Result<Vec<DocPage>, AppError> + Send + 'static,
+{
+  if ABANDONED_PDF_PARSERS.load(Ordering::SeqCst) >= MAX_ABANDONED_PDF_PARSERS {
+    return Err(AppError::new("too many pdf parsers timed out and are still running"));
+  }
+  let start = Instant::now();
+  let (tx, rx) = mpsc::channel();
+  let abandoned = Arc::new(Mutex::new(false));
+  {
+    let abandoned = abandoned.clone();
+    thread::Builder::new()
+      .name("pdf-parser".to_owned())
+      .spawn(move || {
+        let result = catch_unwind(AssertUnwindSafe(|| parse(start)))
+          .unwrap_or_else(|_| Err(AppError::new("pdf parser panicked")));
+        let abandoned = abandoned.lock().unwrap();
+        if *abandoned {
+          ABANDONED_PDF_PARSERS.fetch_sub(1, Ordering::SeqCst);
+        } else {
+          let _ = tx.send(result);
+        }
+      })?;
   }
+  match rx.recv_timeout(PDF_TIME_BUDGET + PDF_ABANDON_GRACE) {
+    Ok(result) => result,
+    Err(_) => {
+      let mut abandoned = abandoned.lock().unwrap();
+      // it may have finished while the lock was taken
+      if let Ok(result) = rx.try_recv() {
+        return result;
+      }
+      *abandoned = true;
+      ABANDONED_PDF_PARSERS.fetch_add(1, Ordering::SeqCst);
+      Err(AppError::new("pdf parser timed out"))
+    }
+  }
+}
 
-  Ok(result)
+/// `file` is only used in log messages, `start` is when loading the pdf started
+fn pdf_pages(doc: lopdf::Document, file: &str, start: Instant) -> Result<Vec<DocPage>, AppError> {
+  if doc.is_encrypted() {
+    return Err(AppError::new("encrypted pdf is not supported"));
+  }
+  let mut pages = vec![];
+  let mut text_len = 0;
+  for (page_num, _) in doc.get_pages() {
+    if start.elapsed() > PDF_TIME_BUDGET || text_len > PDF_MAX_TEXT_LEN {
+      warn!("pdf budget exceeded, stop at page {page_num}: {file}");
+      break;
+    }
+    match doc.extract_text(&[page_num]) {
+      Ok(body) => {
+        let body = body.trim();
+        if body.is_empty() {
+          continue;
+        }
+        text_len += body.len();
+        pages.push(DocPage {
+          page: Some(page_num as u64),
+          body: body.to_owned(),
+        });
+      }
+      Err(e) => warn!("fail to extract text of page {page_num} in {file}: {e}"),
+    }
+  }
+  Ok(pages)
 }
 
 #[allow(unused)]
-pub async fn try_parse(file: &str, mime: &str) -> Result<Option<String>, AppError> {
+pub async fn try_parse(file: &str, mime: &str) -> Result<Option<Vec<DocPage>>, AppError> {
+  if let Some(format) = OfficeFormat::from_path(file) {
+    return Ok(Some(read_office_pages(file, format)?));
+  }
+  if mime.contains("text") {
+    let body = read_txt_to_string(file).await?;
+    return Ok(Some(vec![DocPage { page: None, body }]));
+  } else if mime.contains("pdf") {
+    return Ok(Some(read_pdf_pages(file)?));
+  }
+  Ok(None)
+}
+
+/// Parse the text content of a file, panics inside the parsers are caught and returned as errors
+/// so a malformed file never takes down the indexer thread.
+pub fn try_parse_sync(file: &str, mime: &str, size: u64) -> Result<Option<Vec<DocPage>>, AppError> {
+  // checked before mime, opendocument and rtf mimes contain "text"
+  if let Some(format) = OfficeFormat::from_path(file) {
+    if size > MAX_OFFICE_FILE_SIZE {
+      return Ok(None);
+    }
+    let pages = catch_unwind(AssertUnwindSafe(|| read_office_pages(file, format)))
+      .map_err(|_| AppError::new("office parser panicked"))??;
+    return Ok(Some(pages));
+  }
   if mime.contains("text") {
-    return Ok(Some(read_txt_to_string(file).await?));
+    if size > MAX_TEXT_FILE_SIZE {
+      return Ok(None);
+    }
+    let body = read_txt_to_string_sync(file)?;
+    return Ok(Some(vec![DocPage { page: None, body }]));
   } else if mime.contains("pdf") {
-    return Ok(Some(read_pdf_to_string(file)?));
+    if size > MAX_PDF_FILE_SIZE {
+      return Ok(None);
+    }
+    return Ok(Some(read_pdf_pages(file)?));
   }
   Ok(None)
 }
 
-pub fn try_parse_sync(file: &str, mime: &str, size: u64) -> Result<Option<String>, AppError> {
-  let max_size = 1024 * 1024 * 10;
-  if size > max_size {
+/// Parse the text of a file read into memory, like an entry of an archive.
+/// Only plain text and pdf are supported, `name` is used to guess the mime type.
+pub fn try_parse_bytes(name: &str, bytes: &[u8]) -> Result<Option<Vec<DocPage>>, AppError> {
+  if OfficeFormat::from_path(name).is_some() {
     return Ok(None);
   }
+  let mime = mime_guess::from_path(name).first_or_octet_stream().to_string();
   if mime.contains("text") {
-    return Ok(Some(read_txt_to_string_sync(file)?));
+    let body = String::from_utf8_lossy(bytes).to_string();
+    return Ok(Some(vec![DocPage { page: None, body }]));
   } else if mime.contains("pdf") {
-    return Ok(Some(read_pdf_to_string(file)?));
+    let (bytes, name) = (bytes.to_vec(), name.to_owned());
+    let pages =
+      parse_pdf_in_thread(move |start| pdf_pages(lopdf::Document::load_mem(&bytes)?, &name, start))?;
+    return Ok(Some(pages));
   }
   Ok(None)
 }
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{db::SHARED_DB_CONN, models::FileHash};
+
+use super::derived_table::DerivedTable;
+use super::error::AppError;
+use super::path::{folder_like_pattern, secure_join};
+
+/// hashes are written to the database in batches of this size
+const HASH_BATCH_SIZE: usize = 100;
+
+pub fn hash_file(file: &Path) -> Result<String, AppError> {
+  let mut f = BufReader::new(File::open(file)?);
+  let mut hasher = Sha256::new();
+  std::io::copy(&mut f, &mut hasher)?;
+  Ok(hex::encode(hasher.finalize()))
+}
+
+fn save_hashes(batch: &mut Vec<FileHash>) -> Result<(), AppError> {
+  use crate::schema::file_hashes::table;
+  if batch.is_empty() {
+    return Ok(());
+  }
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::replace_into(table)
+    .values(&*batch)
+    .execute(&mut *conn)?;
+  batch.clear();
+  Ok(())
+}
+
+pub fn forget_hashes(conn: &mut SqliteConnection, files: &[String]) -> Result<(), AppError> {
+  use crate::schema::file_hashes::dsl::*;
+  for chunk in files.chunks(HASH_BATCH_SIZE) {
+    diesel::delete(file_hashes.filter(file_path.eq_any(chunk))).execute(conn)?;
+  }
+  Ok(())
+}
+
+fn known_hashes(conn: &mut SqliteConnection) -> Result<Vec<(String, i64, String)>, AppError> {
+  use crate::schema::file_hashes::dsl::*;
+  Ok(file_hashes.select((file_path, size, modified_at)).load(conn)?)
+}
+
+/// Content hashes of the non-empty files of the file index.
+pub const FILE_HASHES: DerivedTable<FileHash> = DerivedTable {
+  what: "hash",
+  wanted: |_, size| size > 0,
+  load_known: known_hashes,
+  derive: |file_root, file, size, modified_at| {
+    Ok(FileHash {
+      file_path: file.to_owned(),
+      size,
+      modified_at: modified_at.to_owned(),
+      hash: hash_file(&file_root.join(file))?,
+    })
+  },
+  save: save_hashes,
+  forget: forget_hashes,
+};
+
+#[derive(Debug, Serialize)]
+pub struct DuplicateGroup {
+  pub hash: String,
+  pub size: u64,
+  /// paths relative to the user root
+  pub files: Vec<String>,
+  /// bytes freed by keeping only one of the files
+  pub wasted: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DuplicateReport {
+  pub groups: Vec<DuplicateGroup>,
+  pub total_wasted: u64,
+}
+
+/// Group files under `folder` (relative to file root) with identical content,
+/// largest waste first.
+pub fn find_duplicates(
+  user_root: &str,
+  folder: &str,
+  min_size: u64,
+) -> Result<DuplicateReport, AppError> {
+  use crate::schema::file_hashes::dsl::*;
+  let query = file_hashes
+    .filter(size.ge(min_size.max(1) as i64))
+    .filter(file_path.like(folder_like_pattern(folder)).escape('\\'))
+    .order(hash);
+  let rows = {
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    query.load::<FileHash>(&mut *conn)?
+  };
+
+  let mut by_hash: HashMap<String, Vec<FileHash>> = HashMap::new();
+  for row in rows {
+    by_hash.entry(row.hash.clone()).or_default().push(row);
+  }
+  let mut groups: Vec<DuplicateGroup> = by_hash
+    .into_iter()
+    .filter(|(_, rows)| rows.len() > 1)
+    .map(|(h, rows)| {
+      let file_size = rows[0].size as u64;
+      let files: Vec<String> = rows
+        .into_iter()
+        .map(|r| {
+          Path::new(&r.file_path)
+            .strip_prefix(user_root)
+            .map_or(r.file_path.clone(), |p| p.to_string_lossy().to_string())
+        })
+        .collect();
+      DuplicateGroup {
+        hash: h,
+        size: file_size,
+        wasted: file_size * (files.len() as u64 - 1),
+        files,
+      }
+    })
+    .collect();
+  groups.sort_by_key(|g| std::cmp::Reverse(g.wasted));
+  let total_wasted = groups.iter().map(|g| g.wasted).sum();
+  Ok(DuplicateReport {
+    groups,
+    total_wasted,
+  })
+}
+
+/// Hash `keep` and `files` again and return the files whose content is still identical to
+/// `keep`, and the ones which changed or are missing. The stored hashes may be outdated,
+/// so they are never trusted before deleting anything.
+pub fn verify_duplicates(
+  user_abs_root: &PathBuf,
+  keep: &str,
+  files: &[String],
+) -> Result<(Vec<String>, Vec<String>), AppError> {
+  let keep_path = secure_join(user_abs_root, &PathBuf::from(keep))?;
+  let keep_hash = hash_file(&keep_path)?;
+  let mut same = vec![];
+  let mut skipped = vec![];
+  for f in files {
+    let p = secure_join(user_abs_root, &PathBuf::from(f))?;
+    if p == keep_path {
+      continue;
+    }
+    match hash_file(&p) {
+      Ok(h) if h == keep_hash => same.push(f.clone()),
+      _ => skipped.push(f.clone()),
+    }
+  }
+  Ok((same, skipped))
+}
@@ -1,32 +1,70 @@
-use std::{collections::HashMap, fmt::Debug, hash::Hash, sync::Mutex};
+use std::{fmt::Debug, future::Future};
 
-pub type Listener<P> = Box<dyn Fn(P) -> ()>;
+use tokio::sync::broadcast::{self, error::RecvError, Sender};
+use tracing::{debug, error, info, warn};
 
-pub struct EventEmitter<ET, P> {
-  events: Mutex<HashMap<ET, Vec<Listener<P>>>>,
+use super::error::AppError;
+
+/// Async event bus backed by a bounded tokio broadcast channel.
+///
+/// Every subscriber owns a receiver and runs in its own task, so a slow subscriber
+/// never blocks the emitter or other subscribers. When a subscriber falls more than
+/// `capacity` events behind, the oldest events are dropped for it. Subscribers which
+/// can not lose events register a resync, called after events were skipped.
+pub struct EventBus<E> {
+  name: &'static str,
+  sender: Sender<E>,
 }
 
-unsafe impl<T, P> Sync for EventEmitter<T, P> {}
-unsafe impl<T, P> Send for EventEmitter<T, P> {}
+impl<E: Clone + Debug + Send + 'static> EventBus<E> {
+  pub fn new(name: &'static str, capacity: usize) -> Self {
+    let (sender, _) = broadcast::channel(capacity);
+    Self { name, sender }
+  }
 
-impl<ET: PartialEq + Eq + Hash + Clone + Debug, P: Clone> EventEmitter<ET, P> {
-  pub fn new() -> Self {
-    Self {
-      events: Mutex::new(HashMap::new()),
+  /// Emit an event without waiting for subscribers, can be called from any thread.
+  pub fn emit(&self, event: E) {
+    if let Err(e) = self.sender.send(event) {
+      debug!("[{}] event dropped, no subscriber: {:?}", self.name, e.0);
     }
   }
-  pub fn emit(&mut self, event: ET, payload: P) {
-    let mut v = self.events.lock().unwrap();
-    println!("emit: {:?}", v.keys());
-    let listeners = v.entry(event.clone()).or_insert(vec![]);
-    for l in listeners {
-      l(payload.clone());
-    }
+
+  /// Register a subscriber, must be called inside a tokio runtime.
+  /// Errors returned by the handler are logged and do not stop the subscription.
+  pub fn subscribe<F, Fut>(&self, subscriber: &'static str, handler: F)
+  where
+    F: Fn(E) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), AppError>> + Send + 'static,
+  {
+    self.subscribe_with_resync(subscriber, handler, |_| {});
   }
-  pub fn listen(&mut self, event: ET, cb: impl Fn(P) -> () + 'static) {
-    println!("listen: {:?}", event);
-    let b = Box::new(cb);
-    let mut v = self.events.lock().unwrap();
-    v.entry(event.clone()).or_insert(vec![]).push(b);
+
+  /// Like [`Self::subscribe`], `resync` is called with the number of skipped events
+  /// when the subscriber lagged, to rebuild its state from the source of truth.
+  pub fn subscribe_with_resync<F, Fut, R>(&self, subscriber: &'static str, handler: F, resync: R)
+  where
+    F: Fn(E) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), AppError>> + Send + 'static,
+    R: Fn(u64) + Send + Sync + 'static,
+  {
+    let bus = self.name;
+    let mut rx = self.sender.subscribe();
+    info!("[{bus}] subscriber registered: {subscriber}");
+    tokio::spawn(async move {
+      loop {
+        match rx.recv().await {
+          Ok(event) => {
+            if let Err(e) = handler(event).await {
+              error!("[{bus}] subscriber {subscriber} failed: {e}");
+            }
+          }
+          Err(RecvError::Lagged(skipped)) => {
+            warn!("[{bus}] subscriber {subscriber} lagged, {skipped} events skipped");
+            resync(skipped);
+          }
+          Err(RecvError::Closed) => break,
+        }
+      }
+    });
   }
 }
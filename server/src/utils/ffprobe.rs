@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::config;
+
+use super::error::AppError;
+
+/// ffprobe is killed after this long, broken files can make it hang
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProbeStream {
+  #[serde(default)]
+  pub index: u32,
+  pub codec_type: Option<String>,
+  pub codec_name: Option<String>,
+  pub width: Option<u32>,
+  pub height: Option<u32>,
+  pub channels: Option<u32>,
+  /// bits per second, printed as a string
+  pub bit_rate: Option<String>,
+  /// language, title and others, keys depend on the container
+  #[serde(default)]
+  pub tags: HashMap<String, String>,
+  /// flags like `default` and `forced`, 0 or 1
+  #[serde(default)]
+  pub disposition: HashMap<String, i32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProbeFormat {
+  pub format_name: Option<String>,
+  /// seconds, ffprobe prints it as a string
+  pub duration: Option<String>,
+  /// bits per second, printed as a string
+  pub bit_rate: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProbeResult {
+  #[serde(default)]
+  pub streams: Vec<ProbeStream>,
+  #[serde(default)]
+  pub format: ProbeFormat,
+}
+
+impl ProbeResult {
+  pub fn duration(&self) -> Option<f64> {
+    self
+      .format
+      .duration
+      .as_ref()
+      .and_then(|d| d.parse().ok())
+      .filter(|d: &f64| d.is_finite() && *d > 0.0)
+  }
+
+  pub fn video_stream(&self) -> Option<&ProbeStream> {
+    self.first_stream("video")
+  }
+
+  pub fn audio_stream(&self) -> Option<&ProbeStream> {
+    self.first_stream("audio")
+  }
+
+  fn first_stream(&self, codec_type: &str) -> Option<&ProbeStream> {
+    self
+      .streams
+      .iter()
+      // cover art of audio files shows up as a video stream
+      .filter(|s| s.disposition.get("attached_pic") != Some(&1))
+      .find(|s| s.codec_type.as_deref() == Some(codec_type))
+  }
+
+  pub fn bitrate(&self) -> Option<i64> {
+    self.format.bit_rate.as_ref().and_then(|b| b.parse().ok())
+  }
+}
+
+impl ProbeStream {
+  pub fn tag(&self, key: &str) -> Option<&str> {
+    self
+      .tags
+      .iter()
+      .find(|(k, _)| k.eq_ignore_ascii_case(key))
+      .map(|(_, v)| v.as_str())
+  }
+}
+
+fn read_all<R: Read + Send + 'static>(mut r: R) -> thread::JoinHandle<Vec<u8>> {
+  thread::spawn(move || {
+    let mut buf = vec![];
+    let _ = r.read_to_end(&mut buf);
+    buf
+  })
+}
+
+/// Run ffprobe on a media file, blocks until it exits or `PROBE_TIMEOUT` passed.
+pub fn probe(file: &Path) -> Result<ProbeResult, AppError> {
+  let mut child = Command::new(config!(ffprobe_bin_path))
+    .args(["-v", "error", "-print_format", "json", "-show_format", "-show_streams"])
+    .arg(file)
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()?;
+  // read while waiting, ffprobe blocks on a full pipe
+  let stdout = child.stdout.take().map(read_all);
+  let stderr = child.stderr.take().map(read_all);
+  let deadline = Instant::now() + PROBE_TIMEOUT;
+  let status = loop {
+    if let Some(status) = child.try_wait()? {
+      break status;
+    }
+    if Instant::now() >= deadline {
+      let _ = child.kill();
+      let _ = child.wait();
+      return Err(AppError::new(&format!("ffprobe timed out after {}s", PROBE_TIMEOUT.as_secs())));
+    }
+    thread::sleep(Duration::from_millis(50));
+  };
+  let collect = |h: Option<thread::JoinHandle<Vec<u8>>>| h.and_then(|h| h.join().ok()).unwrap_or_default();
+  let (stdout, stderr) = (collect(stdout), collect(stderr));
+  if !status.success() {
+    let err = String::from_utf8_lossy(&stderr);
+    return Err(AppError::new(&format!("ffprobe failed: {}", err.trim())));
+  }
+  serde_json::from_slice(&stdout).map_err(|e| AppError::new(&e.to_string()))
+}
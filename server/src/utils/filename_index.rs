@@ -0,0 +1,385 @@
+use std::collections::HashSet;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tracing::error;
+use tantivy::collector::{DocSetCollector, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{
+  BooleanQuery, FuzzyTermQuery, Occur, Query, RangeQuery, RegexQuery, TermQuery,
+};
+use tantivy::schema::*;
+use tantivy::tokenizer::{LowerCaser, NgramTokenizer, SimpleTokenizer, TextAnalyzer};
+use tantivy::{doc, Directory, Index, IndexReader, IndexWriter, ReloadPolicy};
+
+use crate::config;
+
+use super::archive::{virtual_path, ArchiveFormat, ArchiveLink};
+use super::error::AppError;
+
+/// The index with a reader shared by all searches, reloaded by the writer thread after each
+/// commit.
+pub struct FilenameIndex {
+  pub index: Index,
+  reader: IndexReader,
+}
+
+lazy_static! {
+  pub static ref FILENAME_INDEX: FilenameIndex = {
+    let index = init();
+    let reader = index
+      .reader_builder()
+      .reload_policy(ReloadPolicy::Manual)
+      .try_into()
+      .unwrap();
+    FilenameIndex { index, reader }
+  };
+  /// All writes go through this channel to the writer thread which owns the only `IndexWriter`.
+  static ref WRITER: Mutex<Sender<WriteOp>> = {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+      .name("filename-indexer".to_owned())
+      .spawn(move || run_writer(receiver))
+      .unwrap();
+    Mutex::new(sender)
+  };
+}
+
+const WRITER_HEAP_SIZE: usize = 10_000_000;
+/// commit once this many documents are pending
+const COMMIT_BATCH_SIZE: usize = 1000;
+/// commit pending documents when no write arrives for this long
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// candidates fetched by relevance before re-ranking with recency
+const CANDIDATE_LIMIT: usize = 200;
+const DAY_MILLIS: f32 = 1000. * 3600. * 24.;
+/// share of the n-grams of a keyword a file name must contain, unless a word of it matches
+const MIN_GRAM_SHARE: f32 = 0.7;
+
+fn init() -> Index {
+  let mut schema_builder = Schema::builder();
+
+  let ngram_options = TextOptions::default().set_stored().set_indexing_options(
+    TextFieldIndexing::default()
+      .set_tokenizer("filename_ngram")
+      .set_index_option(IndexRecordOption::WithFreqs),
+  );
+  let words_options = TextOptions::default().set_indexing_options(
+    TextFieldIndexing::default()
+      .set_tokenizer("filename_words")
+      .set_index_option(IndexRecordOption::WithFreqs),
+  );
+
+  schema_builder.add_text_field("path", STRING | STORED);
+  schema_builder.add_text_field("name", ngram_options);
+  schema_builder.add_text_field("name_words", words_options);
+  schema_builder.add_u64_field("is_dir", STORED);
+  schema_builder.add_u64_field("modified", STORED);
+  schema_builder.add_u64_field("updated_at", INDEXED);
+  let schema = schema_builder.build();
+
+  let index_path = config!(filename_index_path);
+  std::fs::create_dir_all(&index_path).unwrap();
+  let mmap_directory: Box<dyn Directory> = Box::new(MmapDirectory::open(&index_path).unwrap());
+  let index = Index::open_or_create(mmap_directory, schema).unwrap();
+
+  let ngram = TextAnalyzer::from(NgramTokenizer::new(2, 3, false)).filter(LowerCaser);
+  let words = TextAnalyzer::from(SimpleTokenizer).filter(LowerCaser);
+  index.tokenizers().register("filename_ngram", ngram);
+  index.tokenizers().register("filename_words", words);
+  index
+}
+
+#[derive(Debug)]
+pub struct FilenameDoc {
+  pub path: String,
+  pub name: String,
+  pub is_dir: bool,
+  /// modified time in millis
+  pub modified: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilenameHit {
+  pub file_name: String,
+  pub file_path: String,
+  pub is_dir: bool,
+  pub modified_at: u64,
+  pub score: f32,
+  /// set for entries inside an archive
+  pub archive: Option<ArchiveLink>,
+}
+
+fn tokens(index: &Index, field: Field, text: &str) -> Result<Vec<String>, AppError> {
+  let analyzer = index.tokenizer_for_field(field)?;
+  let mut tokens = vec![];
+  analyzer
+    .token_stream(text)
+    .process(&mut |token| tokens.push(token.text.clone()));
+  Ok(tokens)
+}
+
+type Clauses = Vec<(Occur, Box<dyn Query>)>;
+
+fn gram_query(index: &Index, kw: &str) -> Result<Clauses, AppError> {
+  let name = index.schema().get_field("name").unwrap();
+  let mut should: Clauses = vec![];
+  for gram in tokens(index, name, kw)? {
+    let term = Term::from_field_text(name, &gram);
+    should.push((
+      Occur::Should,
+      Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs)),
+    ));
+  }
+  Ok(should)
+}
+
+/// The keyword is a (fuzzy) prefix of a word in the file name.
+fn word_query(index: &Index, kw: &str) -> Result<BooleanQuery, AppError> {
+  let name_words = index.schema().get_field("name_words").unwrap();
+  let mut should: Vec<(Occur, Box<dyn Query>)> = vec![];
+  for word in tokens(index, name_words, kw)? {
+    let distance = match word.chars().count() {
+      0..=3 => 0,
+      4..=7 => 1,
+      _ => 2,
+    };
+    let term = Term::from_field_text(name_words, &word);
+    should.push((
+      Occur::Should,
+      Box::new(FuzzyTermQuery::new_prefix(term, distance, true)),
+    ));
+  }
+  Ok(BooleanQuery::new(should))
+}
+
+/// Every whitespace separated keyword must share n-grams with the file name or be a (fuzzy)
+/// prefix of a word in it, word order does not matter. Sharing a single n-gram is enough here,
+/// so `search` drops the hits which share less than `MIN_GRAM_SHARE` of them.
+fn build_query(index: &Index, keyword: &str, folder: Option<&str>) -> Result<BooleanQuery, AppError> {
+  let path = index.schema().get_field("path").unwrap();
+  let mut must: Vec<(Occur, Box<dyn Query>)> = vec![];
+  for kw in keyword.split_whitespace() {
+    let mut should = gram_query(index, kw)?;
+    should.push((Occur::Should, Box::new(word_query(index, kw)?)));
+    must.push((Occur::Must, Box::new(BooleanQuery::new(should))));
+  }
+  if let Some(folder) = folder.filter(|f| !f.is_empty()) {
+    let pattern = format!("{}/.*", regex::escape(folder));
+    must.push((Occur::Must, Box::new(RegexQuery::from_pattern(&pattern, path)?)));
+  }
+  Ok(BooleanQuery::new(must))
+}
+
+fn gram_share(kw_grams: &HashSet<String>, name_grams: &HashSet<String>) -> f32 {
+  if kw_grams.is_empty() {
+    return 0.;
+  }
+  kw_grams.intersection(name_grams).count() as f32 / kw_grams.len() as f32
+}
+
+/// Files under `folder` (relative to file root) matching `keyword`, by relevance from the
+/// index, boosted for recently modified files (x2 for files modified now, decaying to x1
+/// over a few months).
+pub fn search(keyword: &str, folder: Option<&str>, limit: usize) -> Result<Vec<FilenameHit>, AppError> {
+  if keyword.trim().is_empty() {
+    return Ok(vec![]);
+  }
+  let index = &FILENAME_INDEX.index;
+  let schema = index.schema();
+  let path = schema.get_field("path").unwrap();
+  let name = schema.get_field("name").unwrap();
+  let is_dir = schema.get_field("is_dir").unwrap();
+  let modified = schema.get_field("modified").unwrap();
+
+  let searcher = FILENAME_INDEX.reader.searcher();
+  let query = build_query(index, keyword, folder)?;
+  let top_docs = searcher.search(&query, &TopDocs::with_limit(CANDIDATE_LIMIT))?;
+  // per keyword: its n-grams, and the docs where it matches a word
+  let mut keywords = vec![];
+  for kw in keyword.split_whitespace() {
+    let grams: HashSet<String> = tokens(index, name, kw)?.into_iter().collect();
+    let word_docs = searcher.search(&word_query(index, kw)?, &DocSetCollector)?;
+    keywords.push((grams, word_docs));
+  }
+
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+  let mut hits = vec![];
+  for (score, address) in top_docs {
+    let doc = searcher.doc(address)?;
+    let text_of = |field: Field| {
+      doc
+        .get_first(field)
+        .and_then(|v| v.as_text())
+        .unwrap_or_default()
+        .to_owned()
+    };
+    let u64_of = |field: Field| doc.get_first(field).and_then(|v| v.as_u64()).unwrap_or(0);
+    let modified_at = u64_of(modified);
+    let age_days = now.saturating_sub(modified_at) as f32 / DAY_MILLIS;
+    let recency = 1. + 1. / (1. + age_days / 30.);
+    let file_name = text_of(name);
+    let name_grams: HashSet<String> = tokens(index, name, &file_name)?.into_iter().collect();
+    let matched = keywords.iter().all(|(grams, word_docs)| {
+      word_docs.contains(&address) || gram_share(grams, &name_grams) >= MIN_GRAM_SHARE
+    });
+    if !matched {
+      continue;
+    }
+    let file_path = text_of(path);
+    hits.push(FilenameHit {
+      file_name,
+      archive: ArchiveLink::from_path(&file_path),
+      file_path,
+      is_dir: u64_of(is_dir) == 1,
+      modified_at,
+      score: score * recency,
+    });
+  }
+  hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+  hits.truncate(limit);
+  Ok(hits)
+}
+
+enum WriteOp {
+  Upsert { docs: Vec<FilenameDoc>, now: u64 },
+  Delete(Vec<String>),
+  /// delete the documents not seen by the full index run started at this time
+  Cleanup(u64),
+  Commit(Sender<Result<(), AppError>>),
+}
+
+fn send(op: WriteOp) -> Result<(), AppError> {
+  WRITER
+    .lock()
+    .unwrap()
+    .send(op)
+    .map_err(|_| AppError::new("filename indexer is not running"))
+}
+
+fn run_writer(receiver: Receiver<WriteOp>) {
+  let mut writer = match FILENAME_INDEX.index.writer(WRITER_HEAP_SIZE) {
+    Ok(writer) => writer,
+    Err(err) => {
+      error!("fail to create filename index writer: {err}");
+      return;
+    }
+  };
+  let mut pending = 0;
+  loop {
+    let op = if pending > 0 {
+      match receiver.recv_timeout(COMMIT_INTERVAL) {
+        Ok(op) => op,
+        Err(RecvTimeoutError::Timeout) => {
+          pending = 0;
+          commit(&mut writer).unwrap_or_else(|err| error!("fail to commit filename index: {err}"));
+          continue;
+        }
+        Err(RecvTimeoutError::Disconnected) => break,
+      }
+    } else {
+      match receiver.recv() {
+        Ok(op) => op,
+        Err(_) => break,
+      }
+    };
+    let result = match op {
+      WriteOp::Upsert { docs, now } => {
+        pending += docs.len();
+        add_docs(&mut writer, docs, now)
+      }
+      WriteOp::Delete(files) => {
+        pending += files.len();
+        delete_files(&mut writer, &files)
+      }
+      WriteOp::Cleanup(not_updated_at) => {
+        pending += 1;
+        let updated_at = FILENAME_INDEX.index.schema().get_field("updated_at").unwrap();
+        writer
+          .delete_query(Box::new(RangeQuery::new_u64(updated_at, 0..not_updated_at)))
+          .map(|_| ())
+          .map_err(AppError::from)
+      }
+      WriteOp::Commit(reply) => {
+        pending = 0;
+        let _ = reply.send(commit(&mut writer));
+        Ok(())
+      }
+    };
+    result.unwrap_or_else(|err| error!("fail to write filename index: {err}"));
+    if pending >= COMMIT_BATCH_SIZE {
+      pending = 0;
+      commit(&mut writer).unwrap_or_else(|err| error!("fail to commit filename index: {err}"));
+    }
+  }
+  let _ = commit(&mut writer);
+}
+
+fn commit(writer: &mut IndexWriter) -> Result<(), AppError> {
+  writer.commit()?;
+  FILENAME_INDEX.reader.reload()?;
+  Ok(())
+}
+
+fn add_docs(writer: &mut IndexWriter, docs: Vec<FilenameDoc>, now: u64) -> Result<(), AppError> {
+  let schema = FILENAME_INDEX.index.schema();
+  let path = schema.get_field("path").unwrap();
+  let name = schema.get_field("name").unwrap();
+  let name_words = schema.get_field("name_words").unwrap();
+  let is_dir = schema.get_field("is_dir").unwrap();
+  let modified = schema.get_field("modified").unwrap();
+  let updated_at = schema.get_field("updated_at").unwrap();
+
+  for d in docs {
+    writer.delete_term(Term::from_field_text(path, &d.path));
+    writer.add_document(doc!(
+      path => d.path,
+      name => d.name.clone(),
+      name_words => d.name,
+      is_dir => d.is_dir as u64,
+      modified => d.modified,
+      updated_at => now,
+    ))?;
+  }
+  Ok(())
+}
+
+fn delete_files(writer: &mut IndexWriter, files: &[String]) -> Result<(), AppError> {
+  let path = FILENAME_INDEX.index.schema().get_field("path").unwrap();
+  for f in files {
+    writer.delete_term(Term::from_field_text(path, f));
+    if ArchiveFormat::from_path(f).is_some() {
+      let pattern = format!("{}.*", regex::escape(&virtual_path(f, "")));
+      writer.delete_query(Box::new(RegexQuery::from_pattern(&pattern, path)?))?;
+    }
+  }
+  Ok(())
+}
+
+/// Insert or replace documents by path, they become searchable after the next batch commit.
+pub fn upsert(docs: Vec<FilenameDoc>, now: u64) -> Result<(), AppError> {
+  send(WriteOp::Upsert { docs, now })
+}
+
+pub fn delete(files: &[String]) -> Result<(), AppError> {
+  if files.is_empty() {
+    return Ok(());
+  }
+  send(WriteOp::Delete(files.to_vec()))
+}
+
+/// Remove documents which were not seen by the full index run started at `updated_at`,
+/// and wait until all queued writes are committed.
+pub fn cleanup(not_updated_at: u64) -> Result<(), AppError> {
+  send(WriteOp::Cleanup(not_updated_at))?;
+  let (reply, result) = mpsc::channel();
+  send(WriteOp::Commit(reply))?;
+  result
+    .recv()
+    .map_err(|_| AppError::new("filename indexer is not running"))?
+}
@@ -1,13 +1,259 @@
-use diesel::SqliteConnection;
+use std::path::Path;
 
-use crate::models::FileIndex;
+use diesel::sql_types::{BigInt, Double, Text};
+use diesel::{sql_query, RunQueryDsl, SqliteConnection};
+use serde::Serialize;
 
+use crate::db::SHARED_DB_CONN;
+use crate::models::{FileIndex, GeoCluster, GeoPhoto, TimelineBucket, TimelinePhoto};
+
+use super::archive::ARCHIVE_SEPARATOR;
 use super::error::AppError;
+use super::path::folder_like_pattern;
+use super::vfs::rel_join;
 
 pub fn get_all_images(db: &mut SqliteConnection, username_: &str) -> Result<Vec<FileIndex>, AppError> {
   use crate::schema::file_index::dsl::*;
   use diesel::prelude::*;
 
-  let exists = file_index.filter(format.like("%image%").and(username.eq(username_))).load::<FileIndex>(db)?;
+  // entries of archives can not be opened by the gallery
+  let exists = file_index
+    .filter(format.like("%image%").and(username.eq(username_)))
+    .filter(file_path.not_like(format!("%{ARCHIVE_SEPARATOR}%")))
+    .load::<FileIndex>(db)?;
   Ok(exists)
 }
+
+/// Images under the user root, one row per file. The capture time falls back to the modified
+/// time for images without EXIF. Entries of archives are left out.
+const PHOTOS_SQL: &str = "select f.file_path as file_path, max(f.file_name) as file_name, max(f.size) as size, \
+  coalesce(max(m.taken_at), cast(max(f.modified_at) as integer)) as taken_at, \
+  max(m.width) as width, max(m.height) as height \
+  from file_index f left join media_metadata m on m.file_path = f.file_path \
+  where f.is_dir = 0 and f.format like '%image%' and f.file_path like ? escape '\\' \
+  and instr(f.file_path, '!/') = 0 \
+  group by f.file_path";
+
+#[derive(Clone, Copy)]
+pub enum TimelineGranularity {
+  Year,
+  Month,
+  Day,
+}
+
+impl TimelineGranularity {
+  pub fn parse(s: &str) -> Option<Self> {
+    match s {
+      "year" => Some(Self::Year),
+      "month" => Some(Self::Month),
+      "day" => Some(Self::Day),
+      _ => None,
+    }
+  }
+
+  fn strftime_format(&self) -> &'static str {
+    match self {
+      Self::Year => "%Y",
+      Self::Month => "%Y-%m",
+      Self::Day => "%Y-%m-%d",
+    }
+  }
+}
+
+pub fn strip_user_root(user_root: &str, file: &str) -> String {
+  Path::new(file)
+    .strip_prefix(user_root)
+    .map_or(file.to_owned(), |p| p.to_string_lossy().to_string())
+}
+
+/// Photo counts per capture year, month or day, newest first. Dates are in UTC.
+pub fn timeline_buckets(
+  user_root: &str,
+  granularity: TimelineGranularity,
+) -> Result<Vec<TimelineBucket>, AppError> {
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let sql = format!(
+    "select strftime(?, taken_at / 1000, 'unixepoch') as bucket, count(*) as count, \
+    min(taken_at) as first_taken_at, max(taken_at) as last_taken_at \
+    from ({PHOTOS_SQL}) group by bucket order by bucket desc"
+  );
+  let r = sql_query(sql)
+    .bind::<Text, _>(granularity.strftime_format())
+    .bind::<Text, _>(folder_like_pattern(user_root))
+    .load::<TimelineBucket>(conn)?;
+  Ok(r)
+}
+
+#[derive(Serialize)]
+pub struct TimelinePage {
+  /// paths relative to the user root
+  pub photos: Vec<TimelinePhoto>,
+  /// pass it back to get the next page, none on the last page
+  pub next_cursor: Option<String>,
+}
+
+fn parse_cursor(user_root: &str, cursor: &str) -> Result<(i64, String), AppError> {
+  let invalid = || AppError::new("invalid cursor");
+  let (taken_at, file) = cursor.split_once(':').ok_or_else(invalid)?;
+  let taken_at = taken_at.parse().map_err(|_| invalid())?;
+  Ok((taken_at, rel_join(user_root, file)?))
+}
+
+/// Photos taken between `from` and `to` (millis, inclusive), newest first. The cursor is the
+/// position after the last photo of the previous page, so pages stay stable while new photos
+/// are indexed.
+pub fn timeline_photos(
+  user_root: &str,
+  from: Option<i64>,
+  to: Option<i64>,
+  cursor: Option<&str>,
+  limit: usize,
+) -> Result<TimelinePage, AppError> {
+  let (cursor_taken_at, cursor_file) = match cursor {
+    Some(c) => parse_cursor(user_root, c)?,
+    None => (i64::MAX, "".to_owned()),
+  };
+  let mut photos = {
+    let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+    let sql = format!(
+      "select * from ({PHOTOS_SQL}) where taken_at >= ? and taken_at <= ? \
+      and (taken_at < ? or (taken_at = ? and file_path < ?)) \
+      order by taken_at desc, file_path desc limit ?"
+    );
+    sql_query(sql)
+      .bind::<Text, _>(folder_like_pattern(user_root))
+      .bind::<BigInt, _>(from.unwrap_or(i64::MIN))
+      .bind::<BigInt, _>(to.unwrap_or(i64::MAX))
+      .bind::<BigInt, _>(cursor_taken_at)
+      .bind::<BigInt, _>(cursor_taken_at)
+      .bind::<Text, _>(cursor_file)
+      .bind::<BigInt, _>(limit as i64 + 1)
+      .load::<TimelinePhoto>(conn)?
+  };
+  let has_more = photos.len() > limit;
+  photos.truncate(limit);
+  for p in photos.iter_mut() {
+    p.file_path = strip_user_root(user_root, &p.file_path);
+  }
+  let next_cursor = photos
+    .last()
+    .filter(|_| has_more)
+    .map(|p| format!("{}:{}", p.taken_at, p.file_path));
+  Ok(TimelinePage {
+    photos,
+    next_cursor,
+  })
+}
+
+/// grid cells per map tile edge, a cell is about 64 pixels on a 256 pixel tile
+const GEO_CELLS_PER_TILE: f64 = 4.0;
+const GEO_MAX_ZOOM: u32 = 22;
+
+/// edge of a grid cell in degrees, cells halve with every zoom level like map tiles do
+fn geo_cell_size(zoom: u32) -> f64 {
+  360.0 / (2f64.powi(zoom.min(GEO_MAX_ZOOM) as i32) * GEO_CELLS_PER_TILE)
+}
+
+pub struct BoundingBox {
+  pub south: f64,
+  pub west: f64,
+  pub north: f64,
+  pub east: f64,
+}
+
+#[derive(Serialize)]
+pub struct GeoClusterResp {
+  /// pass it to `geo_cluster_photos` to list the photos of the cluster
+  pub id: String,
+  pub count: i64,
+  pub latitude: f64,
+  pub longitude: f64,
+  pub cover: String,
+}
+
+/// Photos with a GPS position inside `bbox`, clustered on a grid for the map `zoom` level.
+/// A box with `west` greater than `east` crosses the antimeridian.
+pub fn geo_clusters(
+  user_root: &str,
+  bbox: &BoundingBox,
+  zoom: u32,
+) -> Result<Vec<GeoClusterResp>, AppError> {
+  let zoom = zoom.min(GEO_MAX_ZOOM);
+  let cell = geo_cell_size(zoom);
+  let longitude_filter = if bbox.west <= bbox.east {
+    "longitude >= ? and longitude <= ?"
+  } else {
+    "(longitude >= ? or longitude <= ?)"
+  };
+  // sqlite takes the bare `file_path` from the row with the max `taken_at`
+  let sql = format!(
+    "select cast((longitude + 180.0) / ? as integer) as cell_x, \
+    cast((latitude + 90.0) / ? as integer) as cell_y, count(*) as count, \
+    avg(latitude) as latitude, avg(longitude) as longitude, \
+    file_path as cover, max(coalesce(taken_at, 0)) as latest_taken_at \
+    from media_metadata \
+    where file_path like ? escape '\\' and latitude is not null and longitude is not null \
+    and latitude >= ? and latitude <= ? and {longitude_filter} \
+    group by cell_x, cell_y"
+  );
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let clusters = sql_query(sql)
+    .bind::<Double, _>(cell)
+    .bind::<Double, _>(cell)
+    .bind::<Text, _>(folder_like_pattern(user_root))
+    .bind::<Double, _>(bbox.south)
+    .bind::<Double, _>(bbox.north)
+    .bind::<Double, _>(bbox.west)
+    .bind::<Double, _>(bbox.east)
+    .load::<GeoCluster>(conn)?;
+  Ok(
+    clusters
+      .into_iter()
+      .map(|c| GeoClusterResp {
+        id: format!("{zoom}:{}:{}", c.cell_x, c.cell_y),
+        count: c.count,
+        latitude: c.latitude,
+        longitude: c.longitude,
+        cover: strip_user_root(user_root, &c.cover),
+      })
+      .collect(),
+  )
+}
+
+/// Photos of a cluster returned by `geo_clusters`, newest first.
+pub fn geo_cluster_photos(
+  user_root: &str,
+  cluster_id: &str,
+  offset: usize,
+  limit: usize,
+) -> Result<Vec<GeoPhoto>, AppError> {
+  let invalid = || AppError::new("invalid cluster id");
+  let parts: Vec<&str> = cluster_id.split(':').collect();
+  let [zoom, x, y] = parts[..] else {
+    return Err(invalid());
+  };
+  let zoom: u32 = zoom.parse().map_err(|_| invalid())?;
+  let x: i64 = x.parse().map_err(|_| invalid())?;
+  let y: i64 = y.parse().map_err(|_| invalid())?;
+  let cell = geo_cell_size(zoom);
+
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let mut photos = sql_query(
+    "select file_path, taken_at, latitude, longitude from media_metadata \
+    where file_path like ? escape '\\' and latitude is not null and longitude is not null \
+    and cast((longitude + 180.0) / ? as integer) = ? and cast((latitude + 90.0) / ? as integer) = ? \
+    order by taken_at desc, file_path limit ? offset ?",
+  )
+  .bind::<Text, _>(folder_like_pattern(user_root))
+  .bind::<Double, _>(cell)
+  .bind::<BigInt, _>(x)
+  .bind::<Double, _>(cell)
+  .bind::<BigInt, _>(y)
+  .bind::<BigInt, _>(limit as i64)
+  .bind::<BigInt, _>(offset as i64)
+  .load::<GeoPhoto>(conn)?;
+  for p in photos.iter_mut() {
+    p.file_path = strip_user_root(user_root, &p.file_path);
+  }
+  Ok(photos)
+}
@@ -0,0 +1,522 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::http::StatusCode;
+use lazy_static::lazy_static;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::config;
+
+use super::error::AppError;
+use super::ffprobe;
+use super::transcode::{self, TranscodePermit};
+
+/// a running transcode keeps going if the requested segment is at most this far ahead
+const LOOKAHEAD_SEGMENTS: usize = 5;
+const SEGMENT_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+/// how often finished segments of running transcodes are moved to the cache
+const HARVEST_INTERVAL: Duration = Duration::from_millis(200);
+/// while all transcode slots are taken, waiting viewers check for a free one this often
+const SLOT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(15);
+const AUDIO_BITRATE: u32 = 128;
+
+/// running transcodes by rendition directory and first segment
+type Jobs = Arc<Mutex<HashMap<(PathBuf, usize), TranscodeJob>>>;
+
+lazy_static! {
+  /// probe results and the time they were last used, by cache key
+  static ref VIDEOS: Mutex<HashMap<String, (VideoInfo, Instant)>> = Mutex::new(HashMap::new());
+  static ref JOBS: Jobs = {
+    let jobs: Jobs = Arc::new(Mutex::new(HashMap::new()));
+    spawn_harvester(jobs.clone());
+    jobs
+  };
+  /// woken when segments were moved to the cache or a transcode ended
+  static ref SEGMENTS_CHANGED: Notify = Notify::new();
+}
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+struct VideoInfo {
+  duration: f64,
+  width: u32,
+  height: u32,
+}
+
+pub struct HlsVideo {
+  source: PathBuf,
+  /// cache directory of the video, changes with the file
+  dir: PathBuf,
+  info: VideoInfo,
+}
+
+#[derive(Clone, Copy)]
+pub struct Rendition {
+  pub height: u32,
+  /// video bitrate in kbps
+  pub bitrate: u32,
+}
+
+fn cache_dir() -> PathBuf {
+  PathBuf::from(config!(hls_cache_path))
+}
+
+fn segment_duration() -> u32 {
+  config!(hls_segment_duration).max(1)
+}
+
+fn bitrate_for(height: u32) -> u32 {
+  match height {
+    0..=360 => 800,
+    361..=480 => 1400,
+    481..=720 => 2800,
+    721..=1080 => 5000,
+    _ => 8000,
+  }
+}
+
+/// Probe the video `file_root/file`, results are kept in memory until the file changes.
+pub fn open(file_root: &Path, file: &str) -> Result<HlsVideo, AppError> {
+  transcode::ensure_enabled()?;
+  let source = file_root.join(file);
+  let meta = fs::metadata(&source)?;
+  let modified = meta.modified()?.duration_since(UNIX_EPOCH)?.as_millis();
+  let mut hasher = Sha256::new();
+  hasher.update(format!("{file}\n{modified}\n{}", meta.len()));
+  let key = hex::encode(hasher.finalize());
+
+  let cached = VIDEOS.lock().unwrap().get_mut(&key).map(|(info, used)| {
+    *used = Instant::now();
+    *info
+  });
+  let info = match cached {
+    Some(info) => info,
+    None => {
+      let probe = ffprobe::probe(&source)?;
+      let duration = probe
+        .duration()
+        .ok_or(AppError::new("can not read the duration of the video"))?;
+      let stream = probe
+        .video_stream()
+        .ok_or(AppError::new("file has no video stream"))?;
+      let info = VideoInfo {
+        duration,
+        width: stream.width.unwrap_or(0),
+        height: stream.height.unwrap_or(0),
+      };
+      VIDEOS.lock().unwrap().insert(key.clone(), (info, Instant::now()));
+      info
+    }
+  };
+  Ok(HlsVideo {
+    source,
+    dir: cache_dir().join(key),
+    info,
+  })
+}
+
+impl HlsVideo {
+  /// configured renditions not larger than the video, the smallest one for tiny videos
+  pub fn renditions(&self) -> Vec<Rendition> {
+    let mut heights = config!(hls_renditions);
+    heights.sort_unstable();
+    heights.dedup();
+    let mut fit: Vec<u32> = heights
+      .iter()
+      .copied()
+      .filter(|h| self.info.height == 0 || *h <= self.info.height)
+      .collect();
+    if fit.is_empty() {
+      fit.push(heights.first().copied().unwrap_or(360).min(self.info.height.max(2)));
+    }
+    fit
+      .into_iter()
+      .map(|height| Rendition {
+        height,
+        bitrate: bitrate_for(height),
+      })
+      .collect()
+  }
+
+  fn rendition(&self, height: u32) -> Result<Rendition, AppError> {
+    self
+      .renditions()
+      .into_iter()
+      .find(|r| r.height == height)
+      .ok_or(AppError::new("unknown rendition").with_status(StatusCode::NOT_FOUND))
+  }
+
+  fn segment_count(&self) -> usize {
+    (self.info.duration / segment_duration() as f64).ceil().max(1.0) as usize
+  }
+
+  /// Master playlist listing the renditions, `file` is the query value the client used.
+  pub fn master_playlist(&self, file: &str) -> String {
+    let file = utf8_percent_encode(file, NON_ALPHANUMERIC);
+    let mut m3u8 = "#EXTM3U\n#EXT-X-VERSION:3\n".to_owned();
+    for r in self.renditions() {
+      let bandwidth = (r.bitrate + AUDIO_BITRATE) * 1000;
+      let _ = write!(m3u8, "#EXT-X-STREAM-INF:BANDWIDTH={bandwidth}");
+      if self.info.width > 0 && self.info.height > 0 {
+        // same rounding to an even width as the scale filter
+        let width = (self.info.width as u64 * r.height as u64 / self.info.height as u64 / 2 * 2) as u32;
+        let _ = write!(m3u8, ",RESOLUTION={width}x{}", r.height);
+      }
+      let _ = write!(m3u8, "\n{}/index.m3u8?file={file}\n", r.height);
+    }
+    m3u8
+  }
+
+  pub fn media_playlist(&self, height: u32, file: &str) -> Result<String, AppError> {
+    self.rendition(height)?;
+    let file = utf8_percent_encode(file, NON_ALPHANUMERIC);
+    let segment = segment_duration();
+    let mut m3u8 = format!(
+      "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{segment}\n\
+      #EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n"
+    );
+    let count = self.segment_count();
+    for i in 0..count {
+      let duration = if i + 1 == count {
+        self.info.duration - (segment as f64 * i as f64)
+      } else {
+        segment as f64
+      };
+      let _ = write!(m3u8, "#EXTINF:{duration:.3},\n{i}.ts?file={file}\n");
+    }
+    m3u8.push_str("#EXT-X-ENDLIST\n");
+    Ok(m3u8)
+  }
+
+  /// Path of a transcoded segment. Segments are cached and shared by all viewers, a missing
+  /// segment starts a transcode from its position, which keeps running ahead of the player.
+  /// The transcode counts against the limits of `username`, it waits while they are reached.
+  pub async fn segment(&self, height: u32, index: usize, username: &str) -> Result<PathBuf, AppError> {
+    let rendition = self.rendition(height)?;
+    if index >= self.segment_count() {
+      return Err(AppError::new("segment out of range").with_status(StatusCode::NOT_FOUND));
+    }
+    let dir = self.dir.join(height.to_string());
+    let file = dir.join(format!("{index}.ts"));
+    let deadline = Instant::now() + SEGMENT_WAIT_TIMEOUT;
+    loop {
+      // created before the checks, so a segment moved in between still wakes it
+      let changed = SEGMENTS_CHANGED.notified();
+      if file.exists() {
+        return Ok(file);
+      }
+      let running = self.ensure_job(&dir, rendition, index, username)?;
+      let now = Instant::now();
+      if now > deadline {
+        if !running {
+          return Err(
+            AppError::new("too many transcodes are running, try again later")
+              .with_status(StatusCode::SERVICE_UNAVAILABLE),
+          );
+        }
+        return Err(AppError::new("timeout while transcoding the video"));
+      }
+      // slots of other transcodes are freed without a notification
+      let wait = if running { deadline - now } else { SLOT_RETRY_INTERVAL };
+      let _ = tokio::time::timeout(wait, changed).await;
+    }
+  }
+
+  /// Make sure a transcode producing segment `index` is running, returns false when
+  /// it has to wait for a free transcode slot. Viewers at different positions of the same
+  /// rendition get their own transcodes, a job is reused when `index` is within its window.
+  fn ensure_job(&self, dir: &Path, rendition: Rendition, index: usize, username: &str) -> Result<bool, AppError> {
+    let key = (dir.to_path_buf(), index);
+    let id = match self.reserve_job(dir, index, username)? {
+      Reservation::Covered => return Ok(true),
+      Reservation::NoSlot => return Ok(false),
+      Reservation::Reserved(id) => id,
+    };
+    // ffmpeg is started without holding the job list
+    let child = start_ffmpeg(&self.source, dir, rendition, index);
+    let mut jobs = JOBS.lock().unwrap();
+    match (child, jobs.get_mut(&key).filter(|job| job.id == id)) {
+      (Ok(child), Some(job)) => job.child = Some(child),
+      // stopped while ffmpeg was starting
+      (Ok(mut child), None) => {
+        let _ = child.kill();
+        let _ = child.wait();
+        // a later job for the segment writes to the same directory
+        if !jobs.contains_key(&key) {
+          let _ = fs::remove_dir_all(tmp_dir(dir, index));
+        }
+      }
+      (Err(err), _) => {
+        if let Some(job) = jobs.remove(&key).filter(|job| job.id == id) {
+          job.stop();
+        }
+        return Err(err);
+      }
+    }
+    Ok(true)
+  }
+
+  /// Add a job for segment `index` with a transcode slot to the job list, unless a running
+  /// job covers the segment already. Fails when the job covering it failed.
+  fn reserve_job(&self, dir: &Path, index: usize, username: &str) -> Result<Reservation, AppError> {
+    let mut jobs = JOBS.lock().unwrap();
+    let mut failed = None;
+    for (key, job) in jobs.iter_mut().filter(|((d, _), _)| d == dir) {
+      let exited = job.harvest();
+      if !job.covers(index) {
+        continue;
+      }
+      if !exited {
+        job.last_access = Instant::now();
+        return Ok(Reservation::Covered);
+      }
+      failed = Some(key.clone());
+    }
+    if dir.join(format!("{index}.ts")).exists() {
+      return Ok(Reservation::Covered);
+    }
+    // it stopped without reaching the segment, starting over would fail the same way
+    if let Some(key) = failed {
+      if let Some(job) = jobs.remove(&key) {
+        job.stop();
+      }
+      return Err(AppError::new("fail to transcode the video"));
+    }
+    let permit = match transcode::try_acquire(username) {
+      Some(permit) => permit,
+      None => {
+        // the viewer seeked away from its own transcode, it gives up the slot for the new position
+        let seeked: Vec<(PathBuf, usize)> = jobs
+          .iter()
+          .filter(|((d, _), job)| d == dir && job.username == username)
+          .map(|(key, _)| key.clone())
+          .collect();
+        for key in seeked {
+          if let Some(job) = jobs.remove(&key) {
+            job.stop();
+          }
+        }
+        let Some(permit) = transcode::try_acquire(username) else {
+          return Ok(Reservation::NoSlot);
+        };
+        permit
+      }
+    };
+    let id = NEXT_JOB_ID.fetch_add(1, Ordering::SeqCst);
+    let job = TranscodeJob {
+      id,
+      child: None,
+      tmp_dir: tmp_dir(dir, index),
+      dir: dir.to_path_buf(),
+      start: index,
+      next_segment: index,
+      last_access: Instant::now(),
+      username: username.to_owned(),
+      _permit: permit,
+    };
+    jobs.insert((dir.to_path_buf(), index), job);
+    Ok(Reservation::Reserved(id))
+  }
+}
+
+enum Reservation {
+  Covered,
+  /// all transcode slots of the user or the server are taken
+  NoSlot,
+  /// id of the new job, its ffmpeg is not started yet
+  Reserved(u64),
+}
+
+struct TranscodeJob {
+  /// tells a job from a later one for the same segment
+  id: u64,
+  /// None while ffmpeg is starting
+  child: Option<Child>,
+  /// ffmpeg writes here, finished segments are moved to `dir`
+  tmp_dir: PathBuf,
+  dir: PathBuf,
+  start: usize,
+  /// first segment not moved to `dir` yet
+  next_segment: usize,
+  last_access: Instant,
+  /// user who started the job, it counts against their limits
+  username: String,
+  /// freed when the job is dropped
+  _permit: TranscodePermit,
+}
+
+fn tmp_dir(dir: &Path, start: usize) -> PathBuf {
+  dir.join(format!("tmp-{start}"))
+}
+
+/// Transcode `source` into segments of `rendition`, from segment `start` on.
+fn start_ffmpeg(source: &Path, dir: &Path, rendition: Rendition, start: usize) -> Result<Child, AppError> {
+  let tmp_dir = tmp_dir(dir, start);
+  fs::create_dir_all(&tmp_dir)?;
+  let segment = segment_duration();
+  let offset = (start as u64 * segment as u64).to_string();
+  let bitrate = rendition.bitrate;
+  let child = Command::new(config!(ffmpeg_bin_path))
+    .args(["-hide_banner", "-loglevel", "error", "-nostdin"])
+    .args(["-ss", &offset])
+    .arg("-i")
+    .arg(source)
+    .args(["-map", "0:v:0", "-map", "0:a:0?", "-sn", "-dn"])
+    .args(["-vf", &format!("scale=-2:{}", rendition.height)])
+    .args(["-c:v", "libx264", "-preset", "veryfast", "-pix_fmt", "yuv420p"])
+    .args(["-b:v", &format!("{bitrate}k")])
+    .args(["-maxrate", &format!("{}k", bitrate * 3 / 2)])
+    .args(["-bufsize", &format!("{}k", bitrate * 2)])
+    // keyframes on segment boundaries, so every segment starts decodable
+    .args(["-force_key_frames", &format!("expr:gte(t,n_forced*{segment})")])
+    .args(["-c:a", "aac", "-ac", "2", "-b:a", &format!("{AUDIO_BITRATE}k")])
+    .args(["-f", "segment", "-segment_format", "mpegts"])
+    .args(["-segment_time", &segment.to_string()])
+    .args(["-segment_start_number", &start.to_string()])
+    // timestamps continue from the position, segments of different runs play together
+    .args(["-output_ts_offset", &offset])
+    .arg(tmp_dir.join("%d.ts"))
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()?;
+  Ok(child)
+}
+
+impl TranscodeJob {
+  /// whether the job produces segment `index` soon
+  fn covers(&self, index: usize) -> bool {
+    index >= self.start && index <= self.next_segment + LOOKAHEAD_SEGMENTS
+  }
+
+  /// Move finished segments to the shared directory, a segment is finished when the next one
+  /// was started or ffmpeg exited successfully. Returns whether ffmpeg exited.
+  fn harvest(&mut self) -> bool {
+    let Some(child) = &mut self.child else {
+      return false;
+    };
+    let status = child.try_wait().ok().flatten();
+    let succeeded = status.is_some_and(|s| s.success());
+    let harvested_from = self.next_segment;
+    loop {
+      let file = self.tmp_dir.join(format!("{}.ts", self.next_segment));
+      if !file.exists() {
+        break;
+      }
+      let next = self.tmp_dir.join(format!("{}.ts", self.next_segment + 1));
+      if !next.exists() && !succeeded {
+        break;
+      }
+      let dest = self.dir.join(format!("{}.ts", self.next_segment));
+      if let Err(err) = fs::rename(&file, &dest) {
+        warn!("fail to move segment {file:?}: {err}");
+        break;
+      }
+      self.next_segment += 1;
+    }
+    if self.next_segment > harvested_from || status.is_some() {
+      SEGMENTS_CHANGED.notify_waiters();
+    }
+    status.is_some()
+  }
+
+  fn stop(mut self) {
+    if let Some(mut child) = self.child.take() {
+      let _ = child.kill();
+      let _ = child.wait();
+    }
+    let _ = fs::remove_dir_all(&self.tmp_dir);
+    // viewers waiting for a slot may take the freed one
+    SEGMENTS_CHANGED.notify_waiters();
+  }
+}
+
+/// Move finished segments of running transcodes to the cache. Every `CLEANUP_INTERVAL` also
+/// stop transcodes nobody requested segments from for `hls_idle_timeout` seconds, and remove
+/// cached videos nobody watched for `hls_cache_max_age` seconds together with their probe
+/// results.
+fn spawn_harvester(jobs: Jobs) {
+  thread::Builder::new()
+    .name("hls-harvester".to_owned())
+    .spawn(move || {
+      let mut last_cleanup = Instant::now();
+      loop {
+        thread::sleep(HARVEST_INTERVAL);
+        let cleanup = last_cleanup.elapsed() >= CLEANUP_INTERVAL;
+        let idle_timeout = Duration::from_secs(config!(hls_idle_timeout));
+        let mut jobs = jobs.lock().unwrap();
+        let finished: Vec<(PathBuf, usize)> = jobs
+          .iter_mut()
+          .filter_map(|(key, job)| {
+            let exited = job.harvest();
+            let idle = cleanup && job.last_access.elapsed() > idle_timeout;
+            (exited || idle).then(|| key.clone())
+          })
+          .collect();
+        for key in finished {
+          if let Some(job) = jobs.remove(&key) {
+            job.stop();
+          }
+        }
+        if !cleanup {
+          continue;
+        }
+        last_cleanup = Instant::now();
+        let active: Vec<PathBuf> = jobs.keys().map(|(dir, _)| dir.clone()).collect();
+        drop(jobs);
+        remove_stale_videos(&active);
+      }
+    })
+    .unwrap();
+}
+
+fn remove_stale_videos(active: &[PathBuf]) {
+  let max_age = Duration::from_secs(config!(hls_cache_max_age));
+  // every playlist and segment request marks the video as used
+  let recently_used: HashSet<String> = {
+    let mut videos = VIDEOS.lock().unwrap();
+    videos.retain(|_, (_, used)| used.elapsed() <= max_age);
+    videos.keys().cloned().collect()
+  };
+  let Ok(entries) = fs::read_dir(cache_dir()) else {
+    return;
+  };
+  for entry in entries.filter_map(|e| e.ok()) {
+    let video_dir = entry.path();
+    let Some(key) = video_dir.file_name().and_then(|n| n.to_str()) else {
+      continue;
+    };
+    if recently_used.contains(key) || active.iter().any(|d| d.starts_with(&video_dir)) {
+      continue;
+    }
+    // nothing is known about videos not opened since the start, rendition directories
+    // change whenever a segment is added
+    let last_write = fs::read_dir(&video_dir)
+      .into_iter()
+      .flatten()
+      .filter_map(|e| e.ok()?.metadata().ok()?.modified().ok())
+      .max();
+    let stale = last_write.is_none_or(|t| {
+      SystemTime::now()
+        .duration_since(t)
+        .is_ok_and(|age| age > max_age)
+    });
+    if stale {
+      if let Err(err) = fs::remove_dir_all(&video_dir) {
+        warn!("fail to remove hls cache {video_dir:?}: {err}");
+      }
+    }
+  }
+}
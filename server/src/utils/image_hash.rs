@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use actix_web::http::StatusCode;
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+use serde::Serialize;
+
+use crate::{db::SHARED_DB_CONN, models::ImageHash};
+
+use super::derived_table::DerivedTable;
+use super::error::AppError;
+use super::gallery::strip_user_root;
+use super::path::folder_like_pattern;
+
+const HASH_BATCH_SIZE: usize = 100;
+/// larger images are not decoded for hashing
+const MAX_IMAGE_FILE_SIZE: i64 = 1024 * 1024 * 100;
+/// higher thresholds match unrelated images and leave too few bits per band
+pub const MAX_THRESHOLD: u32 = 16;
+
+pub fn check_threshold(threshold: u32) -> Result<u32, AppError> {
+  if threshold > MAX_THRESHOLD {
+    return Err(
+      AppError::new(&format!("threshold must be at most {MAX_THRESHOLD}"))
+        .with_status(StatusCode::BAD_REQUEST),
+    );
+  }
+  Ok(threshold)
+}
+
+/// Difference hash: the image is shrunk to 9x8 grayscale pixels and each bit tells whether
+/// a pixel is brighter than its right neighbour. Resized or recompressed copies of an image
+/// get hashes within a small hamming distance.
+pub fn dhash(file: &Path) -> Result<u64, AppError> {
+  let img = image::open(file)?.thumbnail_exact(9, 8).to_luma8();
+  let mut hash = 0u64;
+  for y in 0..8 {
+    for x in 0..8 {
+      let left = img.get_pixel(x, y)[0];
+      let right = img.get_pixel(x + 1, y)[0];
+      hash = (hash << 1) | (left > right) as u64;
+    }
+  }
+  Ok(hash)
+}
+
+pub fn hamming(a: u64, b: u64) -> u32 {
+  (a ^ b).count_ones()
+}
+
+fn save_image_hashes(batch: &mut Vec<ImageHash>) -> Result<(), AppError> {
+  use crate::schema::image_hashes::table;
+  if batch.is_empty() {
+    return Ok(());
+  }
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::replace_into(table)
+    .values(&*batch)
+    .execute(&mut *conn)?;
+  batch.clear();
+  Ok(())
+}
+
+pub fn forget_image_hashes(conn: &mut SqliteConnection, files: &[String]) -> Result<(), AppError> {
+  use crate::schema::image_hashes::dsl::*;
+  for chunk in files.chunks(HASH_BATCH_SIZE) {
+    diesel::delete(image_hashes.filter(file_path.eq_any(chunk))).execute(conn)?;
+  }
+  Ok(())
+}
+
+fn known_image_hashes(conn: &mut SqliteConnection) -> Result<Vec<(String, i64, String)>, AppError> {
+  use crate::schema::image_hashes::dsl::*;
+  Ok(image_hashes.select((file_path, size, modified_at)).load(conn)?)
+}
+
+/// Perceptual hashes of the images of the file index.
+pub const IMAGE_HASHES: DerivedTable<ImageHash> = DerivedTable {
+  what: "image hash",
+  wanted: |format, size| format.contains("image") && size <= MAX_IMAGE_FILE_SIZE,
+  load_known: known_image_hashes,
+  derive: |file_root, file, size, modified_at| {
+    Ok(ImageHash {
+      file_path: file.to_owned(),
+      size,
+      modified_at: modified_at.to_owned(),
+      dhash: dhash(&file_root.join(file))? as i64,
+    })
+  },
+  save: save_image_hashes,
+  forget: forget_image_hashes,
+};
+
+/// Hashes of the images under `user_root` (relative to file root).
+fn load_image_hashes(user_root: &str) -> Result<Vec<(String, u64)>, AppError> {
+  use crate::schema::image_hashes::dsl::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let rows: Vec<(String, i64)> = image_hashes
+    .filter(file_path.like(folder_like_pattern(user_root)).escape('\\'))
+    .select((file_path, dhash))
+    .load(&mut *conn)?;
+  Ok(rows.into_iter().map(|(p, h)| (p, h as u64)).collect())
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimilarImage {
+  pub file_path: String,
+  pub distance: u32,
+}
+
+/// Images of the user within `threshold` of `file` (relative to file root), closest first.
+/// Returned paths are relative to the user root.
+pub fn find_similar_images(
+  user_root: &str,
+  file: &str,
+  threshold: u32,
+) -> Result<Vec<SimilarImage>, AppError> {
+  let threshold = check_threshold(threshold)?;
+  let hashes = load_image_hashes(user_root)?;
+  let (_, target) = hashes
+    .iter()
+    .find(|(p, _)| p == file)
+    .ok_or(AppError::new("image is not indexed yet"))?;
+  let mut similar: Vec<SimilarImage> = hashes
+    .iter()
+    .filter(|(p, _)| p != file)
+    .map(|(p, h)| SimilarImage {
+      file_path: strip_user_root(user_root, p),
+      distance: hamming(*target, *h),
+    })
+    .filter(|s| s.distance <= threshold)
+    .collect();
+  similar.sort_by_key(|s| s.distance);
+  Ok(similar)
+}
+
+fn find_root(parent: &mut [usize], i: usize) -> usize {
+  let mut root = i;
+  while parent[root] != root {
+    root = parent[root];
+  }
+  // path compression
+  let mut i = i;
+  while parent[i] != root {
+    let next = parent[i];
+    parent[i] = root;
+    i = next;
+  }
+  root
+}
+
+/// Split the 64 bits of a hash into `count` bands of about the same width.
+fn bands(count: u32) -> Vec<(u32, u64)> {
+  let mut r = vec![];
+  let mut start = 0;
+  for i in 0..count {
+    let end = 64 * (i + 1) / count;
+    let width = end - start;
+    let mask = if width == 64 { u64::MAX } else { (1u64 << width) - 1 };
+    r.push((start, mask));
+    start = end;
+  }
+  r
+}
+
+/// Groups of the user's images connected by hashes within `threshold`, largest group first.
+/// Paths are relative to the user root.
+///
+/// Two hashes within `threshold` bits agree on at least one of `threshold + 1` bands, so only
+/// images sharing a band are compared instead of every pair.
+pub fn find_near_duplicate_groups(user_root: &str, threshold: u32) -> Result<Vec<Vec<String>>, AppError> {
+  let threshold = check_threshold(threshold)?;
+  let hashes = load_image_hashes(user_root)?;
+  let mut parent: Vec<usize> = (0..hashes.len()).collect();
+  for (shift, mask) in bands(threshold + 1) {
+    let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, (_, h)) in hashes.iter().enumerate() {
+      buckets.entry((h >> shift) & mask).or_default().push(i);
+    }
+    for bucket in buckets.values().filter(|b| b.len() > 1) {
+      for (n, &i) in bucket.iter().enumerate() {
+        for &j in &bucket[n + 1..] {
+          let (a, b) = (find_root(&mut parent, i), find_root(&mut parent, j));
+          if a != b && hamming(hashes[i].1, hashes[j].1) <= threshold {
+            parent[b] = a;
+          }
+        }
+      }
+    }
+  }
+  let mut groups: HashMap<usize, Vec<String>> = HashMap::new();
+  for (i, (path, _)) in hashes.iter().enumerate() {
+    let root = find_root(&mut parent, i);
+    groups
+      .entry(root)
+      .or_default()
+      .push(strip_user_root(user_root, path));
+  }
+  let mut groups: Vec<Vec<String>> = groups.into_values().filter(|g| g.len() > 1).collect();
+  groups.sort_by_key(|g| std::cmp::Reverse(g.len()));
+  Ok(groups)
+}
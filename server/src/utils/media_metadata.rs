@@ -0,0 +1,212 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use chrono::NaiveDate;
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+use exif::{Exif, In, Tag, Value};
+use image::DynamicImage;
+
+use crate::{db::SHARED_DB_CONN, models::MediaMetadata};
+
+use super::derived_table::DerivedTable;
+use super::error::AppError;
+
+const METADATA_BATCH_SIZE: usize = 100;
+
+fn read_exif(file: &Path) -> Option<Exif> {
+  let f = File::open(file).ok()?;
+  exif::Reader::new()
+    .read_from_container(&mut BufReader::new(f))
+    .ok()
+}
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+  match &exif.get_field(tag, In::PRIMARY)?.value {
+    Value::Ascii(values) => {
+      let s = String::from_utf8_lossy(values.first()?);
+      let s = s.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+      (!s.is_empty()).then(|| s.to_owned())
+    }
+    _ => None,
+  }
+}
+
+fn uint_field(exif: &Exif, tag: Tag) -> Option<u32> {
+  exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+fn rational_field(exif: &Exif, tag: Tag) -> Option<Vec<f64>> {
+  match &exif.get_field(tag, In::PRIMARY)?.value {
+    Value::Rational(values) => Some(values.iter().map(|r| r.to_f64()).collect()),
+    _ => None,
+  }
+}
+
+/// capture time in millis, shifted to UTC when the camera recorded its offset
+fn taken_at(exif: &Exif) -> Option<i64> {
+  let (time_tag, offset_tag) = [
+    (Tag::DateTimeOriginal, Tag::OffsetTimeOriginal),
+    (Tag::DateTimeDigitized, Tag::OffsetTimeDigitized),
+    (Tag::DateTime, Tag::OffsetTime),
+  ]
+  .into_iter()
+  .find(|(t, _)| exif.get_field(*t, In::PRIMARY).is_some())?;
+  let raw = match &exif.get_field(time_tag, In::PRIMARY)?.value {
+    Value::Ascii(values) => values.first()?.clone(),
+    _ => return None,
+  };
+  let mut dt = exif::DateTime::from_ascii(&raw).ok()?;
+  if let Some(Value::Ascii(values)) = exif.get_field(offset_tag, In::PRIMARY).map(|f| &f.value) {
+    if let Some(offset) = values.first() {
+      let _ = dt.parse_offset(offset);
+    }
+  }
+  let local = NaiveDate::from_ymd_opt(dt.year as i32, dt.month as u32, dt.day as u32)?
+    .and_hms_opt(dt.hour as u32, dt.minute as u32, dt.second as u32)?;
+  let offset_millis = dt.offset.unwrap_or(0) as i64 * 60 * 1000;
+  Some(local.timestamp_millis() - offset_millis)
+}
+
+/// degrees from the degree, minute and second rationals, negative for south and west
+fn gps_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: &str) -> Option<f64> {
+  let dms = rational_field(exif, tag)?;
+  let mut degrees = dms.first()? + dms.get(1).unwrap_or(&0.0) / 60.0 + dms.get(2).unwrap_or(&0.0) / 3600.0;
+  if ascii_field(exif, ref_tag).is_some_and(|r| r == negative_ref) {
+    degrees = -degrees;
+  }
+  degrees.is_finite().then_some(degrees)
+}
+
+fn altitude(exif: &Exif) -> Option<f64> {
+  let altitude = *rational_field(exif, Tag::GPSAltitude)?.first()?;
+  // reference 1 means below sea level
+  let below = uint_field(exif, Tag::GPSAltitudeRef) == Some(1);
+  let altitude = if below { -altitude } else { altitude };
+  altitude.is_finite().then_some(altitude)
+}
+
+/// EXIF orientation of an image, 1 when it is not recorded
+pub fn read_orientation(file: &Path) -> u32 {
+  read_exif(file)
+    .and_then(|exif| uint_field(&exif, Tag::Orientation))
+    .filter(|o| (1..=8).contains(o))
+    .unwrap_or(1)
+}
+
+/// Rotate and flip a decoded image so it is displayed upright.
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+  match orientation {
+    2 => img.fliph(),
+    3 => img.rotate180(),
+    4 => img.flipv(),
+    5 => img.rotate90().fliph(),
+    6 => img.rotate90(),
+    7 => img.rotate270().fliph(),
+    8 => img.rotate270(),
+    _ => img,
+  }
+}
+
+/// Read the metadata of the image `file_root/file`, fields which are not recorded stay empty.
+pub fn extract(file_root: &Path, file: &str) -> Result<MediaMetadata, AppError> {
+  let path = file_root.join(file);
+  let meta = path.metadata()?;
+  let modified_at = meta
+    .modified()?
+    .duration_since(UNIX_EPOCH)?
+    .as_millis()
+    .to_string();
+  let mut media = MediaMetadata {
+    file_path: file.to_owned(),
+    size: meta.len() as i64,
+    modified_at,
+    ..Default::default()
+  };
+  let exif = read_exif(&path);
+  let mut orientation = 1;
+  let mut dimensions = None;
+  if let Some(exif) = &exif {
+    orientation = uint_field(exif, Tag::Orientation)
+      .filter(|o| (1..=8).contains(o))
+      .unwrap_or(1);
+    media.taken_at = taken_at(exif);
+    media.camera_make = ascii_field(exif, Tag::Make);
+    media.camera_model = ascii_field(exif, Tag::Model);
+    media.lens = ascii_field(exif, Tag::LensModel);
+    media.latitude = gps_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+    media.longitude = gps_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+    media.altitude = altitude(exif);
+    media.orientation = Some(orientation as i32);
+    dimensions = uint_field(exif, Tag::PixelXDimension).zip(uint_field(exif, Tag::PixelYDimension));
+  }
+  // only the header is read for the dimensions
+  let dimensions = dimensions.or_else(|| image::image_dimensions(&path).ok());
+  if let Some((w, h)) = dimensions {
+    let (w, h) = if orientation >= 5 { (h, w) } else { (w, h) };
+    media.width = Some(w as i32);
+    media.height = Some(h as i32);
+  }
+  Ok(media)
+}
+
+fn save_media_metadata(batch: &mut Vec<MediaMetadata>) -> Result<(), AppError> {
+  use crate::schema::media_metadata::table;
+  if batch.is_empty() {
+    return Ok(());
+  }
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::replace_into(table)
+    .values(&*batch)
+    .execute(&mut *conn)?;
+  batch.clear();
+  Ok(())
+}
+
+pub fn forget_media_metadata(conn: &mut SqliteConnection, files: &[String]) -> Result<(), AppError> {
+  use crate::schema::media_metadata::dsl::*;
+  for chunk in files.chunks(METADATA_BATCH_SIZE) {
+    diesel::delete(media_metadata.filter(file_path.eq_any(chunk))).execute(conn)?;
+  }
+  Ok(())
+}
+
+fn known_media_metadata(conn: &mut SqliteConnection) -> Result<Vec<(String, i64, String)>, AppError> {
+  use crate::schema::media_metadata::dsl::*;
+  Ok(media_metadata.select((file_path, size, modified_at)).load(conn)?)
+}
+
+/// Metadata of the images of the file index.
+pub const MEDIA_METADATA: DerivedTable<MediaMetadata> = DerivedTable {
+  what: "metadata",
+  wanted: |format, _| format.contains("image"),
+  load_known: known_media_metadata,
+  derive: |file_root, file, _, _| extract(file_root, file),
+  save: save_media_metadata,
+  forget: forget_media_metadata,
+};
+
+/// Metadata of `file` (relative to file root), read from the file when the stored
+/// metadata is missing or outdated.
+pub fn media_info(file_root: &Path, file: &str) -> Result<MediaMetadata, AppError> {
+  let stored = {
+    use crate::schema::media_metadata::dsl::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    media_metadata
+      .filter(file_path.eq(file))
+      .first::<MediaMetadata>(&mut *conn)
+      .optional()?
+  };
+  let meta = file_root.join(file).metadata()?;
+  let modified = meta.modified()?.duration_since(UNIX_EPOCH)?.as_millis().to_string();
+  match stored {
+    Some(m) if m.size == meta.len() as i64 && m.modified_at == modified => Ok(m),
+    _ => {
+      let media = extract(file_root, file)?;
+      save_media_metadata(&mut vec![media.clone()])?;
+      Ok(media)
+    }
+  }
+}
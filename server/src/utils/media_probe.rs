@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+use mime_guess::mime;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{db::SHARED_DB_CONN, models::MediaProbe};
+
+use super::derived_table::{self, DerivedTable};
+use super::error::AppError;
+use super::ffprobe;
+use super::transcode;
+
+const PROBE_BATCH_SIZE: usize = 100;
+/// containers and codecs browsers play without a transcode
+const DIRECT_PLAY_CONTAINERS: [&str; 8] = ["mov", "mp4", "m4a", "webm", "ogg", "mp3", "flac", "wav"];
+const DIRECT_PLAY_VIDEO_CODECS: [&str; 4] = ["h264", "vp8", "vp9", "av1"];
+const DIRECT_PLAY_AUDIO_CODECS: [&str; 7] = ["aac", "mp3", "opus", "vorbis", "flac", "pcm_s16le", "pcm_u8"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamInfo {
+  pub index: u32,
+  /// video, audio, subtitle, ...
+  pub codec_type: Option<String>,
+  pub codec_name: Option<String>,
+  pub width: Option<u32>,
+  pub height: Option<u32>,
+  pub channels: Option<u32>,
+  pub bitrate: Option<i64>,
+  pub language: Option<String>,
+  pub title: Option<String>,
+  pub default: bool,
+}
+
+/// Probe result as returned by the api.
+#[derive(Debug, Serialize)]
+pub struct MediaInfo {
+  pub duration: Option<f64>,
+  pub container: Option<String>,
+  pub video_codec: Option<String>,
+  pub audio_codec: Option<String>,
+  pub width: Option<i32>,
+  pub height: Option<i32>,
+  pub bitrate: Option<i64>,
+  pub streams: Vec<StreamInfo>,
+  /// browsers can play the file as it is, otherwise it needs a transcode
+  pub direct_play: bool,
+}
+
+pub fn is_media(file: &str) -> bool {
+  mime_guess::from_path(file)
+    .first()
+    .is_some_and(|m| m.type_() == mime::VIDEO || m.type_() == mime::AUDIO)
+}
+
+/// Run ffprobe on the audio or video `file_root/file`.
+pub fn probe(file_root: &Path, file: &str) -> Result<MediaProbe, AppError> {
+  let path = file_root.join(file);
+  let meta = path.metadata()?;
+  let modified_at = meta
+    .modified()?
+    .duration_since(UNIX_EPOCH)?
+    .as_millis()
+    .to_string();
+  let r = ffprobe::probe(&path)?;
+  let streams: Vec<StreamInfo> = r
+    .streams
+    .iter()
+    .map(|s| StreamInfo {
+      index: s.index,
+      codec_type: s.codec_type.clone(),
+      codec_name: s.codec_name.clone(),
+      width: s.width,
+      height: s.height,
+      channels: s.channels,
+      bitrate: s.bit_rate.as_ref().and_then(|b| b.parse().ok()),
+      language: s.tag("language").map(|l| l.to_owned()),
+      title: s.tag("title").map(|t| t.to_owned()),
+      default: s.disposition.get("default") == Some(&1),
+    })
+    .collect();
+  let video = r.video_stream();
+  Ok(MediaProbe {
+    file_path: file.to_owned(),
+    size: meta.len() as i64,
+    modified_at,
+    duration: r.duration(),
+    container: r.format.format_name.clone(),
+    video_codec: video.and_then(|s| s.codec_name.clone()),
+    audio_codec: r.audio_stream().and_then(|s| s.codec_name.clone()),
+    width: video.and_then(|s| s.width).map(|w| w as i32),
+    height: video.and_then(|s| s.height).map(|h| h as i32),
+    bitrate: r.bitrate(),
+    streams: serde_json::to_string(&streams).map_err(|e| AppError::new(&e.to_string()))?,
+  })
+}
+
+/// Whether browsers play the file without a transcode. Matroska shares its format name
+/// with webm, so it is told apart by the extension.
+pub fn can_direct_play(p: &MediaProbe) -> bool {
+  let Some(container) = &p.container else {
+    return false;
+  };
+  let names: Vec<&str> = container.split(',').collect();
+  let is_webm = names.contains(&"webm");
+  let container_ok = if is_webm {
+    p.file_path.to_lowercase().ends_with(".webm")
+  } else {
+    names.iter().any(|n| DIRECT_PLAY_CONTAINERS.contains(n))
+  };
+  let video_ok = p
+    .video_codec
+    .as_deref()
+    .is_none_or(|c| DIRECT_PLAY_VIDEO_CODECS.contains(&c));
+  let audio_ok = p
+    .audio_codec
+    .as_deref()
+    .is_none_or(|c| DIRECT_PLAY_AUDIO_CODECS.contains(&c));
+  container_ok && video_ok && audio_ok && (p.video_codec.is_some() || p.audio_codec.is_some())
+}
+
+impl From<MediaProbe> for MediaInfo {
+  fn from(p: MediaProbe) -> Self {
+    let direct_play = can_direct_play(&p);
+    let streams = serde_json::from_str(&p.streams).unwrap_or_default();
+    Self {
+      duration: p.duration,
+      container: p.container,
+      video_codec: p.video_codec,
+      audio_codec: p.audio_codec,
+      width: p.width,
+      height: p.height,
+      bitrate: p.bitrate,
+      streams,
+      direct_play,
+    }
+  }
+}
+
+fn save_media_probes(batch: &mut Vec<MediaProbe>) -> Result<(), AppError> {
+  use crate::schema::media_probes::table;
+  if batch.is_empty() {
+    return Ok(());
+  }
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::replace_into(table)
+    .values(&*batch)
+    .execute(&mut *conn)?;
+  batch.clear();
+  Ok(())
+}
+
+pub fn forget_media_probes(conn: &mut SqliteConnection, files: &[String]) -> Result<(), AppError> {
+  use crate::schema::media_probes::dsl::*;
+  for chunk in files.chunks(PROBE_BATCH_SIZE) {
+    diesel::delete(media_probes.filter(file_path.eq_any(chunk))).execute(conn)?;
+  }
+  Ok(())
+}
+
+fn known_media_probes(conn: &mut SqliteConnection) -> Result<Vec<(String, i64, String)>, AppError> {
+  use crate::schema::media_probes::dsl::*;
+  Ok(media_probes.select((file_path, size, modified_at)).load(conn)?)
+}
+
+/// ffprobe results of the audio and video files of the file index.
+pub const MEDIA_PROBES: DerivedTable<MediaProbe> = DerivedTable {
+  what: "media streams",
+  wanted: |format, _| format.contains("video") || format.contains("audio"),
+  load_known: known_media_probes,
+  derive: |file_root, file, _, _| probe(file_root, file),
+  save: save_media_probes,
+  forget: forget_media_probes,
+};
+
+/// Probe the audio and video files in the file index, unchanged files are skipped.
+/// Must run after the file index is cleaned up.
+pub fn update_media_probes(file_root: &Path) -> Result<(), AppError> {
+  if !transcode::ffprobe_available() {
+    warn!("ffprobe is not available, skip probing media files");
+    return Ok(());
+  }
+  derived_table::sync(file_root, &MEDIA_PROBES)
+}
+
+/// Stored probe results of `files` (relative to file root), keyed by path. Files which were
+/// not probed yet are left out, nothing is probed here so listings stay fast.
+pub fn stored_media_info(files: &[String]) -> Result<HashMap<String, MediaInfo>, AppError> {
+  use crate::schema::media_probes::dsl::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let mut r = HashMap::new();
+  for chunk in files.chunks(PROBE_BATCH_SIZE) {
+    let probes = media_probes
+      .filter(file_path.eq_any(chunk))
+      .load::<MediaProbe>(&mut *conn)?;
+    for p in probes {
+      r.insert(p.file_path.clone(), MediaInfo::from(p));
+    }
+  }
+  Ok(r)
+}
+
+/// Probe result of `file` (relative to file root), probed when the stored one is missing
+/// or outdated.
+pub fn media_info(file_root: &Path, file: &str) -> Result<MediaInfo, AppError> {
+  let stored = {
+    use crate::schema::media_probes::dsl::*;
+    let mut conn = SHARED_DB_CONN.lock().unwrap();
+    media_probes
+      .filter(file_path.eq(file))
+      .first::<MediaProbe>(&mut *conn)
+      .optional()?
+  };
+  let meta = file_root.join(file).metadata()?;
+  let modified = meta.modified()?.duration_since(UNIX_EPOCH)?.as_millis().to_string();
+  match stored {
+    Some(p) if p.size == meta.len() as i64 && p.modified_at == modified => Ok(p.into()),
+    _ => {
+      let p = probe(file_root, file)?;
+      save_media_probes(&mut vec![p.clone()])?;
+      Ok(p.into())
+    }
+  }
+}
@@ -6,11 +6,30 @@ pub mod crypto;
 pub mod session;
 pub mod auth;
 pub mod transcode;
+pub mod ffprobe;
+pub mod hls;
+pub mod subtitle;
 pub mod stream;
 pub mod path;
 pub mod gallery;
+pub mod album;
 pub mod search_engine;
+pub mod filename_index;
+pub mod tokenizer;
 pub mod doc_parser;
+pub mod office_parser;
+pub mod archive;
+pub mod derived_table;
+pub mod duplicates;
+pub mod image_hash;
+pub mod media_metadata;
+pub mod media_probe;
+pub mod music;
+pub mod thumbnail;
+pub mod video_preview;
+pub mod saved_search;
 pub mod eventbus;
+pub mod push;
+pub mod webhook;
 #[cfg(debug_assertions)]
 pub mod performance;
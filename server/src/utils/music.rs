@@ -0,0 +1,220 @@
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use actix_web::http::StatusCode;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Nullable, Text};
+use diesel::{sql_query, SqliteConnection};
+use lofty::{Accessor, AudioFile, ItemKey, PictureType, Probe, TaggedFileExt};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  db::SHARED_DB_CONN,
+  models::{MusicAlbum, MusicArtist, MusicTrack},
+};
+
+use super::derived_table::DerivedTable;
+use super::error::AppError;
+use super::path::{folder_like_pattern, like_escape};
+use super::thumbnail::{self, CacheEntry, MAX_DIMENSION, MIN_DIMENSION};
+
+const MUSIC_BATCH_SIZE: usize = 100;
+/// part of the cache key of cover thumbnails, bump it when they change
+const COVER_CACHE_VERSION: u32 = 1;
+/// tracks are grouped by album artist, falling back to the track artist
+const ARTIST_SQL: &str = "coalesce(nullif(album_artist, ''), nullif(artist, ''), 'Unknown Artist')";
+const ALBUM_SQL: &str = "coalesce(nullif(album, ''), 'Unknown Album')";
+
+fn non_empty(s: Option<std::borrow::Cow<str>>) -> Option<String> {
+  s.map(|s| s.trim().to_owned()).filter(|s| !s.is_empty())
+}
+
+/// Read the tags of the audio file `file_root/file`, fields which are not tagged stay empty.
+pub fn read_tags(file_root: &Path, file: &str) -> Result<MusicTrack, AppError> {
+  let path = file_root.join(file);
+  let meta = path.metadata()?;
+  let modified_at = meta
+    .modified()?
+    .duration_since(UNIX_EPOCH)?
+    .as_millis()
+    .to_string();
+  let tagged = Probe::open(&path)?.guess_file_type()?.read()?;
+  let properties = tagged.properties();
+  let duration = properties.duration().as_secs_f64();
+  let mut track = MusicTrack {
+    file_path: file.to_owned(),
+    size: meta.len() as i64,
+    modified_at,
+    duration: (duration > 0.0).then_some(duration),
+    bitrate: properties.audio_bitrate().map(|b| b as i32),
+    ..Default::default()
+  };
+  if let Some(tag) = tagged.primary_tag().or_else(|| tagged.first_tag()) {
+    track.title = non_empty(tag.title());
+    track.artist = non_empty(tag.artist());
+    track.album = non_empty(tag.album());
+    track.album_artist = tag
+      .get_string(&ItemKey::AlbumArtist)
+      .map(|a| a.trim().to_owned())
+      .filter(|a| !a.is_empty());
+    track.genre = non_empty(tag.genre());
+    track.track_number = tag.track().map(|t| t as i32);
+    track.disc_number = tag.disk().map(|d| d as i32);
+    track.year = tag.year().map(|y| y as i32);
+    track.has_cover = !tag.pictures().is_empty();
+  }
+  Ok(track)
+}
+
+fn save_music_tracks(batch: &mut Vec<MusicTrack>) -> Result<(), AppError> {
+  use crate::schema::music_tracks::table;
+  if batch.is_empty() {
+    return Ok(());
+  }
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  diesel::replace_into(table)
+    .values(&*batch)
+    .execute(&mut *conn)?;
+  batch.clear();
+  Ok(())
+}
+
+pub fn forget_music_tracks(conn: &mut SqliteConnection, files: &[String]) -> Result<(), AppError> {
+  use crate::schema::music_tracks::dsl::*;
+  for chunk in files.chunks(MUSIC_BATCH_SIZE) {
+    diesel::delete(music_tracks.filter(file_path.eq_any(chunk))).execute(conn)?;
+  }
+  Ok(())
+}
+
+fn known_music_tracks(conn: &mut SqliteConnection) -> Result<Vec<(String, i64, String)>, AppError> {
+  use crate::schema::music_tracks::dsl::*;
+  Ok(music_tracks.select((file_path, size, modified_at)).load(conn)?)
+}
+
+/// Tags of the audio files of the file index.
+pub const MUSIC_TRACKS: DerivedTable<MusicTrack> = DerivedTable {
+  what: "tags",
+  wanted: |format, _| format.contains("audio"),
+  load_known: known_music_tracks,
+  derive: |file_root, file, _, _| read_tags(file_root, file),
+  save: save_music_tracks,
+  forget: forget_music_tracks,
+};
+
+/// Artists in the library of a user, by name.
+pub fn list_artists(user_root: &str) -> Result<Vec<MusicArtist>, AppError> {
+  let sql = format!(
+    "select {ARTIST_SQL} as artist, count(distinct {ALBUM_SQL}) as album_count, count(*) as track_count \
+    from music_tracks where file_path like ? escape '\\' group by 1 order by 1 collate nocase"
+  );
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = sql_query(sql)
+    .bind::<Text, _>(folder_like_pattern(user_root))
+    .load::<MusicArtist>(conn)?;
+  Ok(r)
+}
+
+/// Albums in the library of a user, only the ones of `artist` when it is set.
+pub fn list_albums(user_root: &str, artist: Option<&str>) -> Result<Vec<MusicAlbum>, AppError> {
+  let sql = format!(
+    "select {ALBUM_SQL} as album, {ARTIST_SQL} as artist, max(year) as year, count(*) as track_count, \
+    coalesce(sum(duration), 0) as duration, min(case when has_cover then file_path end) as cover \
+    from music_tracks where file_path like ? escape '\\' and (? is null or {ARTIST_SQL} = ?) \
+    group by 1, 2 order by 2 collate nocase, year, 1 collate nocase"
+  );
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = sql_query(sql)
+    .bind::<Text, _>(folder_like_pattern(user_root))
+    .bind::<Nullable<Text>, _>(artist)
+    .bind::<Nullable<Text>, _>(artist)
+    .load::<MusicAlbum>(conn)?;
+  Ok(r)
+}
+
+/// Tracks of a user filtered by artist and album as grouped by `list_albums`,
+/// in disc and track order.
+pub fn list_tracks(
+  user_root: &str,
+  artist: Option<&str>,
+  album: Option<&str>,
+  offset: usize,
+  limit: usize,
+) -> Result<Vec<MusicTrack>, AppError> {
+  let sql = format!(
+    "select * from music_tracks where file_path like ? escape '\\' \
+    and (? is null or {ARTIST_SQL} = ?) and (? is null or {ALBUM_SQL} = ?) \
+    order by disc_number, track_number, file_path limit ? offset ?"
+  );
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = sql_query(sql)
+    .bind::<Text, _>(folder_like_pattern(user_root))
+    .bind::<Nullable<Text>, _>(artist)
+    .bind::<Nullable<Text>, _>(artist)
+    .bind::<Nullable<Text>, _>(album)
+    .bind::<Nullable<Text>, _>(album)
+    .bind::<BigInt, _>(limit as i64)
+    .bind::<BigInt, _>(offset as i64)
+    .load::<MusicTrack>(conn)?;
+  Ok(r)
+}
+
+/// Tracks whose title, artist or album contains `keyword`.
+pub fn search_tracks(user_root: &str, keyword: &str, limit: usize) -> Result<Vec<MusicTrack>, AppError> {
+  let sql = "select * from music_tracks where file_path like ?1 escape '\\' \
+    and (title like ?2 escape '\\' or artist like ?2 escape '\\' \
+    or album_artist like ?2 escape '\\' or album like ?2 escape '\\') \
+    order by artist collate nocase, album collate nocase, disc_number, track_number limit ?3";
+  let pattern = format!("%{}%", like_escape(keyword.trim()));
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = sql_query(sql)
+    .bind::<Text, _>(folder_like_pattern(user_root))
+    .bind::<Text, _>(pattern)
+    .bind::<BigInt, _>(limit as i64)
+    .load::<MusicTrack>(conn)?;
+  Ok(r)
+}
+
+/// Locate the cover thumbnail of the audio file `file` (relative to file root) in the
+/// thumbnail cache, keyed like image thumbnails.
+pub fn cover_entry(file_root: &Path, file: &str, dimension: u32) -> Result<CacheEntry, AppError> {
+  let dimension = dimension.clamp(MIN_DIMENSION, MAX_DIMENSION);
+  let source = file_root.join(file);
+  let meta = fs::metadata(&source)?;
+  let modified = meta.modified()?.duration_since(UNIX_EPOCH)?.as_millis();
+  let (ext, mime) = thumbnail::output_format();
+  let mut hasher = Sha256::new();
+  hasher.update(format!(
+    "cover-{COVER_CACHE_VERSION}\n{file}\n{modified}\n{}\n{dimension}\n{ext}",
+    meta.len()
+  ));
+  let key = hex::encode(hasher.finalize());
+  Ok(CacheEntry {
+    source,
+    cache_file: thumbnail::cache_path(&key, ext),
+    etag: format!("\"{key}\""),
+    mime,
+    dimension,
+  })
+}
+
+/// Read the cached cover thumbnail, it is created from the embedded front cover, or the
+/// first embedded picture, when missing.
+pub fn load_cover(entry: &CacheEntry) -> Result<Vec<u8>, AppError> {
+  if let Ok(data) = fs::read(&entry.cache_file) {
+    return Ok(data);
+  }
+  let not_found = || AppError::new("no cover art").with_status(StatusCode::NOT_FOUND);
+  let tagged = Probe::open(&entry.source)?.guess_file_type()?.read()?;
+  let pictures: Vec<_> = tagged.tags().iter().flat_map(|t| t.pictures()).collect();
+  let picture = pictures
+    .iter()
+    .find(|p| p.pic_type() == PictureType::CoverFront)
+    .or_else(|| pictures.first())
+    .ok_or_else(not_found)?;
+  let img = image::load_from_memory(picture.data())?;
+  let data = thumbnail::encode(&img.thumbnail(entry.dimension, entry.dimension), entry.mime)?;
+  thumbnail::store(&entry.cache_file, &data)?;
+  Ok(data)
+}
@@ -0,0 +1,514 @@
+use std::{
+  collections::HashMap,
+  fs::File,
+  io::Read,
+  path::Path,
+};
+
+use percent_encoding::percent_decode_str;
+use quick_xml::{events::Event, Reader};
+
+use crate::conv_err;
+
+use super::{archive::ZipReader, doc_parser::DocPage, error::AppError};
+
+/// max uncompressed size of a single xml entry, guards against zip bombs
+const MAX_ENTRY_SIZE: u64 = 1024 * 1024 * 64;
+
+conv_err!(quick_xml::Error);
+
+#[derive(Debug, Clone, Copy)]
+pub enum OfficeFormat {
+  Docx,
+  Xlsx,
+  Pptx,
+  Odt,
+  Ods,
+  Odp,
+  Epub,
+  Rtf,
+}
+
+impl OfficeFormat {
+  pub fn from_path(file: &str) -> Option<Self> {
+    let ext = Path::new(file).extension()?.to_string_lossy().to_lowercase();
+    let format = match ext.as_str() {
+      "docx" | "docm" => Self::Docx,
+      "xlsx" | "xlsm" => Self::Xlsx,
+      "pptx" | "pptm" => Self::Pptx,
+      "odt" => Self::Odt,
+      "ods" => Self::Ods,
+      "odp" => Self::Odp,
+      "epub" => Self::Epub,
+      "rtf" => Self::Rtf,
+      _ => return None,
+    };
+    Some(format)
+  }
+}
+
+pub fn read_office_pages(file: &str, format: OfficeFormat) -> Result<Vec<DocPage>, AppError> {
+  let pages = match format {
+    OfficeFormat::Docx => {
+      let mut zip = open_zip(file)?;
+      let xml = read_entry(&mut zip, "word/document.xml")?;
+      single_page(xml_to_text(&xml, Some(OOXML_TEXT_TAGS), None)?)
+    }
+    OfficeFormat::Pptx => {
+      let mut zip = open_zip(file)?;
+      let mut pages = vec![];
+      for (num, name) in numbered_entries(&zip, "ppt/slides/slide", ".xml") {
+        let xml = read_entry(&mut zip, &name)?;
+        pages.push(DocPage {
+          page: Some(num),
+          body: join_pages(xml_to_text(&xml, Some(OOXML_TEXT_TAGS), None)?),
+        });
+      }
+      pages
+    }
+    OfficeFormat::Xlsx => read_xlsx(file)?,
+    OfficeFormat::Odt => {
+      let mut zip = open_zip(file)?;
+      let xml = read_entry(&mut zip, "content.xml")?;
+      single_page(xml_to_text(&xml, None, None)?)
+    }
+    OfficeFormat::Ods => read_odf_paged(file, b"table")?,
+    OfficeFormat::Odp => read_odf_paged(file, b"page")?,
+    OfficeFormat::Epub => read_epub(file)?,
+    OfficeFormat::Rtf => {
+      let mut content = vec![];
+      File::open(file)?
+        .take(MAX_ENTRY_SIZE)
+        .read_to_end(&mut content)?;
+      single_page(vec![rtf_to_text(&content)])
+    }
+  };
+  Ok(pages.into_iter().filter(|p| !p.body.trim().is_empty()).collect())
+}
+
+fn single_page(parts: Vec<String>) -> Vec<DocPage> {
+  vec![DocPage {
+    page: None,
+    body: join_pages(parts),
+  }]
+}
+
+fn join_pages(parts: Vec<String>) -> String {
+  parts.join("\n")
+}
+
+fn open_zip(file: &str) -> Result<ZipReader, AppError> {
+  ZipReader::open(file)
+}
+
+fn read_entry(zip: &mut ZipReader, name: &str) -> Result<Vec<u8>, AppError> {
+  let index = zip
+    .index_of(name)
+    .ok_or(AppError::new(&format!("entry {name} not found")))?;
+  zip.read(index, MAX_ENTRY_SIZE)
+}
+
+/// entries like `ppt/slides/slide12.xml` sorted by their number
+fn numbered_entries(zip: &ZipReader, prefix: &str, suffix: &str) -> Vec<(u64, String)> {
+  let mut entries: Vec<(u64, String)> = zip
+    .entries()
+    .map(|e| e.filename())
+    .filter_map(|name| {
+      let num = name.strip_prefix(prefix)?.strip_suffix(suffix)?.parse().ok()?;
+      Some((num, name.to_owned()))
+    })
+    .collect();
+  entries.sort();
+  entries
+}
+
+/// text runs of word and powerpoint documents, other text nodes are field codes and metadata
+const OOXML_TEXT_TAGS: &[&[u8]] = &[b"t"];
+
+const BREAK_TAGS: [&[u8]; 12] = [
+  b"p", b"h", b"br", b"tab", b"line-break", b"div", b"li", b"tr", b"h1", b"h2", b"h3", b"row",
+];
+
+/// Collect text nodes of an xml document, matching is done on local names so namespace
+/// prefixes (`w:`, `a:`, `text:`) are ignored.
+///
+/// * `text_tags` only keep text inside these elements, keep all text when `None`
+/// * `page_tag` start a new part whenever this element closes, the result has one item otherwise
+fn xml_to_text(
+  xml: &[u8],
+  text_tags: Option<&[&[u8]]>,
+  page_tag: Option<&[u8]>,
+) -> Result<Vec<String>, AppError> {
+  let mut reader = Reader::from_reader(xml);
+  let mut buf = vec![];
+  let mut parts = vec![];
+  let mut text = String::new();
+  let mut inside_text_tag = 0;
+  let mut skip = 0;
+  loop {
+    match reader.read_event_into(&mut buf)? {
+      Event::Start(e) => {
+        let name = e.local_name();
+        let name = name.as_ref();
+        if text_tags.is_some_and(|tags| tags.contains(&name)) {
+          inside_text_tag += 1;
+        }
+        if name == b"script" || name == b"style" || name == b"head" {
+          skip += 1;
+        }
+      }
+      Event::End(e) => {
+        let name = e.local_name();
+        let name = name.as_ref();
+        if text_tags.is_some_and(|tags| tags.contains(&name)) {
+          inside_text_tag -= 1;
+        }
+        if name == b"script" || name == b"style" || name == b"head" {
+          skip -= 1;
+        }
+        if BREAK_TAGS.contains(&name) {
+          text.push('\n');
+        } else if name == b"c" || name == b"td" || name == b"table-cell" {
+          text.push(' ');
+        }
+        if page_tag == Some(name) {
+          parts.push(std::mem::take(&mut text));
+        }
+      }
+      Event::Empty(e) => {
+        let name = e.local_name();
+        if BREAK_TAGS.contains(&name.as_ref()) {
+          text.push('\n');
+        } else if name.as_ref() == b"s" {
+          text.push(' ');
+        }
+      }
+      Event::Text(t) if skip == 0 && (text_tags.is_none() || inside_text_tag > 0) => {
+        // html entities like &nbsp; are unknown to xml, keep the raw text for them
+        match t.unescape() {
+          Ok(s) => text.push_str(&s),
+          Err(_) => text.push_str(&String::from_utf8_lossy(&t)),
+        }
+      }
+      Event::CData(t) if skip == 0 && (text_tags.is_none() || inside_text_tag > 0) => {
+        text.push_str(&String::from_utf8_lossy(&t));
+      }
+      Event::Eof => break,
+      _ => (),
+    }
+    buf.clear();
+  }
+  if !text.trim().is_empty() || parts.is_empty() {
+    parts.push(text);
+  }
+  Ok(parts)
+}
+
+fn read_odf_paged(file: &str, page_tag: &[u8]) -> Result<Vec<DocPage>, AppError> {
+  let mut zip = open_zip(file)?;
+  let xml = read_entry(&mut zip, "content.xml")?;
+  let pages = xml_to_text(&xml, None, Some(page_tag))?
+    .into_iter()
+    .enumerate()
+    .map(|(idx, body)| DocPage {
+      page: Some(idx as u64 + 1),
+      body,
+    })
+    .collect();
+  Ok(pages)
+}
+
+fn read_xlsx(file: &str) -> Result<Vec<DocPage>, AppError> {
+  let mut zip = open_zip(file)?;
+  let shared_strings = match read_entry(&mut zip, "xl/sharedStrings.xml") {
+    Ok(xml) => read_shared_strings(&xml)?,
+    Err(_) => vec![],
+  };
+  let mut pages = vec![];
+  for (num, name) in numbered_entries(&zip, "xl/worksheets/sheet", ".xml") {
+    let xml = read_entry(&mut zip, &name)?;
+    pages.push(DocPage {
+      page: Some(num),
+      body: read_sheet(&xml, &shared_strings)?,
+    });
+  }
+  Ok(pages)
+}
+
+/// each `<si>` item is one shared string, which may be split into several `<t>` runs
+fn read_shared_strings(xml: &[u8]) -> Result<Vec<String>, AppError> {
+  let mut reader = Reader::from_reader(xml);
+  let mut buf = vec![];
+  let mut strings = vec![];
+  let mut current = String::new();
+  let mut in_t = false;
+  loop {
+    match reader.read_event_into(&mut buf)? {
+      Event::Start(e) if e.local_name().as_ref() == b"t" => in_t = true,
+      Event::End(e) if e.local_name().as_ref() == b"t" => in_t = false,
+      Event::End(e) if e.local_name().as_ref() == b"si" => {
+        strings.push(std::mem::take(&mut current));
+      }
+      Event::Text(t) if in_t => current.push_str(&t.unescape()?),
+      Event::Eof => break,
+      _ => (),
+    }
+    buf.clear();
+  }
+  Ok(strings)
+}
+
+fn read_sheet(xml: &[u8], shared_strings: &[String]) -> Result<String, AppError> {
+  let mut reader = Reader::from_reader(xml);
+  let mut buf = vec![];
+  let mut text = String::new();
+  let mut cell_type: Option<String> = None;
+  let mut in_value = false;
+  loop {
+    match reader.read_event_into(&mut buf)? {
+      Event::Start(e) => match e.local_name().as_ref() {
+        b"c" => {
+          cell_type = e
+            .try_get_attribute("t")
+            .ok()
+            .flatten()
+            .map(|a| String::from_utf8_lossy(&a.value).to_string());
+        }
+        b"v" | b"t" => in_value = true,
+        _ => (),
+      },
+      Event::End(e) => match e.local_name().as_ref() {
+        b"v" | b"t" => in_value = false,
+        b"c" => text.push(' '),
+        b"row" => text.push('\n'),
+        _ => (),
+      },
+      Event::Text(t) if in_value => {
+        let value = t.unescape()?;
+        if cell_type.as_deref() == Some("s") {
+          let s = value
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|idx| shared_strings.get(idx));
+          if let Some(s) = s {
+            text.push_str(s);
+          }
+        } else {
+          text.push_str(&value);
+        }
+      }
+      Event::Eof => break,
+      _ => (),
+    }
+    buf.clear();
+  }
+  Ok(text)
+}
+
+/// Read chapters in spine order, each chapter becomes a page.
+fn read_epub(file: &str) -> Result<Vec<DocPage>, AppError> {
+  let mut zip = open_zip(file)?;
+  let container = read_entry(&mut zip, "META-INF/container.xml")?;
+  let opf_path = find_attribute(&container, b"rootfile", b"full-path")?
+    .into_iter()
+    .next()
+    .ok_or(AppError::new("invalid epub: no rootfile"))?;
+  let opf = read_entry(&mut zip, &opf_path)?;
+  let opf_dir = Path::new(&opf_path).parent().map_or("".to_owned(), |p| {
+    p.to_string_lossy().to_string()
+  });
+
+  let mut manifest = HashMap::new();
+  let mut spine = vec![];
+  let mut reader = Reader::from_reader(opf.as_slice());
+  let mut buf = vec![];
+  loop {
+    match reader.read_event_into(&mut buf)? {
+      Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+        b"item" => {
+          let id = e.try_get_attribute("id").ok().flatten();
+          let href = e.try_get_attribute("href").ok().flatten();
+          if let (Some(id), Some(href)) = (id, href) {
+            manifest.insert(
+              String::from_utf8_lossy(&id.value).to_string(),
+              href.unescape_value()?.to_string(),
+            );
+          }
+        }
+        b"itemref" => {
+          if let Some(idref) = e.try_get_attribute("idref").ok().flatten() {
+            spine.push(String::from_utf8_lossy(&idref.value).to_string());
+          }
+        }
+        _ => (),
+      },
+      Event::Eof => break,
+      _ => (),
+    }
+    buf.clear();
+  }
+
+  let mut pages = vec![];
+  for (idx, idref) in spine.iter().enumerate() {
+    let href = match manifest.get(idref) {
+      Some(href) => href,
+      None => continue,
+    };
+    let entry = resolve_href(&opf_dir, href);
+    let xhtml = match read_entry(&mut zip, &entry) {
+      Ok(xhtml) => xhtml,
+      Err(_) => continue,
+    };
+    pages.push(DocPage {
+      page: Some(idx as u64 + 1),
+      body: join_pages(xml_to_text(&xhtml, None, None)?),
+    });
+  }
+  Ok(pages)
+}
+
+/// Path of the zip entry an href of the package document points to. Hrefs are URLs relative
+/// to the directory of the package document, they may be percent-encoded and contain `..`.
+fn resolve_href(base_dir: &str, href: &str) -> String {
+  let href = href.split('#').next().unwrap_or("");
+  let href = percent_decode_str(href).decode_utf8_lossy();
+  let mut parts: Vec<&str> = vec![];
+  for part in base_dir.split('/').chain(href.split('/')) {
+    match part {
+      "" | "." => (),
+      ".." => {
+        parts.pop();
+      }
+      _ => parts.push(part),
+    }
+  }
+  parts.join("/")
+}
+
+fn find_attribute(xml: &[u8], tag: &[u8], attr: &[u8]) -> Result<Vec<String>, AppError> {
+  let mut reader = Reader::from_reader(xml);
+  let mut buf = vec![];
+  let mut values = vec![];
+  loop {
+    match reader.read_event_into(&mut buf)? {
+      Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == tag => {
+        for a in e.attributes().flatten() {
+          if a.key.local_name().as_ref() == attr {
+            values.push(a.unescape_value()?.to_string());
+          }
+        }
+      }
+      Event::Eof => break,
+      _ => (),
+    }
+    buf.clear();
+  }
+  Ok(values)
+}
+
+/// Strip rtf control words and groups, keeping the document text.
+/// Hex escapes are decoded as latin-1, which is good enough for indexing.
+pub fn rtf_to_text(content: &[u8]) -> String {
+  const SKIP_DESTINATIONS: [&str; 9] = [
+    "fonttbl", "colortbl", "stylesheet", "info", "pict", "header", "footer", "themedata",
+    "listtable",
+  ];
+  let mut text = String::new();
+  // for each open group, whether its content is skipped
+  let mut groups: Vec<bool> = vec![];
+  let mut skipping = false;
+  // number of fallback characters to skip after a \u escape
+  let mut skip_chars: usize = 0;
+  let mut i = 0;
+  while i < content.len() {
+    let c = content[i];
+    match c {
+      b'{' => {
+        groups.push(skipping);
+        i += 1;
+      }
+      b'}' => {
+        skipping = groups.pop().unwrap_or(false);
+        i += 1;
+      }
+      b'\\' => {
+        i += 1;
+        if i >= content.len() {
+          break;
+        }
+        let next = content[i];
+        if next == b'*' {
+          skipping = true;
+          i += 1;
+        } else if next == b'\'' {
+          let hex = content.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+          if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            if !skipping && skip_chars == 0 {
+              text.push(byte as char);
+            }
+            skip_chars = skip_chars.saturating_sub(1);
+          }
+          i += 3;
+        } else if next.is_ascii_alphabetic() {
+          let start = i;
+          while i < content.len() && content[i].is_ascii_alphabetic() {
+            i += 1;
+          }
+          let word = String::from_utf8_lossy(&content[start..i]).to_string();
+          let num_start = i;
+          if i < content.len() && content[i] == b'-' {
+            i += 1;
+          }
+          while i < content.len() && content[i].is_ascii_digit() {
+            i += 1;
+          }
+          let param: Option<i32> = std::str::from_utf8(&content[num_start..i])
+            .ok()
+            .and_then(|n| n.parse().ok());
+          // a single space delimits the control word and is not part of the text
+          if i < content.len() && content[i] == b' ' {
+            i += 1;
+          }
+          if SKIP_DESTINATIONS.contains(&word.as_str()) {
+            skipping = true;
+          } else if skipping {
+            continue;
+          }
+          match word.as_str() {
+            "par" | "line" | "row" | "sect" | "page" => text.push('\n'),
+            "tab" | "cell" => text.push(' '),
+            "u" => {
+              if let Some(code) = param {
+                let code = if code < 0 { code + 65536 } else { code } as u32;
+                if let Some(ch) = char::from_u32(code) {
+                  text.push(ch);
+                }
+                skip_chars = 1;
+              }
+            }
+            _ => (),
+          }
+        } else {
+          // escaped literal like \{ \} \\
+          if !skipping && (next == b'{' || next == b'}' || next == b'\\') {
+            text.push(next as char);
+          }
+          i += 1;
+        }
+      }
+      b'\r' | b'\n' => i += 1,
+      _ => {
+        if !skipping {
+          if skip_chars > 0 {
+            skip_chars -= 1;
+          } else {
+            text.push(c as char);
+          }
+        }
+        i += 1;
+      }
+    }
+  }
+  text
+}
@@ -3,6 +3,22 @@ use actix_web::http::StatusCode;
 use super::error::AppError;
 use std::path::PathBuf;
 
+/// escape `\`, `%` and `_` for a LIKE pattern with `\` as escape character
+pub fn like_escape(s: &str) -> String {
+  s.replace('\\', "\\\\")
+    .replace('%', "\\%")
+    .replace('_', "\\_")
+}
+
+/// LIKE pattern matching the paths under `folder`, with `\` as escape character.
+/// An empty folder, e.g. the root of the admin, matches every path.
+pub fn folder_like_pattern(folder: &str) -> String {
+  if folder.is_empty() {
+    return "%".to_owned();
+  }
+  like_escape(folder) + "/%"
+}
+
 pub fn secure_join(root: &PathBuf, unsafe_path: &PathBuf) -> Result<PathBuf, AppError> {
   if unsafe_path.has_root() {
     return Err(
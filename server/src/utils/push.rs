@@ -0,0 +1,134 @@
+use std::path::Path;
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use futures::Stream;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+
+use crate::schedulers::update_file_index::JobStatus;
+
+const PUSH_CHANNEL_CAPACITY: usize = 256;
+const KEEP_ALIVE_SECS: u64 = 15;
+
+lazy_static! {
+  pub static ref PUSH_CHANNEL: Sender<PushEvent> = {
+    let (tx, _) = broadcast::channel(PUSH_CHANNEL_CAPACITY);
+    tx
+  };
+}
+
+/// Message delivered to the browser, serialized as the `data` of a server-sent event.
+/// File paths are relative to the receiving user's root.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PushMessage {
+  FileAdded { files: Vec<String> },
+  FileModified { files: Vec<String> },
+  FileDeleted { files: Vec<String> },
+  UploadFinished { files: Vec<String> },
+  JobStatus { job: String, status: JobStatus },
+  /// the subscriber fell behind and missed events, client should reload its views
+  Resync,
+}
+
+impl PushMessage {
+  fn event_name(&self) -> &'static str {
+    match self {
+      Self::FileAdded { .. } => "file_added",
+      Self::FileModified { .. } => "file_modified",
+      Self::FileDeleted { .. } => "file_deleted",
+      Self::UploadFinished { .. } => "upload_finished",
+      Self::JobStatus { .. } => "job_status",
+      Self::Resync => "resync",
+    }
+  }
+
+  fn to_sse(&self) -> Bytes {
+    let data = serde_json::to_string(self).unwrap_or_else(|_| "{}".to_owned());
+    Bytes::from(format!("event: {}\ndata: {}\n\n", self.event_name(), data))
+  }
+}
+
+/// Event published on the push channel. Paths in `message` are relative to file root
+/// (they include the user root), they are rewritten per subscriber in [`PushEvent::for_user`].
+#[derive(Debug, Clone)]
+pub struct PushEvent {
+  message: PushMessage,
+}
+
+impl PushEvent {
+  pub fn new(message: PushMessage) -> Self {
+    Self { message }
+  }
+
+  /// returns the message as seen by a user, or None if nothing in it belongs to the user
+  pub fn for_user(&self, user_root: &str) -> Option<PushMessage> {
+    let strip = |files: &Vec<String>| -> Option<Vec<String>> {
+      let files: Vec<String> = files
+        .iter()
+        .filter_map(|f| {
+          Path::new(f)
+            .strip_prefix(user_root)
+            .ok()
+            .map(|p| p.to_string_lossy().to_string())
+        })
+        .collect();
+      if files.is_empty() {
+        None
+      } else {
+        Some(files)
+      }
+    };
+    match &self.message {
+      PushMessage::FileAdded { files } => strip(files).map(|files| PushMessage::FileAdded { files }),
+      PushMessage::FileModified { files } => {
+        strip(files).map(|files| PushMessage::FileModified { files })
+      }
+      PushMessage::FileDeleted { files } => {
+        strip(files).map(|files| PushMessage::FileDeleted { files })
+      }
+      PushMessage::UploadFinished { files } => {
+        strip(files).map(|files| PushMessage::UploadFinished { files })
+      }
+      m @ PushMessage::JobStatus { .. } => Some(m.clone()),
+      PushMessage::Resync => Some(PushMessage::Resync),
+    }
+  }
+}
+
+pub fn publish(message: PushMessage) {
+  // send only fails when there is no subscriber, which is fine
+  let _ = PUSH_CHANNEL.send(PushEvent::new(message));
+}
+
+/// Creates a `text/event-stream` body for one user. Comment lines are sent periodically
+/// to keep proxies from closing idle connections.
+pub fn subscribe(user_root: String) -> impl Stream<Item = Result<Bytes, std::io::Error>> {
+  let rx = PUSH_CHANNEL.subscribe();
+  futures::stream::unfold(
+    (rx, user_root),
+    |(mut rx, user_root): (Receiver<PushEvent>, String)| async move {
+      loop {
+        let keep_alive = tokio::time::sleep(Duration::from_secs(KEEP_ALIVE_SECS));
+        tokio::select! {
+          ev = rx.recv() => match ev {
+            Ok(ev) => {
+              if let Some(msg) = ev.for_user(&user_root) {
+                return Some((Ok(msg.to_sse()), (rx, user_root)));
+              }
+            }
+            Err(RecvError::Lagged(_)) => {
+              return Some((Ok(PushMessage::Resync.to_sse()), (rx, user_root)));
+            }
+            Err(RecvError::Closed) => return None,
+          },
+          _ = keep_alive => {
+            return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), (rx, user_root)));
+          }
+        }
+      }
+    },
+  )
+}
@@ -72,6 +72,27 @@ pub fn create_binary_resp(data: Vec<u8>, mime_type: Option<String>) -> HttpRespo
   resp.body(data)
 }
 
+/// Binary response which the browser may cache and revalidate with `If-None-Match`.
+pub fn create_cacheable_binary_resp(
+  data: Vec<u8>,
+  mime_type: Option<String>,
+  etag: &str,
+  max_age: u32,
+) -> HttpResponse {
+  let mut resp = HttpResponse::Ok();
+  resp.append_header(("ETag", etag));
+  resp.append_header(("Cache-Control", format!("private, max-age={max_age}")));
+  resp.content_type(mime_type.unwrap_or_default());
+  resp.body(data)
+}
+
+pub fn create_not_modified_resp(etag: &str, max_age: u32) -> HttpResponse {
+  HttpResponse::NotModified()
+    .append_header(("ETag", etag))
+    .append_header(("Cache-Control", format!("private, max-age={max_age}")))
+    .finish()
+}
+
 pub fn create_stream_resp(
   stream: RangeStream<ReaderStream<File>>,
   mime_type: Option<String>,
@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::StatusCode;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  db::SHARED_DB_CONN,
+  models::{FileIndex, NewSavedSearch, SavedSearch},
+};
+
+use super::error::AppError;
+use super::filename_index;
+use super::path::folder_like_pattern;
+use super::search_engine::{search_docs, SearchOptions};
+use super::vfs::{rel_join, FileStatWithName};
+
+/// Saved searches show up as read-only folders under this virtual directory of the user root.
+/// A real directory with the same name is hidden.
+pub const SMART_FOLDER_ROOT: &str = "@saved";
+/// max number of files listed in a smart folder
+const MAX_SMART_FOLDER_ITEMS: usize = 1000;
+const DAY_MILLIS: u64 = 1000 * 3600 * 24;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedSearchQuery {
+  pub filename_keyword: Option<String>,
+  pub content_keyword: Option<String>,
+  pub mimes: Vec<String>,
+  pub min_size: Option<u64>,
+  pub max_size: Option<u64>,
+  /// modified time range in millis
+  pub modified_after: Option<u64>,
+  pub modified_before: Option<u64>,
+  /// only files modified in the last n days, evaluated when the search runs
+  pub modified_within_days: Option<u64>,
+  /// directory relative to user root
+  pub folder: Option<String>,
+}
+
+fn now_millis() -> Result<u64, AppError> {
+  Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
+}
+
+pub fn list_saved_searches(username_: &str) -> Result<Vec<SavedSearch>, AppError> {
+  use crate::schema::saved_searches::dsl::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = saved_searches
+    .filter(username.eq(username_))
+    .order(name)
+    .load::<SavedSearch>(conn)?;
+  Ok(r)
+}
+
+pub fn get_saved_search(username_: &str, name_: &str) -> Result<SavedSearch, AppError> {
+  use crate::schema::saved_searches::dsl::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = saved_searches
+    .filter(username.eq(username_).and(name.eq(name_)))
+    .first::<SavedSearch>(conn)
+    .optional()?;
+  r.ok_or(AppError::new("saved search not found").with_status(StatusCode::NOT_FOUND))
+}
+
+/// Create a saved search or replace the query of an existing one with the same name.
+pub fn save_search(
+  username_: &str,
+  name_: &str,
+  query_: &SavedSearchQuery,
+) -> Result<SavedSearch, AppError> {
+  use crate::schema::saved_searches::dsl::*;
+  use crate::schema::saved_searches::table;
+  if name_.is_empty() || name_.contains('/') || name_.starts_with('.') {
+    return Err(AppError::new("invalid saved search name").with_status(StatusCode::BAD_REQUEST));
+  }
+  let query_json = serde_json::to_string(query_).map_err(|e| AppError::new(&e.to_string()))?;
+  let now = now_millis()?.to_string();
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let updated = diesel::update(saved_searches.filter(username.eq(username_).and(name.eq(name_))))
+    .set(query.eq(&query_json))
+    .get_result::<SavedSearch>(conn)
+    .optional()?;
+  if let Some(r) = updated {
+    return Ok(r);
+  }
+  let r = diesel::insert_into(table)
+    .values(NewSavedSearch {
+      username: username_,
+      name: name_,
+      query: &query_json,
+      created_at: &now,
+    })
+    .get_result::<SavedSearch>(conn)?;
+  Ok(r)
+}
+
+pub fn delete_saved_search(username_: &str, id_: i32) -> Result<(), AppError> {
+  use crate::schema::saved_searches::dsl::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let effect =
+    diesel::delete(saved_searches.filter(username.eq(username_).and(id.eq(id_)))).execute(conn)?;
+  if effect == 0 {
+    return Err(AppError::new("saved search not found").with_status(StatusCode::NOT_FOUND));
+  }
+  Ok(())
+}
+
+pub fn parse_query(saved: &SavedSearch) -> Result<SavedSearchQuery, AppError> {
+  serde_json::from_str(&saved.query).map_err(|e| AppError::new(&e.to_string()))
+}
+
+fn in_folder(path: &str, folder: &str) -> bool {
+  folder.is_empty() || Path::new(path).starts_with(folder)
+}
+
+/// Run a saved search, returns the latest file index rows of the matched files.
+/// Keywords narrow the candidates through the search indexes, the other filters
+/// are checked against the file index.
+pub fn run_saved_search(user_root: &str, q: &SavedSearchQuery) -> Result<Vec<FileIndex>, AppError> {
+  let folder = rel_join(user_root, q.folder.as_deref().unwrap_or(""))?;
+  let modified_after = match q.modified_within_days {
+    Some(days) => Some(
+      now_millis()?
+        .saturating_sub(days * DAY_MILLIS)
+        .max(q.modified_after.unwrap_or(0)),
+    ),
+    None => q.modified_after,
+  };
+
+  let mut candidates: Option<Vec<String>> = None;
+  if let Some(kw) = q.content_keyword.as_ref().filter(|k| !k.trim().is_empty()) {
+    let options = SearchOptions {
+      offset: 0,
+      limit: MAX_SMART_FOLDER_ITEMS,
+      mimes: q.mimes.clone(),
+      min_size: q.min_size,
+      max_size: q.max_size,
+      modified_after,
+      modified_before: q.modified_before,
+      folder: Some(folder.clone()),
+    };
+    let result = search_docs(kw, &options)?;
+    let mut seen = HashSet::new();
+    candidates = Some(
+      result
+        .hits
+        .into_iter()
+        .map(|h| h.path)
+        .filter(|p| seen.insert(p.clone()))
+        .collect(),
+    );
+  }
+  if let Some(kw) = q.filename_keyword.as_ref().filter(|k| !k.trim().is_empty()) {
+    let hits = filename_index::search(kw, Some(&folder), MAX_SMART_FOLDER_ITEMS)?;
+    let names: Vec<String> = hits
+      .into_iter()
+      .map(|h| h.file_path)
+      .filter(|p| in_folder(p, &folder))
+      .collect();
+    candidates = Some(match candidates {
+      Some(paths) => {
+        let names: HashSet<String> = names.into_iter().collect();
+        paths.into_iter().filter(|p| names.contains(p)).collect()
+      }
+      None => names,
+    });
+  }
+
+  let rows = {
+    use crate::schema::file_index::dsl::*;
+    let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+    match &candidates {
+      Some(paths) => {
+        let mut rows = vec![];
+        for chunk in paths.chunks(100) {
+          rows.extend(file_index.filter(file_path.eq_any(chunk)).load::<FileIndex>(conn)?);
+        }
+        rows
+      }
+      None => {
+        file_index
+          .filter(file_path.like(folder_like_pattern(&folder)).escape('\\'))
+          .load::<FileIndex>(conn)?
+      }
+    }
+  };
+
+  // the file index keeps rows of the previous run until it is cleaned up
+  let mut latest: HashMap<String, FileIndex> = HashMap::new();
+  for row in rows {
+    match latest.get(&row.file_path) {
+      Some(r) if r.updated_at.parse::<u64>().unwrap_or(0) >= row.updated_at.parse().unwrap_or(0) => {}
+      _ => {
+        latest.insert(row.file_path.clone(), row);
+      }
+    }
+  }
+
+  let matches = |row: &FileIndex| {
+    let size = row.size as u64;
+    let modified = row.modified_at.parse::<u64>().unwrap_or(0);
+    let format = row.format.as_deref().unwrap_or("");
+    in_folder(&row.file_path, &folder)
+      && (q.mimes.is_empty() || q.mimes.iter().any(|m| format.contains(m.as_str())))
+      && q.min_size.is_none_or(|min| size >= min)
+      && q.max_size.is_none_or(|max| size <= max)
+      && modified_after.is_none_or(|after| modified >= after)
+      && q.modified_before.is_none_or(|before| modified <= before)
+  };
+  let order: HashMap<String, usize> = candidates
+    .unwrap_or_default()
+    .into_iter()
+    .enumerate()
+    .map(|(i, p)| (p, i))
+    .collect();
+  let mut result: Vec<FileIndex> = latest.into_values().filter(matches).collect();
+  // keep the relevance order of keyword searches, newest first otherwise
+  if order.is_empty() {
+    result.sort_by_key(|r| std::cmp::Reverse(r.modified_at.parse::<u64>().unwrap_or(0)));
+  } else {
+    result.sort_by_key(|r| order.get(&r.file_path).copied().unwrap_or(usize::MAX));
+  }
+  result.truncate(MAX_SMART_FOLDER_ITEMS);
+  Ok(result)
+}
+
+/// Map a path inside a smart folder, `@saved/<search>/<path>`, to `<path>` relative to the
+/// user root. Files of a smart folder are named by their path so they can be opened as usual.
+pub fn resolve_virtual_path(file: &str) -> Option<String> {
+  let mut components = Path::new(file)
+    .components()
+    .filter(|c| !matches!(c, Component::CurDir | Component::RootDir));
+  match components.next() {
+    Some(Component::Normal(root)) if root == SMART_FOLDER_ROOT => {}
+    _ => return None,
+  }
+  components.next()?;
+  let rest: PathBuf = components.collect();
+  if rest.as_os_str().is_empty() {
+    return None;
+  }
+  Some(rest.to_string_lossy().to_string())
+}
+
+/// true for `@saved` and everything under it
+pub fn is_virtual_path(file: &str) -> bool {
+  Path::new(file)
+    .components()
+    .find(|c| !matches!(c, Component::CurDir | Component::RootDir))
+    .is_some_and(|c| c.as_os_str() == SMART_FOLDER_ROOT)
+}
+
+/// Fail for paths under `@saved`. Smart folders are read-only, and a real directory with
+/// the name could not be reached, so none is created.
+pub fn ensure_writable(file: &str) -> Result<(), AppError> {
+  if is_virtual_path(file) {
+    return Err(
+      AppError::new(&format!("{SMART_FOLDER_ROOT} is reserved for smart folders"))
+        .with_status(StatusCode::BAD_REQUEST),
+    );
+  }
+  Ok(())
+}
+
+fn virtual_dir(name: &str, created: u128) -> FileStatWithName {
+  FileStatWithName {
+    name: name.to_owned(),
+    is_dir: true,
+    is_file: false,
+    file_type: "".to_owned(),
+    size: 0,
+    created,
+    modified: created,
+    accessed: created,
+  }
+}
+
+/// Listing of `@saved` (the saved searches) and `@saved/<search>` (the matched files),
+/// `None` for other directories.
+pub fn read_smart_folder(
+  username: &str,
+  user_root: &str,
+  dir: &str,
+) -> Result<Option<Vec<FileStatWithName>>, AppError> {
+  if !is_virtual_path(dir) || resolve_virtual_path(dir).is_some() {
+    return Ok(None);
+  }
+  let components: Vec<_> = Path::new(dir)
+    .components()
+    .filter(|c| !matches!(c, Component::CurDir | Component::RootDir))
+    .collect();
+  if components.len() == 1 {
+    let searches = list_saved_searches(username)?;
+    let dirs = searches
+      .iter()
+      .map(|s| virtual_dir(&s.name, s.created_at.parse().unwrap_or(0)))
+      .collect();
+    return Ok(Some(dirs));
+  }
+  let name = components[1].as_os_str().to_string_lossy();
+  let saved = get_saved_search(username, &name)?;
+  let q = parse_query(&saved)?;
+  let files = run_saved_search(user_root, &q)?
+    .into_iter()
+    .map(|r| {
+      let rel = Path::new(&r.file_path)
+        .strip_prefix(user_root)
+        .map_or(r.file_path.clone(), |p| p.to_string_lossy().to_string());
+      let created = r.created_at.parse().unwrap_or(0);
+      FileStatWithName {
+        name: rel,
+        is_dir: r.is_dir,
+        is_file: !r.is_dir,
+        file_type: "".to_owned(),
+        size: r.size as u64,
+        created,
+        modified: r.modified_at.parse().unwrap_or(created),
+        accessed: r.modified_at.parse().unwrap_or(created),
+      }
+    })
+    .collect();
+  Ok(Some(files))
+}
+
+/// Entry for the `@saved` directory in the listing of the user root.
+pub fn smart_folder_root_entry(username: &str) -> Result<Option<FileStatWithName>, AppError> {
+  let searches = list_saved_searches(username)?;
+  Ok(searches.first().map(|_| virtual_dir(SMART_FOLDER_ROOT, 0)))
+}
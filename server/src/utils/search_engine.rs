@@ -1,203 +1,812 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 use lazy_static::lazy_static;
+use serde::Serialize;
+use tracing::{error, info, warn};
 use tantivy;
-use tantivy::collector::TopDocs;
+use tantivy::collector::{Count, FacetCollector, TopDocs};
 use tantivy::directory::MmapDirectory;
 use tantivy::doc;
-use tantivy::query::QueryParser;
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery};
 use tantivy::query::QueryParserError;
 use tantivy::schema::*;
 use tantivy::Directory;
 use tantivy::Index;
+use tantivy::IndexReader;
+use tantivy::IndexWriter;
 use tantivy::ReloadPolicy;
+use tantivy::SnippetGenerator;
 
 use crate::config;
 use crate::conv_err;
 
+use super::archive::ArchiveLink;
 use super::error::AppError;
+use super::tokenizer::{configured_tokenizer, register_tokenizers};
+
+/// An index with a reader shared by all searches, the reader is reloaded by the indexer
+/// after each commit.
+pub struct SearchIndex {
+  pub index: Index,
+  reader: IndexReader,
+}
+
+impl SearchIndex {
+  fn new(index: Index) -> Result<Self, AppError> {
+    let reader = index
+      .reader_builder()
+      .reload_policy(ReloadPolicy::Manual)
+      .try_into()?;
+    Ok(Self { index, reader })
+  }
+}
 
 lazy_static! {
-  pub static ref SEARCH_INDEX: Arc<Mutex<Index>> = {
-    let index = init();
-    Arc::new(Mutex::new(index))
+  /// Replaced as a whole when a rebuild finishes, searches clone the `Arc`
+  /// and never wait for indexing.
+  static ref SEARCH_INDEX: RwLock<Arc<SearchIndex>> = {
+    let index = SearchIndex::new(init()).unwrap();
+    RwLock::new(Arc::new(index))
+  };
+  /// All writes go through this channel to the indexer thread which owns the only `IndexWriter`.
+  static ref INDEXER: Mutex<Sender<IndexOp>> = {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+      .name("search-indexer".to_owned())
+      .spawn(move || run_indexer(receiver))
+      .unwrap();
+    Mutex::new(sender)
   };
 }
 
-fn init() -> Index {
+static REBUILDING: AtomicBool = AtomicBool::new(false);
+/// set when the active index was lost or removed and replaced by an empty one
+static RECREATED: AtomicBool = AtomicBool::new(false);
+
+const WRITER_HEAP_SIZE: usize = 50_000_000;
+/// commit once this many documents are pending
+const COMMIT_BATCH_SIZE: usize = 1000;
+/// commit pending documents when no write arrives for this long
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn current() -> Arc<SearchIndex> {
+  SEARCH_INDEX.read().unwrap().clone()
+}
+
+/// Bump when the fields built by `build_schema` change,
+/// indexes with another version are rebuilt in the background.
+pub const SCHEMA_VERSION: u64 = 2;
+/// file in the index root holding the directory name of the active index
+const CURRENT_FILE: &str = "CURRENT";
+const VERSION_FILE: &str = "schema_version";
+
+fn text_options(tokenizer: &str) -> TextOptions {
+  let indexing = TextFieldIndexing::default()
+    .set_tokenizer(tokenizer)
+    .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+  TextOptions::default()
+    .set_indexing_options(indexing)
+    .set_stored()
+}
+
+fn build_schema() -> Schema {
   let mut schema_builder = Schema::builder();
 
   let path_options = TextOptions::default().set_stored().set_indexing_options(
     TextFieldIndexing::default().set_index_option(IndexRecordOption::WithFreqsAndPositions),
   );
 
-  let body_field_indexing = TextFieldIndexing::default()
-    .set_tokenizer("jieba")
-    .set_index_option(IndexRecordOption::WithFreqsAndPositions);
-  let body_options = TextOptions::default()
-    .set_indexing_options(body_field_indexing)
-    .set_stored();
-
-  schema_builder.add_text_field("name", TEXT | STORED);
+  schema_builder.add_text_field("name", text_options(&configured_tokenizer("name")));
   schema_builder.add_text_field("path", TEXT | STORED);
-  schema_builder.add_text_field("body", body_options);
+  // untokenized path, documents of a file are deleted by it
+  schema_builder.add_text_field("path_raw", STRING);
+  schema_builder.add_text_field("body", text_options(&configured_tokenizer("body")));
   schema_builder.add_text_field("updated_at", path_options);
-  let schema = schema_builder.build();
-  let index_path = config!(search_index_path);
-  std::fs::create_dir_all(&index_path).unwrap();
+  schema_builder.add_u64_field("page", INDEXED | STORED);
+  schema_builder.add_u64_field("size", FAST | INDEXED | STORED);
+  schema_builder.add_u64_field("modified", FAST | INDEXED | STORED);
+  schema_builder.add_facet_field("mime", FacetOptions::default().set_stored());
+  schema_builder.add_facet_field("dir", FacetOptions::default());
+  schema_builder.build()
+}
+
+fn index_root() -> PathBuf {
+  PathBuf::from(config!(search_index_path))
+}
+
+/// Directory of the active index. Indexes created before versioning live directly
+/// in the index root. None when there is no index or `CURRENT` points to a missing one.
+fn active_dir(root: &Path) -> Option<PathBuf> {
+  if let Ok(name) = std::fs::read_to_string(root.join(CURRENT_FILE)) {
+    let dir = root.join(name.trim());
+    let exists = MmapDirectory::open(&dir)
+      .ok()
+      .is_some_and(|d| Index::exists(&d).unwrap_or(false));
+    if !exists {
+      warn!("search index {dir:?} named in {CURRENT_FILE} is missing");
+      return None;
+    }
+    return Some(dir);
+  }
+  let directory = MmapDirectory::open(root).ok()?;
+  match Index::exists(&directory) {
+    Ok(true) => Some(root.to_path_buf()),
+    _ => None,
+  }
+}
+
+/// Point the index root to `dir`, the rename makes the switch atomic.
+fn set_active_dir(root: &Path, dir: &Path) -> Result<(), AppError> {
+  let name = dir.file_name().unwrap().to_string_lossy().to_string();
+  let tmp = root.join(format!("{CURRENT_FILE}.tmp"));
+  std::fs::write(&tmp, name)?;
+  std::fs::rename(&tmp, root.join(CURRENT_FILE))?;
+  Ok(())
+}
+
+fn new_generation_dir(root: &Path) -> Result<PathBuf, AppError> {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+  Ok(root.join(format!("gen-{now}")))
+}
+
+fn schema_version(dir: &Path) -> u64 {
+  std::fs::read_to_string(dir.join(VERSION_FILE))
+    .ok()
+    .and_then(|v| v.trim().parse().ok())
+    .unwrap_or(0)
+}
+
+fn open_index(dir: &Path) -> Result<Index, AppError> {
+  let mmap_directory: Box<dyn Directory> = Box::new(MmapDirectory::open(dir)?);
+  let index = Index::open(mmap_directory)?;
+  register_tokenizers(&index);
+  Ok(index)
+}
+
+fn create_index(dir: &Path) -> Result<Index, AppError> {
+  std::fs::create_dir_all(dir)?;
+  let index = Index::create_in_dir(dir, build_schema())?;
+  std::fs::write(dir.join(VERSION_FILE), SCHEMA_VERSION.to_string())?;
+  register_tokenizers(&index);
+  Ok(index)
+}
+
+fn init() -> Index {
+  let root = index_root();
+  std::fs::create_dir_all(&root).unwrap();
+  if let Some(dir) = active_dir(&root) {
+    return open_index(&dir).unwrap();
+  }
+  if root.join(CURRENT_FILE).exists() {
+    RECREATED.store(true, Ordering::SeqCst);
+  }
+  let dir = new_generation_dir(&root).unwrap();
+  let index = create_index(&dir).unwrap();
+  set_active_dir(&root, &dir).unwrap();
+  index
+}
+
+/// Every field of the current schema must exist with the same options,
+/// this also catches tokenizers changed in the configuration.
+fn is_compatible(schema: &Schema) -> bool {
+  build_schema().fields().all(|(_, entry)| {
+    schema
+      .get_field(entry.name())
+      .map(|f| schema.get_field_entry(f))
+      == Some(entry)
+  })
+}
+
+/// Whether the index is filled from scratch, by a rebuild or after it was lost or removed,
+/// so documents of unchanged files have to be added again.
+pub fn is_rebuilding() -> bool {
+  REBUILDING.load(Ordering::SeqCst) || RECREATED.load(Ordering::SeqCst)
+}
+
+/// true when the active index was built with another schema, or was missing at startup,
+/// and should be rebuilt
+pub fn needs_rebuild() -> bool {
+  let index = current();
+  let version = active_dir(&index_root()).map_or(0, |dir| schema_version(&dir));
+  RECREATED.load(Ordering::SeqCst) || version != SCHEMA_VERSION || !is_compatible(&index.index.schema())
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexStatus {
+  pub schema_version: u64,
+  pub expected_schema_version: u64,
+  pub compatible: bool,
+  pub rebuilding: bool,
+  pub num_docs: u64,
+  pub num_segments: usize,
+}
 
-  let mmap_directory: Box<dyn Directory> = Box::new(MmapDirectory::open(&index_path).unwrap());
-  let index;
-  if Index::exists(&*mmap_directory).unwrap() {
-    index = Index::open(mmap_directory).unwrap();
-  } else {
-    index = Index::open_or_create(mmap_directory, schema.clone()).unwrap();
+pub fn index_status() -> Result<IndexStatus, AppError> {
+  let index = current();
+  let schema_version = active_dir(&index_root()).map_or(0, |dir| schema_version(&dir));
+  Ok(IndexStatus {
+    schema_version,
+    expected_schema_version: SCHEMA_VERSION,
+    compatible: schema_version == SCHEMA_VERSION && is_compatible(&index.index.schema()),
+    rebuilding: REBUILDING.load(Ordering::SeqCst),
+    num_docs: index.reader.searcher().num_docs(),
+    num_segments: index.index.searchable_segment_ids()?.len(),
+  })
+}
+
+/// Create an empty index in a new directory. Until [`finish_rebuild`] queries keep using the
+/// active index while writes go to both, so changes made during the rebuild are not lost.
+pub fn begin_rebuild() -> Result<(), AppError> {
+  if REBUILDING.swap(true, Ordering::SeqCst) {
+    return Err(AppError::new("search index is already rebuilding"));
+  }
+  let result = new_generation_dir(&index_root()).and_then(|dir| {
+    let index = create_index(&dir)?;
+    request(|reply| IndexOp::BeginRebuild { dir, index, reply })
+  });
+  if result.is_err() {
+    REBUILDING.store(false, Ordering::SeqCst);
   }
-  let tokenizer = tantivy_jieba::JiebaTokenizer {};
-  index.tokenizers().register("jieba", tokenizer);
+  result
+}
+
+/// Switch queries over to the rebuilt index and remove the old one.
+pub fn finish_rebuild() -> Result<(), AppError> {
+  request(IndexOp::FinishRebuild)
+}
 
-  return index;
+pub fn abort_rebuild() {
+  send(IndexOp::AbortRebuild).unwrap_or_else(|err| warn!("fail to abort rebuild: {err}"));
 }
 
-#[derive(Debug)]
+fn remove_old_index(root: &Path, old_dir: &Path) -> Result<(), AppError> {
+  if old_dir != root {
+    std::fs::remove_dir_all(old_dir)?;
+    return Ok(());
+  }
+  // an unversioned index shares the root with the new index directories
+  for entry in std::fs::read_dir(root)? {
+    let entry = entry?;
+    if entry.file_type()?.is_file() && entry.file_name() != CURRENT_FILE {
+      std::fs::remove_file(entry.path())?;
+    }
+  }
+  Ok(())
+}
+
+/// Merge all segments into one and remove files no longer used by the index.
+pub fn optimize() -> Result<(), AppError> {
+  request(IndexOp::Optimize)
+}
+
+/// Remove the search index so it is created again with the current configuration,
+/// must be called before [`SEARCH_INDEX`] is first used.
+pub fn remove_index() -> Result<(), AppError> {
+  let index_path = index_root();
+  if index_path.exists() {
+    std::fs::remove_dir_all(&index_path)?;
+  }
+  RECREATED.store(true, Ordering::SeqCst);
+  Ok(())
+}
+
+#[derive(Debug, Clone)]
 pub struct Doc {
   pub path: String,
   pub name: String,
   pub body: String,
+  /// 1-based page number for paged documents like pdf
+  pub page: Option<u64>,
+  pub size: u64,
+  /// modified time in millis
+  pub modified: u64,
+  pub mime: String,
+  /// parent directory of `path`
+  pub dir: String,
 }
 
 conv_err!(tantivy::error::TantivyError);
 conv_err!(QueryParserError);
+conv_err!(tantivy::directory::error::OpenDirectoryError);
 
-pub fn search_docs(query: &str) -> Result<Vec<Document>, AppError> {
-  let index = SEARCH_INDEX.lock().unwrap();
-  let schema = index.schema();
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+  pub path: String,
+  pub name: String,
+  pub page: Option<u64>,
+  pub size: Option<u64>,
+  pub modified: Option<u64>,
+  pub score: f32,
+  /// short excerpt of the body around the matched terms
+  pub fragment: String,
+  /// byte ranges `[start, end)` of matched terms in `fragment`
+  pub highlighted: Vec<(usize, usize)>,
+  /// set for entries inside an archive
+  pub archive: Option<ArchiveLink>,
+}
 
-  let reader = index
-    .reader_builder()
-    .reload_policy(ReloadPolicy::OnCommit)
-    .try_into()?;
+#[derive(Debug, Serialize)]
+pub struct FacetCount {
+  pub mime: String,
+  pub count: u64,
+}
 
-  let searcher = reader.searcher();
-  let name = schema.get_field("name").unwrap();
-  let body = schema.get_field("body").unwrap();
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+  pub total: usize,
+  pub hits: Vec<SearchHit>,
+  /// hit count per mime type, computed without the mime filter
+  pub facets: Vec<FacetCount>,
+}
 
-  let query_parser = QueryParser::for_index(&index, vec![name, body]);
-  let query = query_parser.parse_query(query)?;
-  let top_docs = searcher.search(&query, &TopDocs::with_limit(10))?;
-  let mut docs = vec![];
-  for (_score, doc_address) in top_docs {
-    let retrieved_doc = searcher.doc(doc_address)?;
-    docs.push(retrieved_doc);
-  }
-  Ok(docs)
+#[derive(Debug, Default)]
+pub struct SearchOptions {
+  pub offset: usize,
+  pub limit: usize,
+  pub mimes: Vec<String>,
+  pub min_size: Option<u64>,
+  pub max_size: Option<u64>,
+  /// modified time range in millis
+  pub modified_after: Option<u64>,
+  pub modified_before: Option<u64>,
+  /// only return files under this directory, relative to file root
+  pub folder: Option<String>,
 }
 
-pub fn insert_docs(docs: Vec<Doc>, now: &str) -> Result<(), AppError> {
-  let index = SEARCH_INDEX.lock().unwrap();
-  let schema = index.schema();
+const SNIPPET_MAX_CHARS: usize = 200;
 
-  let mut index_writer = index.writer(10_000_000)?;
+/// fields added after the first release are missing in old indexes
+fn required_field(schema: &Schema, name: &str) -> Result<Field, AppError> {
+  schema.get_field(name).ok_or(AppError::new(&format!(
+    "search index has no field {name}, please rebuild the index"
+  )))
+}
 
-  let name = schema.get_field("name").unwrap();
-  let path = schema.get_field("path").unwrap();
-  let body = schema.get_field("body").unwrap();
-  let updated_at = schema.get_field("updated_at").unwrap();
+fn dir_facet(dir: &str) -> Facet {
+  Facet::from_path(Path::new(dir).iter().map(|s| s.to_string_lossy().to_string()))
+}
 
-  for doc in docs {
-    index_writer.add_document(doc!(
-      name => doc.name,
-      path => doc.path,
-      body => doc.body,
-      updated_at => now.to_string(),
-    ))?;
-  }
+fn mime_facet(mime: &str) -> Facet {
+  Facet::from_path([mime])
+}
 
-  index_writer.commit()?;
-  Ok(())
+fn u64_range(field: Field, min: Option<u64>, max: Option<u64>) -> Option<Box<dyn Query>> {
+  if min.is_none() && max.is_none() {
+    return None;
+  }
+  let range = min.unwrap_or(0)..max.map_or(u64::MAX, |m| m.saturating_add(1));
+  Some(Box::new(RangeQuery::new_u64(field, range)))
 }
 
-#[allow(unused)]
-pub fn cleanup(not_updated_at: &str) -> Result<(), AppError> {
-  let index = SEARCH_INDEX.lock().unwrap();
+pub fn search_docs(query: &str, options: &SearchOptions) -> Result<SearchResult, AppError> {
+  let search_index = current();
+  let index = &search_index.index;
   let schema = index.schema();
-  let updated_at = schema.get_field("updated_at").unwrap();
+  let searcher = search_index.reader.searcher();
+  let name = required_field(&schema, "name")?;
+  let path = required_field(&schema, "path")?;
+  let body = required_field(&schema, "body")?;
+  let page = schema.get_field("page");
+  let size = required_field(&schema, "size")?;
+  let modified = required_field(&schema, "modified")?;
+  let mime = required_field(&schema, "mime")?;
+  let dir = required_field(&schema, "dir")?;
+
+  let query_parser = QueryParser::for_index(index, vec![name, body]);
+  let text_query = query_parser.parse_query(query)?;
+
+  let mut filters: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, text_query.box_clone())];
+  if let Some(folder) = options.folder.as_ref().filter(|f| !f.is_empty()) {
+    let term = Term::from_facet(dir, &dir_facet(folder));
+    filters.push((
+      Occur::Must,
+      Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+    ));
+  }
+  if let Some(q) = u64_range(size, options.min_size, options.max_size) {
+    filters.push((Occur::Must, q));
+  }
+  if let Some(q) = u64_range(modified, options.modified_after, options.modified_before) {
+    filters.push((Occur::Must, q));
+  }
+  let facet_query = BooleanQuery::new(filters.iter().map(|(o, q)| (*o, q.box_clone())).collect());
+
+  if !options.mimes.is_empty() {
+    let mime_queries: Vec<(Occur, Box<dyn Query>)> = options
+      .mimes
+      .iter()
+      .map(|m| {
+        let term = Term::from_facet(mime, &mime_facet(m));
+        let q: Box<dyn Query> = Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+        (Occur::Should, q)
+      })
+      .collect();
+    filters.push((Occur::Must, Box::new(BooleanQuery::new(mime_queries))));
+  }
+  let query = BooleanQuery::new(filters);
+
+  let (top_docs, total) = searcher.search(
+    &query,
+    &(
+      TopDocs::with_limit(options.limit).and_offset(options.offset),
+      Count,
+    ),
+  )?;
+
+  let mut facet_collector = FacetCollector::for_field(mime);
+  facet_collector.add_facet(Facet::root());
+  let facet_counts = searcher.search(&facet_query, &facet_collector)?;
+  let facets = facet_counts
+    .get(Facet::root())
+    .map(|(facet, count)| FacetCount {
+      mime: facet.to_path().last().map_or("".to_owned(), |m| m.to_string()),
+      count,
+    })
+    .collect();
+
+  let mut snippet_generator = SnippetGenerator::create(&searcher, &*text_query, body)?;
+  snippet_generator.set_max_num_chars(SNIPPET_MAX_CHARS);
+
+  let mut hits = vec![];
+  for (score, doc_address) in top_docs {
+    let retrieved_doc = searcher.doc(doc_address)?;
+    let snippet = snippet_generator.snippet_from_doc(&retrieved_doc);
+    let text_of = |field: Field| {
+      retrieved_doc
+        .get_first(field)
+        .and_then(|v| v.as_text())
+        .unwrap_or_default()
+        .to_owned()
+    };
+    let u64_of = |field: Field| retrieved_doc.get_first(field).and_then(|v| v.as_u64());
+    let file_path = text_of(path);
+    hits.push(SearchHit {
+      archive: ArchiveLink::from_path(&file_path),
+      path: file_path,
+      name: text_of(name),
+      page: page.and_then(u64_of),
+      size: u64_of(size),
+      modified: u64_of(modified),
+      score,
+      fragment: snippet.fragment().to_owned(),
+      highlighted: snippet.highlighted().iter().map(|r| (r.start, r.end)).collect(),
+    });
+  }
+  Ok(SearchResult {
+    total,
+    hits,
+    facets,
+  })
+}
 
-  let mut index_writer = index.writer(10_000_000)?;
+enum IndexOp {
+  Insert {
+    docs: Vec<Doc>,
+    now: String,
+  },
+  /// delete the documents matching `query`, parsed with `field` as default field
+  DeleteQuery {
+    field: &'static str,
+    query: String,
+  },
+  /// delete the documents of these files
+  DeletePaths(Vec<String>),
+  Commit(Sender<Result<(), AppError>>),
+  Optimize(Sender<Result<(), AppError>>),
+  BeginRebuild {
+    dir: PathBuf,
+    index: Index,
+    reply: Sender<Result<(), AppError>>,
+  },
+  FinishRebuild(Sender<Result<(), AppError>>),
+  AbortRebuild,
+}
 
-  let mut query_parser = QueryParser::for_index(&index, vec![updated_at]);
-  query_parser.set_conjunction_by_default();
-  let query_str = format!(r#"updated_at:[0 TO {not_updated_at}}}"#);
-  let query = query_parser.parse_query(&query_str)?;
+fn send(op: IndexOp) -> Result<(), AppError> {
+  INDEXER
+    .lock()
+    .unwrap()
+    .send(op)
+    .map_err(|_| AppError::new("search indexer is not running"))
+}
 
-  index_writer.delete_query(query)?;
+/// send an operation and wait until the indexer has handled it
+fn request(op: impl FnOnce(Sender<Result<(), AppError>>) -> IndexOp) -> Result<(), AppError> {
+  let (reply, result) = mpsc::channel();
+  send(op(reply))?;
+  result
+    .recv()
+    .map_err(|_| AppError::new("search indexer is not running"))?
+}
 
-  index_writer.commit()?;
-  Ok(())
+struct Rebuild {
+  dir: PathBuf,
+  index: Index,
+  writer: IndexWriter,
 }
 
-#[allow(unused)]
-pub fn cleanup_stale_data(max_stale_secs: u64) -> Result<(), AppError> {
-  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
-  let delete_before = (now - (max_stale_secs * 1000) as u128).to_string();
-  let index = SEARCH_INDEX.lock().unwrap();
-  let schema = index.schema();
-  let updated_at = schema.get_field("updated_at").unwrap();
+struct Indexer {
+  writer: IndexWriter,
+  rebuild: Option<Rebuild>,
+  /// documents added or delete queries since the last commit
+  pending: usize,
+}
 
-  let mut index_writer = index.writer(10_000_000)?;
+fn run_indexer(receiver: Receiver<IndexOp>) {
+  let writer = match current().index.writer(WRITER_HEAP_SIZE) {
+    Ok(writer) => writer,
+    Err(err) => {
+      error!("fail to create search index writer: {err}");
+      return;
+    }
+  };
+  let mut indexer = Indexer {
+    writer,
+    rebuild: None,
+    pending: 0,
+  };
+  loop {
+    let op = if indexer.pending > 0 {
+      match receiver.recv_timeout(COMMIT_INTERVAL) {
+        Ok(op) => op,
+        Err(RecvTimeoutError::Timeout) => {
+          indexer.commit().unwrap_or_else(|err| error!("fail to commit search index: {err}"));
+          continue;
+        }
+        Err(RecvTimeoutError::Disconnected) => break,
+      }
+    } else {
+      match receiver.recv() {
+        Ok(op) => op,
+        Err(_) => break,
+      }
+    };
+    indexer.handle(op);
+    if indexer.pending >= COMMIT_BATCH_SIZE {
+      indexer.commit().unwrap_or_else(|err| error!("fail to commit search index: {err}"));
+    }
+  }
+  let _ = indexer.commit();
+}
 
-  let mut query_parser = QueryParser::for_index(&index, vec![updated_at]);
-  query_parser.set_conjunction_by_default();
-  let query_str = format!(r#"updated_at:[0 TO {delete_before}}}"#);
-  let query = query_parser.parse_query(&query_str)?;
+impl Indexer {
+  fn handle(&mut self, op: IndexOp) {
+    match op {
+      IndexOp::Insert { docs, now } => {
+        self.pending += docs.len();
+        self
+          .write_all(|index, writer| add_docs(index, writer, &docs, &now))
+          .unwrap_or_else(|err| error!("fail to add documents to search index: {err}"));
+      }
+      IndexOp::DeleteQuery { field, query } => {
+        self.pending += 1;
+        self
+          .write_all(|index, writer| delete_by_query(index, writer, field, &query))
+          .unwrap_or_else(|err| error!("fail to delete from search index: {err}"));
+      }
+      IndexOp::DeletePaths(paths) => {
+        self.pending += paths.len();
+        self
+          .write_all(|index, writer| delete_paths(index, writer, &paths))
+          .unwrap_or_else(|err| error!("fail to delete from search index: {err}"));
+      }
+      IndexOp::Commit(reply) => {
+        let _ = reply.send(self.commit());
+      }
+      IndexOp::Optimize(reply) => {
+        let _ = reply.send(self.optimize());
+      }
+      IndexOp::BeginRebuild { dir, index, reply } => {
+        let result = index.writer(WRITER_HEAP_SIZE).map(|writer| {
+          self.rebuild = Some(Rebuild { dir, index, writer });
+        });
+        let _ = reply.send(result.map_err(AppError::from));
+      }
+      IndexOp::FinishRebuild(reply) => {
+        let _ = reply.send(self.finish_rebuild());
+      }
+      IndexOp::AbortRebuild => {
+        if let Some(rebuild) = self.rebuild.take() {
+          drop(rebuild.writer);
+          let _ = std::fs::remove_dir_all(&rebuild.dir);
+        }
+        REBUILDING.store(false, Ordering::SeqCst);
+      }
+    }
+  }
 
-  index_writer.delete_query(query)?;
+  /// Apply a write to the active index and to the index being rebuilt, if any.
+  fn write_all(
+    &mut self,
+    write: impl Fn(&Index, &mut IndexWriter) -> Result<(), AppError>,
+  ) -> Result<(), AppError> {
+    write(&current().index, &mut self.writer)?;
+    if let Some(rebuild) = &mut self.rebuild {
+      write(&rebuild.index, &mut rebuild.writer)?;
+    }
+    Ok(())
+  }
 
-  index_writer.commit()?;
-  Ok(())
+  fn commit(&mut self) -> Result<(), AppError> {
+    self.pending = 0;
+    self.writer.commit()?;
+    current().reader.reload()?;
+    if let Some(rebuild) = &mut self.rebuild {
+      rebuild.writer.commit()?;
+    }
+    Ok(())
+  }
+
+  fn optimize(&mut self) -> Result<(), AppError> {
+    self.commit()?;
+    let segment_ids = current().index.searchable_segment_ids()?;
+    if segment_ids.len() > 1 {
+      self.writer.merge(&segment_ids).wait()?;
+    }
+    self.writer.garbage_collect_files().wait()?;
+    current().reader.reload()?;
+    Ok(())
+  }
+
+  fn finish_rebuild(&mut self) -> Result<(), AppError> {
+    self.commit()?;
+    let Rebuild { dir, index, writer } = self
+      .rebuild
+      .take()
+      .ok_or(AppError::new("search index is not rebuilding"))?;
+    REBUILDING.store(false, Ordering::SeqCst);
+    RECREATED.store(false, Ordering::SeqCst);
+
+    let root = index_root();
+    let old_dir = active_dir(&root);
+    set_active_dir(&root, &dir)?;
+    *SEARCH_INDEX.write().unwrap() = Arc::new(SearchIndex::new(index)?);
+    // the old writer holds the lock of the old index until dropped
+    self.writer = writer;
+
+    if let Some(old_dir) = old_dir {
+      remove_old_index(&root, &old_dir).unwrap_or_else(|err| {
+        warn!("fail to remove old search index {old_dir:?}: {err}");
+      });
+    }
+    info!("search index rebuilt in {dir:?}");
+    Ok(())
+  }
 }
 
-#[allow(unused)]
-pub fn delete(files: &Vec<String>) -> Result<(), AppError> {
-  let index = SEARCH_INDEX.lock().unwrap();
+/// Queue documents for indexing, they become searchable after the next batch commit.
+pub fn insert_docs(docs: Vec<Doc>, now: &str) -> Result<(), AppError> {
+  send(IndexOp::Insert {
+    docs,
+    now: now.to_owned(),
+  })
+}
+
+/// wait until all queued writes are committed and searchable
+pub fn commit() -> Result<(), AppError> {
+  request(IndexOp::Commit)
+}
+
+fn add_docs(
+  index: &Index,
+  index_writer: &mut IndexWriter,
+  docs: &[Doc],
+  now: &str,
+) -> Result<(), AppError> {
   let schema = index.schema();
-  let path = schema.get_field("path").unwrap();
 
-  let mut index_writer = index.writer(10_000_000)?;
+  let name = required_field(&schema, "name")?;
+  let path = required_field(&schema, "path")?;
+  let body = required_field(&schema, "body")?;
+  let updated_at = required_field(&schema, "updated_at")?;
+  // fields below are missing in indexes created by older versions
+  let page = schema.get_field("page");
+  let size = schema.get_field("size");
+  let modified = schema.get_field("modified");
+  let mime = schema.get_field("mime");
+  let dir = schema.get_field("dir");
+  let path_raw = schema.get_field("path_raw");
 
-  let mut query_parser = QueryParser::for_index(&index, vec![path]);
+  for doc in docs {
+    let mut document = doc!(
+      name => doc.name.clone(),
+      path => doc.path.clone(),
+      body => doc.body.clone(),
+      updated_at => now.to_string(),
+    );
+    if let (Some(page), Some(page_num)) = (page, doc.page) {
+      document.add_u64(page, page_num);
+    }
+    if let Some(size) = size {
+      document.add_u64(size, doc.size);
+    }
+    if let Some(modified) = modified {
+      document.add_u64(modified, doc.modified);
+    }
+    if let Some(mime) = mime {
+      document.add_facet(mime, mime_facet(&doc.mime));
+    }
+    if let Some(dir) = dir {
+      document.add_facet(dir, dir_facet(&doc.dir));
+    }
+    if let Some(path_raw) = path_raw {
+      document.add_text(path_raw, &doc.path);
+    }
+    index_writer.add_document(document)?;
+  }
+  Ok(())
+}
+
+fn delete_by_query(
+  index: &Index,
+  index_writer: &mut IndexWriter,
+  field_name: &str,
+  query_str: &str,
+) -> Result<(), AppError> {
+  let field = required_field(&index.schema(), field_name)?;
+
+  let mut query_parser = QueryParser::for_index(index, vec![field]);
   query_parser.set_conjunction_by_default();
-  let query_str = files
-    .into_iter()
-    .map(|f| format!(r#""{f}""#))
-    .collect::<Vec<_>>()
-    .join(" AND ");
-  let query = query_parser.parse_query(&query_str)?;
+  let query = query_parser.parse_query(query_str)?;
 
   index_writer.delete_query(query)?;
+  Ok(())
+}
 
-  index_writer.commit()?;
+fn delete_paths(index: &Index, index_writer: &mut IndexWriter, paths: &[String]) -> Result<(), AppError> {
+  let Some(path_raw) = index.schema().get_field("path_raw") else {
+    // older indexes only have the tokenized path, the phrase may match other paths too
+    for p in paths {
+      delete_by_query(index, index_writer, "path", &format!(r#""{p}""#))?;
+    }
+    return Ok(());
+  };
+  for p in paths {
+    index_writer.delete_term(Term::from_field_text(path_raw, p));
+  }
   Ok(())
 }
 
+/// Delete documents of a previous full update and wait until it is committed.
 #[allow(unused)]
-pub fn cleanup_by_path(file_path: &str) -> Result<(), AppError> {
-  let index = SEARCH_INDEX.lock().unwrap();
-  let schema = index.schema();
-  let path = schema.get_field("path").unwrap();
-
-  let mut index_writer = index.writer(10_000_000)?;
+pub fn cleanup(not_updated_at: &str) -> Result<(), AppError> {
+  send(IndexOp::DeleteQuery {
+    field: "updated_at",
+    query: format!(r#"updated_at:[0 TO {not_updated_at}}}"#),
+  })?;
+  commit()
+}
 
-  let mut query_parser = QueryParser::for_index(&index, vec![path]);
-  query_parser.set_conjunction_by_default();
-  let query_str = format!(r#""{file_path}""#);
-  let query = query_parser.parse_query(&query_str)?;
+#[allow(unused)]
+pub fn cleanup_stale_data(max_stale_secs: u64) -> Result<(), AppError> {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+  let delete_before = (now - (max_stale_secs * 1000) as u128).to_string();
+  send(IndexOp::DeleteQuery {
+    field: "updated_at",
+    query: format!(r#"updated_at:[0 TO {delete_before}}}"#),
+  })
+}
 
-  index_writer.delete_query(query)?;
+/// Delete the documents of `files`, paths relative to file root.
+pub fn delete(files: &Vec<String>) -> Result<(), AppError> {
+  if files.is_empty() {
+    return Ok(());
+  }
+  send(IndexOp::DeletePaths(files.clone()))
+}
 
-  index_writer.commit()?;
-  Ok(())
+#[allow(unused)]
+pub fn cleanup_by_path(file_path: &str) -> Result<(), AppError> {
+  send(IndexOp::DeleteQuery {
+    field: "path",
+    query: format!(r#""{file_path}""#),
+  })
 }
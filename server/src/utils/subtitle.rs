@@ -0,0 +1,362 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use actix_web::http::StatusCode;
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::config;
+
+use super::error::AppError;
+use super::media_probe;
+use super::thumbnail;
+use super::transcode;
+
+const SIDECAR_EXTENSIONS: [&str; 4] = ["srt", "vtt", "ass", "ssa"];
+/// embedded codecs ffmpeg converts to WebVTT, bitmap subtitles like PGS can not be converted
+const TEXT_SUBTITLE_CODECS: [&str; 6] = ["subrip", "ass", "ssa", "webvtt", "mov_text", "text"];
+/// part of the cache key, bump it when the conversion changes
+const CACHE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubtitleTrack {
+  /// `sidecar:<file name>` or `embedded:<stream index>`
+  pub id: String,
+  pub label: String,
+  pub language: Option<String>,
+  /// srt, vtt, ass or ssa for sidecars, the ffmpeg codec name for embedded tracks
+  pub format: String,
+  pub default: bool,
+}
+
+fn extension(file: &Path) -> Option<String> {
+  file.extension().map(|e| e.to_string_lossy().to_lowercase())
+}
+
+/// `movie.en.forced.srt` next to `movie.mkv` is labeled `en.forced`, with language `en`
+fn sidecar_track(video_stem: &str, file_name: &str) -> Option<SubtitleTrack> {
+  let path = Path::new(file_name);
+  let ext = extension(path).filter(|e| SIDECAR_EXTENSIONS.contains(&e.as_str()))?;
+  let stem = path.file_stem()?.to_string_lossy();
+  let suffix = stem.strip_prefix(video_stem)?;
+  if !suffix.is_empty() && !suffix.starts_with('.') {
+    return None;
+  }
+  let suffix = suffix.trim_start_matches('.');
+  let language = suffix
+    .split('.')
+    .next()
+    .filter(|l| (2..=3).contains(&l.len()) && l.chars().all(|c| c.is_ascii_alphabetic()))
+    .map(|l| l.to_lowercase());
+  let label = if suffix.is_empty() { ext.clone() } else { suffix.to_owned() };
+  Some(SubtitleTrack {
+    id: format!("sidecar:{file_name}"),
+    label,
+    language,
+    format: ext,
+    default: false,
+  })
+}
+
+/// Subtitles of the video `file_root/file`: sidecar files in its directory named after it,
+/// then the text tracks embedded in it.
+pub fn list_tracks(file_root: &Path, file: &str) -> Result<Vec<SubtitleTrack>, AppError> {
+  let path = file_root.join(file);
+  let stem = path
+    .file_stem()
+    .ok_or(AppError::new("invalid file").with_status(StatusCode::BAD_REQUEST))?
+    .to_string_lossy()
+    .to_string();
+  let mut tracks = vec![];
+  if let Some(dir) = path.parent() {
+    for entry in fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+      let name = entry.file_name().to_string_lossy().to_string();
+      if entry.file_type().is_ok_and(|t| t.is_file()) {
+        tracks.extend(sidecar_track(&stem, &name));
+      }
+    }
+  }
+  tracks.sort_by(|a, b| a.label.cmp(&b.label));
+
+  // an unreadable video still has its sidecar subtitles
+  if let Ok(media) = media_probe::media_info(file_root, file) {
+    for s in media.streams {
+      if s.codec_type.as_deref() != Some("subtitle") {
+        continue;
+      }
+      let Some(codec) = s.codec_name.filter(|c| TEXT_SUBTITLE_CODECS.contains(&c.as_str())) else {
+        continue;
+      };
+      let label = s
+        .title
+        .clone()
+        .or_else(|| s.language.clone())
+        .unwrap_or_else(|| format!("track {}", s.index));
+      tracks.push(SubtitleTrack {
+        id: format!("embedded:{}", s.index),
+        label,
+        language: s.language,
+        format: codec,
+        default: s.default,
+      });
+    }
+  }
+  Ok(tracks)
+}
+
+/// Decode subtitle bytes to text. A BOM wins, then valid UTF-8, otherwise the
+/// encoding is guessed from the content, e.g. GBK or Windows-1252.
+pub fn decode_text(data: &[u8]) -> String {
+  if let Some((encoding, bom_len)) = Encoding::for_bom(data) {
+    return encoding.decode_without_bom_handling(&data[bom_len..]).0.into_owned();
+  }
+  if let Ok(s) = std::str::from_utf8(data) {
+    return s.to_owned();
+  }
+  let mut detector = EncodingDetector::new();
+  detector.feed(data, true);
+  let encoding = detector.guess(None, true);
+  if encoding == UTF_8 {
+    return String::from_utf8_lossy(data).into_owned();
+  }
+  encoding.decode_without_bom_handling(data).0.into_owned()
+}
+
+/// Convert SubRip to WebVTT: the header is added and the millisecond separator of the
+/// timings changes from `,` to `.`, cue numbers are kept as cue identifiers.
+pub fn srt_to_webvtt(srt: &str) -> String {
+  let mut vtt = "WEBVTT\n\n".to_owned();
+  let normalized = srt.replace("\r\n", "\n").replace('\r', "\n");
+  for line in normalized.lines() {
+    match srt_timing_to_webvtt(line) {
+      Some(timing) => vtt.push_str(&timing),
+      None => vtt.push_str(line),
+    }
+    vtt.push('\n');
+  }
+  vtt
+}
+
+fn is_srt_timestamp(s: &str) -> bool {
+  s.contains(':') && s.chars().all(|c| c.is_ascii_digit() || matches!(c, ':' | ',' | '.'))
+}
+
+/// `00:00:01,000 --> 00:00:02,500 X1:40` to `00:00:01.000 --> 00:00:02.500 X1:40`, None for
+/// lines which are not timings, e.g. cue text containing an arrow.
+fn srt_timing_to_webvtt(line: &str) -> Option<String> {
+  let (start, rest) = line.split_once("-->")?;
+  let rest = rest.trim_start();
+  let (end, settings) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+  let start = start.trim();
+  if !is_srt_timestamp(start) || !is_srt_timestamp(end) {
+    return None;
+  }
+  let mut timing = format!("{} --> {}", start.replacen(',', ".", 1), end.replacen(',', ".", 1));
+  if !settings.trim().is_empty() {
+    timing.push(' ');
+    timing.push_str(settings.trim());
+  }
+  Some(timing)
+}
+
+/// Run ffmpeg with `input_args` and convert its first subtitle stream to WebVTT,
+/// `stdin` is piped to it when the input is `pipe:0`.
+fn ffmpeg_to_webvtt(input_args: &[&str], stdin: Option<Vec<u8>>) -> Result<String, AppError> {
+  transcode::ensure_enabled()?;
+  let mut child = Command::new(config!(ffmpeg_bin_path))
+    .args(["-hide_banner", "-loglevel", "error", "-nostdin"])
+    .args(input_args)
+    .args(["-f", "webvtt", "pipe:1"])
+    .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()?;
+  // written from another thread, ffmpeg blocks on a full stdout while reading its input
+  let writer = match (stdin, child.stdin.take()) {
+    (Some(data), Some(mut pipe)) => Some(thread::spawn(move || pipe.write_all(&data))),
+    _ => None,
+  };
+  let output = child.wait_with_output()?;
+  if let Some(writer) = writer {
+    let _ = writer.join();
+  }
+  if !output.status.success() {
+    let err = String::from_utf8_lossy(&output.stderr);
+    return Err(AppError::new(&format!("ffmpeg failed: {}", err.trim())));
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Converted tracks are cached with the thumbnails, keyed by the source and the track.
+fn cache_file(source: &Path, file: &str, track_id: &str) -> Result<PathBuf, AppError> {
+  let meta = fs::metadata(source)?;
+  let modified = meta.modified()?.duration_since(UNIX_EPOCH)?.as_millis();
+  let mut hasher = Sha256::new();
+  hasher.update(format!(
+    "subtitle-{CACHE_VERSION}\n{file}\n{modified}\n{}\n{track_id}",
+    meta.len()
+  ));
+  Ok(thumbnail::cache_path(&hex::encode(hasher.finalize()), "vtt"))
+}
+
+fn cached<F>(source: &Path, file: &str, track_id: &str, convert: F) -> Result<String, AppError>
+where
+  F: FnOnce() -> Result<String, AppError>,
+{
+  let cache_file = cache_file(source, file, track_id)?;
+  if let Ok(vtt) = fs::read_to_string(&cache_file) {
+    return Ok(vtt);
+  }
+  let vtt = convert()?;
+  thumbnail::store(&cache_file, vtt.as_bytes())?;
+  Ok(vtt)
+}
+
+/// How a track is turned into WebVTT.
+enum Conversion {
+  /// srt and vtt sidecars are converted in process
+  Text { source: PathBuf, format: String },
+  /// other tracks go through ffmpeg, `file` is the source relative to file root, `stream`
+  /// the index of an embedded track
+  Ffmpeg {
+    source: PathBuf,
+    file: String,
+    stream: Option<String>,
+  },
+}
+
+fn conversion(file_root: &Path, file: &str, track_id: &str) -> Result<Conversion, AppError> {
+  let not_found = || AppError::new("subtitle track not found").with_status(StatusCode::NOT_FOUND);
+  let track = list_tracks(file_root, file)?
+    .into_iter()
+    .find(|t| t.id == track_id)
+    .ok_or_else(not_found)?;
+  let video = file_root.join(file);
+  if let Some(name) = track.id.strip_prefix("sidecar:") {
+    let source = video.with_file_name(name);
+    if track.format == "srt" || track.format == "vtt" {
+      return Ok(Conversion::Text {
+        source,
+        format: track.format,
+      });
+    }
+    return Ok(Conversion::Ffmpeg {
+      source,
+      file: Path::new(file).with_file_name(name).to_string_lossy().to_string(),
+      stream: None,
+    });
+  }
+  let index = track.id.strip_prefix("embedded:").ok_or_else(not_found)?;
+  Ok(Conversion::Ffmpeg {
+    source: video,
+    file: file.to_owned(),
+    stream: Some(index.to_owned()),
+  })
+}
+
+/// Whether reading track `track_id` runs ffmpeg, the caller holds a transcode slot then.
+pub fn needs_ffmpeg(file_root: &Path, file: &str, track_id: &str) -> Result<bool, AppError> {
+  match conversion(file_root, file, track_id)? {
+    Conversion::Text { .. } => Ok(false),
+    Conversion::Ffmpeg { source, file, .. } => Ok(!cache_file(&source, &file, track_id)?.exists()),
+  }
+}
+
+/// Track `track_id` of the video `file_root/file` as WebVTT.
+pub fn read_webvtt(file_root: &Path, file: &str, track_id: &str) -> Result<String, AppError> {
+  match conversion(file_root, file, track_id)? {
+    Conversion::Text { source, format } => {
+      let text = decode_text(&fs::read(&source)?);
+      Ok(if format == "srt" { srt_to_webvtt(&text) } else { text })
+    }
+    Conversion::Ffmpeg {
+      source,
+      file,
+      stream: None,
+    } => cached(&source, &file, track_id, || {
+      let text = decode_text(&fs::read(&source)?);
+      // the ass demuxer reads ssa too
+      ffmpeg_to_webvtt(&["-f", "ass", "-i", "pipe:0"], Some(text.into_bytes()))
+    }),
+    Conversion::Ffmpeg {
+      source,
+      file,
+      stream: Some(index),
+    } => cached(&source, &file, track_id, || {
+      let video = source.to_string_lossy();
+      ffmpeg_to_webvtt(&["-i", &video, "-map", &format!("0:{index}")], None)
+    }),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn converts_crlf_srt() {
+    let srt = "1\r\n00:00:01,000 --> 00:00:02,500\r\nHello\r\n\r\n2\r\n00:00:03,000 --> 00:00:04,000\r\nWorld\r\n";
+    assert_eq!(
+      srt_to_webvtt(srt),
+      "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.500\nHello\n\n2\n00:00:03.000 --> 00:00:04.000\nWorld\n"
+    );
+  }
+
+  #[test]
+  fn keeps_arrows_in_cue_text() {
+    let srt = "1\n00:00:01,000 --> 00:00:02,000\nleft --> right\n1,5 --> 2,5\n";
+    assert_eq!(
+      srt_to_webvtt(srt),
+      "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.000\nleft --> right\n1,5 --> 2,5\n"
+    );
+  }
+
+  #[test]
+  fn keeps_positional_settings() {
+    assert_eq!(
+      srt_timing_to_webvtt("00:00:01,000 --> 00:00:02,500  X1:40 X2:600 Y1:20 Y2:50"),
+      Some("00:00:01.000 --> 00:00:02.500 X1:40 X2:600 Y1:20 Y2:50".to_owned())
+    );
+    assert_eq!(
+      srt_timing_to_webvtt("00:00:01,000-->00:00:02,500"),
+      Some("00:00:01.000 --> 00:00:02.500".to_owned())
+    );
+  }
+
+  #[test]
+  fn decodes_gbk() {
+    let text = "1\n00:00:01,000 --> 00:00:02,000\n今天的天气很好，我们一起去公园散步吧。\n";
+    let (gbk, _, _) = encoding_rs::GBK.encode(text);
+    assert!(std::str::from_utf8(&gbk).is_err());
+    assert_eq!(decode_text(&gbk), text);
+  }
+
+  #[test]
+  fn decodes_bom_and_utf8() {
+    assert_eq!(decode_text("\u{feff}字幕".as_bytes()), "字幕");
+    assert_eq!(decode_text("字幕".as_bytes()), "字幕");
+  }
+
+  #[test]
+  fn labels_sidecars() {
+    let track = sidecar_track("movie", "movie.en.forced.srt").unwrap();
+    assert_eq!(track.id, "sidecar:movie.en.forced.srt");
+    assert_eq!(track.label, "en.forced");
+    assert_eq!(track.language.as_deref(), Some("en"));
+    assert_eq!(track.format, "srt");
+
+    let track = sidecar_track("movie", "movie.ASS").unwrap();
+    assert_eq!(track.label, "ass");
+    assert_eq!(track.language, None);
+
+    assert_eq!(sidecar_track("movie", "movie.director.vtt").unwrap().language, None);
+    assert!(sidecar_track("movie", "movie2.en.srt").is_none());
+    assert!(sidecar_track("movie", "movie.en.txt").is_none());
+  }
+}
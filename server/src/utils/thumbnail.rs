@@ -0,0 +1,231 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::{ColorType, DynamicImage};
+use lazy_static::lazy_static;
+use mime_guess::mime;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::config;
+
+use super::archive::ARCHIVE_SEPARATOR;
+use super::error::AppError;
+use super::media_metadata::{apply_orientation, read_orientation};
+
+pub const MIN_DIMENSION: u32 = 16;
+pub const MAX_DIMENSION: u32 = 2048;
+const QUALITY: u8 = 80;
+/// part of the cache key, bump it when thumbnails of the same image change
+const CACHE_VERSION: u32 = 2;
+/// the cache is trimmed to this part of `thumbnail_cache_max_size` when it is full,
+/// so eviction does not run again after every new thumbnail
+const EVICT_TARGET_RATIO: f64 = 0.9;
+
+lazy_static! {
+  /// bytes written since the cache size was last checked
+  static ref WRITTEN_SINCE_EVICT: AtomicU64 = AtomicU64::new(0);
+  static ref PREGENERATE: Mutex<Sender<(PathBuf, Vec<String>)>> = Mutex::new(spawn_pregenerate_worker());
+}
+
+pub struct CacheEntry {
+  /// original image
+  pub source: PathBuf,
+  pub cache_file: PathBuf,
+  pub etag: String,
+  pub mime: &'static str,
+  pub dimension: u32,
+}
+
+fn cache_dir() -> PathBuf {
+  PathBuf::from(config!(thumbnail_cache_path))
+}
+
+/// extension and mime type of the configured thumbnail format
+pub fn output_format() -> (&'static str, &'static str) {
+  match config!(thumbnail_format).as_str() {
+    "jpeg" | "jpg" => ("jpg", "image/jpeg"),
+    _ => ("webp", "image/webp"),
+  }
+}
+
+/// Locate the thumbnail of `file` (relative to file root). The key covers the path, modified
+/// time and size of the original and the dimension, so a changed image gets a new thumbnail
+/// and the key doubles as ETag.
+pub fn cache_entry(file_root: &Path, file: &str, dimension: u32) -> Result<CacheEntry, AppError> {
+  let dimension = dimension.clamp(MIN_DIMENSION, MAX_DIMENSION);
+  let source = file_root.join(file);
+  let meta = fs::metadata(&source)?;
+  let modified = meta.modified()?.duration_since(UNIX_EPOCH)?.as_millis();
+  let (ext, mime) = output_format();
+  let mut hasher = Sha256::new();
+  hasher.update(format!(
+    "{CACHE_VERSION}\n{file}\n{modified}\n{}\n{dimension}\n{ext}",
+    meta.len()
+  ));
+  let key = hex::encode(hasher.finalize());
+  let cache_file = cache_path(&key, ext);
+  Ok(CacheEntry {
+    source,
+    cache_file,
+    etag: format!("\"{key}\""),
+    mime,
+    dimension,
+  })
+}
+
+/// Location of a cached file, `key` is a hex digest.
+pub fn cache_path(key: &str, ext: &str) -> PathBuf {
+  cache_dir().join(&key[..2]).join(format!("{key}.{ext}"))
+}
+
+pub fn encode(img: &DynamicImage, mime: &str) -> Result<Vec<u8>, AppError> {
+  let mut buf = vec![];
+  if mime == "image/jpeg" {
+    let rgb = img.to_rgb8();
+    JpegEncoder::new_with_quality(&mut Cursor::new(&mut buf), QUALITY).encode(
+      &rgb,
+      rgb.width(),
+      rgb.height(),
+      ColorType::Rgb8,
+    )?;
+  } else {
+    let rgba = img.to_rgba8();
+    WebPEncoder::new_with_quality(&mut Cursor::new(&mut buf), WebPQuality::lossy(QUALITY)).encode(
+      &rgba,
+      rgba.width(),
+      rgba.height(),
+      ColorType::Rgba8,
+    )?;
+  }
+  Ok(buf)
+}
+
+fn generate(entry: &CacheEntry) -> Result<Vec<u8>, AppError> {
+  let img = image::io::Reader::open(&entry.source)?
+    .with_guessed_format()?
+    .decode()?;
+  let img = img.thumbnail(entry.dimension, entry.dimension);
+  let img = apply_orientation(img, read_orientation(&entry.source));
+  let data = encode(&img, entry.mime)?;
+  store(&entry.cache_file, &data)?;
+  Ok(data)
+}
+
+/// Write a file to the cache, the oldest files are evicted when the cache grows too large.
+pub fn store(cache_file: &Path, data: &[u8]) -> Result<(), AppError> {
+  if let Some(parent) = cache_file.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  // write to a temporary file first, a concurrent reader never sees a partial file
+  let tmp = cache_file.with_extension("tmp");
+  fs::write(&tmp, data)?;
+  fs::rename(&tmp, cache_file)?;
+
+  let max_size = config!(thumbnail_cache_max_size);
+  let written = WRITTEN_SINCE_EVICT.fetch_add(data.len() as u64, Ordering::Relaxed) + data.len() as u64;
+  if written > max_size / 20 {
+    WRITTEN_SINCE_EVICT.store(0, Ordering::Relaxed);
+    evict(max_size)?;
+  }
+  Ok(())
+}
+
+/// Read the cached thumbnail, it is created when missing.
+pub fn load(entry: &CacheEntry) -> Result<Vec<u8>, AppError> {
+  match fs::read(&entry.cache_file) {
+    Ok(data) => Ok(data),
+    Err(_) => generate(entry),
+  }
+}
+
+/// Remove the oldest thumbnails until the cache is below `max_size` bytes.
+pub fn evict(max_size: u64) -> Result<(), AppError> {
+  let dir = cache_dir();
+  if !dir.exists() {
+    return Ok(());
+  }
+  let mut files = vec![];
+  let mut total = 0;
+  for entry in WalkDir::new(&dir).into_iter().filter_map(|e| e.ok()) {
+    if !entry.file_type().is_file() {
+      continue;
+    }
+    let meta = entry.metadata()?;
+    total += meta.len();
+    files.push((meta.modified()?, meta.len(), entry.into_path()));
+  }
+  if total <= max_size {
+    return Ok(());
+  }
+  files.sort_by_key(|(modified, ..)| *modified);
+  let target = (max_size as f64 * EVICT_TARGET_RATIO) as u64;
+  for (_, size, path) in files {
+    if total <= target {
+      break;
+    }
+    if let Err(err) = fs::remove_file(&path) {
+      warn!("fail to evict thumbnail {path:?}: {err}");
+      continue;
+    }
+    total -= size;
+  }
+  Ok(())
+}
+
+fn spawn_pregenerate_worker() -> Sender<(PathBuf, Vec<String>)> {
+  let (tx, rx) = channel::<(PathBuf, Vec<String>)>();
+  thread::Builder::new()
+    .name("thumbnail-pregenerate".to_owned())
+    .spawn(move || {
+      for (file_root, files) in rx {
+        let sizes = config!(thumbnail_pregenerate_sizes);
+        for file in files {
+          for dimension in sizes.iter() {
+            let r = cache_entry(&file_root, &file, *dimension).and_then(|entry| {
+              if !entry.cache_file.exists() {
+                generate(&entry)?;
+              }
+              Ok(())
+            });
+            if let Err(err) = r {
+              warn!("fail to create thumbnail of {file}: {err}");
+            }
+          }
+        }
+      }
+    })
+    .unwrap();
+  tx
+}
+
+/// Create thumbnails of the configured sizes for `files` (relative to file root) in the
+/// background, files which are not images are skipped.
+pub fn pregenerate(file_root: &Path, files: Vec<String>) {
+  let images: Vec<String> = files
+    .into_iter()
+    .filter(|f| !f.contains(ARCHIVE_SEPARATOR))
+    .filter(|f| {
+      mime_guess::from_path(f)
+        .first()
+        .is_some_and(|m| m.type_() == mime::IMAGE && m.subtype() != mime::SVG)
+    })
+    .collect();
+  if images.is_empty() || config!(thumbnail_pregenerate_sizes).is_empty() {
+    return;
+  }
+  PREGENERATE
+    .lock()
+    .unwrap()
+    .send((file_root.to_path_buf(), images))
+    .unwrap_or_else(|err| warn!("thumbnail worker stopped: {err}"));
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use tantivy::tokenizer::{
+  BoxTokenStream, Language, LowerCaser, NgramTokenizer, RemoveLongFilter, SimpleTokenizer,
+  Stemmer, TextAnalyzer, Tokenizer,
+};
+use tantivy::Index;
+use whatlang::Lang;
+
+use crate::config;
+
+/// Tokenizers which can be selected per field with `content_tokenizers` in config.toml.
+/// `default` and `en_stem` are tantivy built-ins.
+pub const TOKENIZERS: [&str; 6] = ["default", "simple", "jieba", "en_stem", "ngram", "multi_lang"];
+
+/// text fields of the content index whose tokenizer can be configured
+pub const TOKENIZED_FIELDS: [(&str, &str); 2] = [("name", "default"), ("body", "jieba")];
+
+/// tokenizer configured for a field, unknown names fall back to the field default
+pub fn configured_tokenizer(field: &str) -> String {
+  let default = TOKENIZED_FIELDS
+    .iter()
+    .find(|(f, _)| *f == field)
+    .map_or("default", |(_, t)| *t);
+  let configured = config!(content_tokenizers);
+  match configured.get(field) {
+    Some(t) if TOKENIZERS.contains(&t.as_str()) => t.clone(),
+    Some(t) => {
+      tracing::warn!("unknown tokenizer {t} for field {field}, use {default}");
+      default.to_owned()
+    }
+    None => default.to_owned(),
+  }
+}
+
+pub fn default_tokenizers() -> HashMap<String, String> {
+  TOKENIZED_FIELDS
+    .iter()
+    .map(|(f, t)| (f.to_string(), t.to_string()))
+    .collect()
+}
+
+/// Register every selectable tokenizer, so an index keeps working with the tokenizers
+/// it was built with after the configuration changes.
+pub fn register_tokenizers(index: &Index) {
+  let tokenizers = index.tokenizers();
+  tokenizers.register("jieba", tantivy_jieba::JiebaTokenizer {});
+  tokenizers.register(
+    "simple",
+    TextAnalyzer::from(SimpleTokenizer).filter(LowerCaser),
+  );
+  tokenizers.register(
+    "ngram",
+    TextAnalyzer::from(NgramTokenizer::new(2, 3, false)).filter(LowerCaser),
+  );
+  tokenizers.register("multi_lang", MultiLangTokenizer::new());
+}
+
+fn stemmer(language: Language) -> TextAnalyzer {
+  TextAnalyzer::from(SimpleTokenizer)
+    .filter(RemoveLongFilter::limit(40))
+    .filter(LowerCaser)
+    .filter(Stemmer::new(language))
+}
+
+/// Detects the language of the text and picks jieba for chinese and japanese,
+/// a snowball stemmer for languages tantivy supports and simple tokenizing otherwise.
+///
+/// Detection on short query strings is less reliable than on document bodies,
+/// so queries in a language different from the document may miss stemmed forms.
+#[derive(Clone)]
+pub struct MultiLangTokenizer {
+  cjk: TextAnalyzer,
+  fallback: TextAnalyzer,
+  stemmers: HashMap<Lang, TextAnalyzer>,
+}
+
+impl MultiLangTokenizer {
+  pub fn new() -> Self {
+    let languages = [
+      (Lang::Eng, Language::English),
+      (Lang::Fra, Language::French),
+      (Lang::Deu, Language::German),
+      (Lang::Spa, Language::Spanish),
+      (Lang::Por, Language::Portuguese),
+      (Lang::Ita, Language::Italian),
+      (Lang::Nld, Language::Dutch),
+      (Lang::Swe, Language::Swedish),
+      (Lang::Dan, Language::Danish),
+      (Lang::Fin, Language::Finnish),
+      (Lang::Hun, Language::Hungarian),
+      (Lang::Ron, Language::Romanian),
+      (Lang::Rus, Language::Russian),
+      (Lang::Tur, Language::Turkish),
+      (Lang::Ell, Language::Greek),
+      (Lang::Ara, Language::Arabic),
+      (Lang::Tam, Language::Tamil),
+      (Lang::Nob, Language::Norwegian),
+    ];
+    Self {
+      cjk: TextAnalyzer::from(tantivy_jieba::JiebaTokenizer {}),
+      fallback: TextAnalyzer::from(SimpleTokenizer).filter(LowerCaser),
+      stemmers: languages
+        .into_iter()
+        .map(|(lang, language)| (lang, stemmer(language)))
+        .collect(),
+    }
+  }
+}
+
+impl Tokenizer for MultiLangTokenizer {
+  fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+    let lang = whatlang::detect(text).map(|info| info.lang());
+    match lang {
+      Some(Lang::Cmn) | Some(Lang::Jpn) => self.cjk.token_stream(text),
+      Some(lang) => match self.stemmers.get(&lang) {
+        Some(analyzer) => analyzer.token_stream(text),
+        None => self.fallback.token_stream(text),
+      },
+      None => self.fallback.token_stream(text),
+    }
+  }
+}
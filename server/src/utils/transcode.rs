@@ -1,20 +1,223 @@
-use ffmpeg_cli_utils::FFMpeg;
-use tokio::io::AsyncRead;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-pub async fn ffmpeg_scale(file: &PathBuf, size: u32, bitrate: u32) -> impl AsyncRead {
-  let stream = scale(file, size, bitrate);
-  stream
+use actix_web::http::StatusCode;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+use crate::config;
+
+use super::error::AppError;
+
+/// a queued transcode gives up after waiting this long for a free slot
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+  static ref FFMPEG_AVAILABLE: AtomicBool = AtomicBool::new(false);
+  static ref FFPROBE_AVAILABLE: AtomicBool = AtomicBool::new(false);
+  static ref GLOBAL_SLOTS: Arc<Semaphore> = Arc::new(Semaphore::new(config!(transcode_max_concurrent).max(1)));
+  static ref USER_SLOTS: Mutex<HashMap<String, Arc<Semaphore>>> = Mutex::new(HashMap::new());
 }
 
-fn scale(file: &PathBuf, size: u32, bitrate: u32) -> impl AsyncRead {
-  let file = file.clone().canonicalize().unwrap();
-  FFMpeg::set_ffmpeg_bin("./ffmpeg");
-  let stream = FFMpeg::input(&file.to_string_lossy().to_string())
+fn check_bin(bin: &str) -> bool {
+  match std::process::Command::new(bin)
+    .arg("-version")
+    .stdin(Stdio::null())
     .output()
-    .resize(-2, size as i32)
-    .set_bitrate(bitrate as u64)
-    .stream()
-    .unwrap();
-  stream
+  {
+    Ok(output) if output.status.success() => {
+      let version = String::from_utf8_lossy(&output.stdout);
+      info!("found {}", version.lines().next().unwrap_or(bin));
+      true
+    }
+    Ok(output) => {
+      warn!("{bin} -version exited with {}", output.status);
+      false
+    }
+    Err(err) => {
+      warn!("can not run {bin}: {err}");
+      false
+    }
+  }
+}
+
+/// Run the configured ffmpeg and ffprobe once, transcoding and probing stay unavailable
+/// when they fail. Called at startup.
+pub fn check_ffmpeg() {
+  let transcode = config!(use_ffmpeg_trancode);
+  if !transcode && !config!(probe_media) {
+    return;
+  }
+  let ffprobe = check_bin(&config!(ffprobe_bin_path));
+  FFPROBE_AVAILABLE.store(ffprobe, Ordering::Relaxed);
+  if transcode {
+    let available = ffprobe && check_bin(&config!(ffmpeg_bin_path));
+    if !available {
+      warn!("transcoding is unavailable");
+    }
+    FFMPEG_AVAILABLE.store(available, Ordering::Relaxed);
+  }
+}
+
+/// whether ffprobe passed the startup check
+pub fn ffprobe_available() -> bool {
+  FFPROBE_AVAILABLE.load(Ordering::Relaxed)
+}
+
+/// Error out when transcoding is disabled in the config or ffmpeg did not pass the startup check.
+pub fn ensure_enabled() -> Result<(), AppError> {
+  if !config!(use_ffmpeg_trancode) {
+    return Err(AppError::new("transcoding is disabled").with_status(StatusCode::NOT_IMPLEMENTED));
+  }
+  if !FFMPEG_AVAILABLE.load(Ordering::Relaxed) {
+    return Err(AppError::new("ffmpeg is not available").with_status(StatusCode::SERVICE_UNAVAILABLE));
+  }
+  Ok(())
+}
+
+/// A slot of the global and the per-user transcode limit, released on drop.
+pub struct TranscodePermit {
+  _global: OwnedSemaphorePermit,
+  _user: OwnedSemaphorePermit,
+}
+
+fn user_slots(username: &str) -> Arc<Semaphore> {
+  USER_SLOTS
+    .lock()
+    .unwrap()
+    .entry(username.to_owned())
+    .or_insert_with(|| Arc::new(Semaphore::new(config!(transcode_max_per_user).max(1))))
+    .clone()
+}
+
+/// Wait for a free transcode slot, the per-user slot is taken first so one user
+/// queueing many videos does not hold global slots while waiting.
+pub async fn acquire(username: &str) -> Result<TranscodePermit, AppError> {
+  let user = user_slots(username);
+  let acquire = async move {
+    let user = user.acquire_owned().await.ok()?;
+    let global = GLOBAL_SLOTS.clone().acquire_owned().await.ok()?;
+    Some(TranscodePermit {
+      _global: global,
+      _user: user,
+    })
+  };
+  tokio::time::timeout(QUEUE_TIMEOUT, acquire)
+    .await
+    .ok()
+    .flatten()
+    .ok_or(AppError::new("too many transcodes are running, try again later").with_status(StatusCode::SERVICE_UNAVAILABLE))
+}
+
+/// Take a transcode slot if one is free right now.
+pub fn try_acquire(username: &str) -> Option<TranscodePermit> {
+  let user = user_slots(username).try_acquire_owned().ok()?;
+  let global = GLOBAL_SLOTS.clone().try_acquire_owned().ok()?;
+  Some(TranscodePermit {
+    _global: global,
+    _user: user,
+  })
+}
+
+/// Output of a running ffmpeg. Dropping it, e.g. when the client disconnects,
+/// kills ffmpeg and frees the transcode slot.
+pub struct TranscodeStream {
+  stdout: ChildStdout,
+  _child: Child,
+  _permit: TranscodePermit,
+}
+
+impl AsyncRead for TranscodeStream {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+    Pin::new(&mut self.stdout).poll_read(cx, buf)
+  }
+}
+
+/// Transcode `file` to a fragmented mp4 of `height` pixels and `bitrate` kbps, waits in the
+/// queue when `username` or the server is at the concurrency limit.
+pub async fn scale(file: &Path, height: u32, bitrate: u32, username: &str) -> Result<TranscodeStream, AppError> {
+  let scale = format!("scale=-2:{height}");
+  let bitrate = format!("{bitrate}k");
+  let output_args = [
+    &["-map", "0:v:0", "-map", "0:a:0?", "-sn", "-dn"][..],
+    &["-vf", &scale],
+    &["-c:v", "libx264", "-preset", "veryfast", "-pix_fmt", "yuv420p"],
+    &["-b:v", &bitrate],
+    &["-c:a", "aac", "-ac", "2"],
+    // mp4 needs a seekable output unless it is fragmented
+    &["-movflags", "frag_keyframe+empty_moov+default_base_moof", "-f", "mp4"],
+  ]
+  .concat();
+  run(file, &output_args, username).await
+}
+
+#[derive(Clone, Copy)]
+pub enum AudioCodec {
+  Opus,
+  Mp3,
+}
+
+impl AudioCodec {
+  pub fn parse(s: &str) -> Option<Self> {
+    match s {
+      "opus" => Some(Self::Opus),
+      "mp3" => Some(Self::Mp3),
+      _ => None,
+    }
+  }
+
+  pub fn mime(&self) -> &'static str {
+    match self {
+      Self::Opus => "audio/ogg",
+      Self::Mp3 => "audio/mpeg",
+    }
+  }
+}
+
+/// Transcode the audio of `file` to `codec` at `bitrate` kbps, queued like `scale`.
+pub async fn audio(file: &Path, codec: AudioCodec, bitrate: u32, username: &str) -> Result<TranscodeStream, AppError> {
+  let bitrate = format!("{}k", bitrate.clamp(32, 320));
+  let (encoder, format) = match codec {
+    AudioCodec::Opus => ("libopus", "ogg"),
+    AudioCodec::Mp3 => ("libmp3lame", "mp3"),
+  };
+  let output_args = ["-map", "0:a:0", "-vn", "-sn", "-dn", "-c:a", encoder, "-b:a", &bitrate, "-f", format];
+  run(file, &output_args, username).await
+}
+
+async fn run(file: &Path, output_args: &[&str], username: &str) -> Result<TranscodeStream, AppError> {
+  ensure_enabled()?;
+  if !file.is_file() {
+    return Err(AppError::new("file not found").with_status(StatusCode::NOT_FOUND));
+  }
+  let permit = acquire(username).await?;
+  let mut child = Command::new(config!(ffmpeg_bin_path))
+    .args(["-hide_banner", "-loglevel", "error", "-nostdin"])
+    .arg("-i")
+    .arg(file)
+    .args(output_args)
+    .arg("pipe:1")
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .kill_on_drop(true)
+    .spawn()?;
+  let stdout = child
+    .stdout
+    .take()
+    .ok_or(AppError::new("can not read the output of ffmpeg"))?;
+  Ok(TranscodeStream {
+    stdout,
+    _child: child,
+    _permit: permit,
+  })
 }
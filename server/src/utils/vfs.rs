@@ -1,3 +1,4 @@
+use actix_web::http::StatusCode;
 use actix_web::web::block;
 use async_zip::error::ZipError;
 use async_zip::write::ZipFileWriter;
@@ -7,60 +8,119 @@ use diesel::{sql_query, RunQueryDsl};
 use lazy_static::lazy_static;
 use serde::Serialize;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::hash::Hash;
-use std::io::Cursor;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path};
 use std::rc::Rc;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::UNIX_EPOCH;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fs::Metadata, io, path::PathBuf};
-use tantivy::Document;
 use tokio::fs::{self, File};
-use tokio::io::{duplex, AsyncRead, AsyncSeekExt, DuplexStream};
+use tokio::io::{duplex, AsyncSeekExt, DuplexStream};
 use tokio_util::io::ReaderStream;
+use tracing::warn;
 
 use crate::{config, conv_err};
 use crate::db::SHARED_DB_CONN;
 use crate::models::{FileIndex, FileIndexSizeCount};
-use crate::schedulers::update_file_index::UpdateGalleryJob;
+use crate::schedulers::update_file_index::{UpdateGalleryJob, JOB_UPDATE_GALLERY};
 
+use super::archive::ARCHIVE_SEPARATOR;
 use super::error::AppError;
-use super::eventbus::EventEmitter;
+use super::eventbus::EventBus;
+use super::filename_index::{self, FilenameHit};
 use super::path::secure_join;
-use super::search_engine::search_docs;
+use super::push::{publish, PushMessage};
+use super::saved_search;
+use super::search_engine::{search_docs, SearchOptions, SearchResult};
 use super::stream::RangeStream;
-use super::transcode::ffmpeg_scale;
+use super::thumbnail;
+use super::transcode::{self, TranscodeStream};
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
-pub enum FSHookType {
-  AddFile,
-  DeleteFile,
+/// directory under each user root holding files moved to the trash, it is not indexed
+pub const TRASH_DIR: &str = ".trash";
+
+/// Trash directories of all users, relative to file root. Directories named like the trash
+/// deeper in the tree are ordinary directories.
+pub fn trash_dirs() -> Result<HashSet<PathBuf>, AppError> {
+  use crate::schema::users::dsl::*;
+  use diesel::prelude::*;
+  let mut conn = SHARED_DB_CONN.lock().unwrap();
+  let roots: Vec<String> = users.select(user_root).load(&mut *conn)?;
+  Ok(roots.iter().map(|r| Path::new(r).join(TRASH_DIR)).collect())
 }
 
-#[derive(Debug, Clone)]
-pub struct FSHookPayload(pub Vec<String>);
+/// File system events, paths are relative to file root (they include the user root).
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FsEvent {
+  Create { files: Vec<String> },
+  Modify { files: Vec<String> },
+  Rename { from: String, to: String },
+  Delete { files: Vec<String> },
+  Read { file: String },
+}
+
+impl FsEvent {
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::Create { .. } => "create",
+      Self::Modify { .. } => "modify",
+      Self::Rename { .. } => "rename",
+      Self::Delete { .. } => "delete",
+      Self::Read { .. } => "read",
+    }
+  }
+
+  /// all paths touched by the event, for rename the old path comes first
+  pub fn files(&self) -> Vec<String> {
+    match self {
+      Self::Create { files } | Self::Modify { files } | Self::Delete { files } => files.clone(),
+      Self::Rename { from, to } => vec![from.clone(), to.clone()],
+      Self::Read { file } => vec![file.clone()],
+    }
+  }
+}
 
 lazy_static! {
-  pub static ref FS_HOOK: Arc<Mutex<EventEmitter<FSHookType, FSHookPayload>>> = {
-    let mut ev = EventEmitter::<FSHookType, FSHookPayload>::new();
-
-    ev.listen(FSHookType::AddFile, |payload| {
-      let file_root = PathBuf::from_str(&config!(file_root)).unwrap();
-      thread::spawn(move || {
-        UpdateGalleryJob::update_file_indices(payload.0, &file_root).unwrap()
-      });
-    });
+  pub static ref FS_EVENTS: EventBus<FsEvent> = EventBus::new("fs_events", 1024);
+}
 
-    ev.listen(FSHookType::DeleteFile, |payload| {
-      thread::spawn(move || {
-        UpdateGalleryJob::delete_file_indices(payload.0).unwrap();
-      });
-    });
-    Arc::new(Mutex::new(ev))
-  };
+/// Register built-in subscribers of [`FS_EVENTS`], called once at startup.
+pub fn subscribe_fs_events() {
+  FS_EVENTS.subscribe_with_resync(
+    "file_index",
+    |ev| async move {
+      let file_root = PathBuf::from_str(&config!(file_root))?;
+      block(move || match ev {
+        FsEvent::Create { files } | FsEvent::Modify { files } => {
+          UpdateGalleryJob::update_file_indices(files, &file_root)
+        }
+        FsEvent::Rename { from, to } => {
+          UpdateGalleryJob::delete_file_indices(vec![from])?;
+          UpdateGalleryJob::update_file_indices(vec![to], &file_root)
+        }
+        FsEvent::Delete { files } => UpdateGalleryJob::delete_file_indices(files),
+        FsEvent::Read { .. } => Ok(()),
+      })
+      .await?
+    },
+    // the skipped events are unknown, a full run brings the index back in line with the disk
+    |_| JOB_UPDATE_GALLERY.lock().unwrap().update_immediate(),
+  );
+
+  FS_EVENTS.subscribe("push", |ev| async move {
+    match ev {
+      FsEvent::Create { files } => publish(PushMessage::FileAdded { files }),
+      FsEvent::Modify { files } => publish(PushMessage::FileModified { files }),
+      FsEvent::Rename { from, to } => {
+        publish(PushMessage::FileDeleted { files: vec![from] });
+        publish(PushMessage::FileAdded { files: vec![to] });
+      }
+      FsEvent::Delete { files } => publish(PushMessage::FileDeleted { files }),
+      FsEvent::Read { .. } => (),
+    }
+    Ok(())
+  });
 }
 
 pub async fn read_dir(
@@ -69,11 +129,17 @@ pub async fn read_dir(
   dir: &str,
 ) -> Result<Vec<FileStatWithName>, AppError> {
   let odir = PathBuf::from(dir);
+  let is_root = odir
+    .components()
+    .all(|c| matches!(c, Component::CurDir | Component::RootDir));
   let dir = normailze_path(file_root, user_root, dir)?;
   let mut result = fs::read_dir(&dir).await?;
   let mut files_in_dir: Vec<FileStatWithName> = vec![];
   while let Result::Ok(Option::Some(dir_entry)) = result.next_entry().await {
     let filename = dir_entry.file_name().to_string_lossy().into_owned();
+    if is_root && filename == TRASH_DIR {
+      continue;
+    }
     let file_path = odir.join(&filename);
     let file_path = file_path.to_str().map_or("", |v| v);
     let file_stat = stat(file_root, user_root, file_path).await?;
@@ -83,32 +149,52 @@ pub async fn read_dir(
   Ok(files_in_dir)
 }
 
-#[allow(unused)]
+/// Read an image, resized through the thumbnail cache when `resize` is set.
+/// Images which can not be decoded are returned as they are.
 pub async fn read_image(
   file_root: PathBuf,
   user_root: String,
   file: String,
   resize: Option<u32>,
-) -> Result<Vec<u8>, AppError> {
-  let dir = normailze_path(&file_root, &user_root, &file)?;
-
-  let result = fs::read(dir).await?;
+) -> Result<ImageData, AppError> {
+  let path = normailze_path(&file_root, &user_root, &file)?;
   if let Some(resize) = resize {
-    let buf = block(move || {
-      let img = image::io::Reader::new(Cursor::new(&result))
-        .with_guessed_format()
-        .unwrap()
-        .decode()
-        .unwrap();
-      let img = img.thumbnail(resize, resize);
-      let mut buf = Vec::new();
-      img.write_to(&mut Cursor::new(&mut buf), image::ImageOutputFormat::Png);
-      buf
-    })
-    .await?;
-    return Ok(buf);
+    let rel = path.strip_prefix(&file_root)?.to_string_lossy().to_string();
+    let entry = block(move || thumbnail::cache_entry(&file_root, &rel, resize)).await??;
+    let etag = entry.etag.clone();
+    let mime = entry.mime.to_owned();
+    match block(move || thumbnail::load(&entry)).await? {
+      Ok(data) => {
+        return Ok(ImageData {
+          data,
+          mime: Some(mime),
+          etag: Some(etag),
+        })
+      }
+      Err(err) => warn!("fail to create thumbnail of {file}: {err}"),
+    }
   }
-  Ok(result)
+  let data = fs::read(&path).await?;
+  let mime = mime_guess::from_path(&file).first().map(|m| m.to_string());
+  Ok(ImageData {
+    data,
+    mime,
+    etag: None,
+  })
+}
+
+/// Etag of the thumbnail `read_image` would return, to answer conditional requests
+/// without reading the image.
+pub async fn thumbnail_etag(
+  file_root: PathBuf,
+  user_root: String,
+  file: String,
+  resize: u32,
+) -> Result<String, AppError> {
+  let path = normailze_path(&file_root, &user_root, &file)?;
+  let rel = path.strip_prefix(&file_root)?.to_string_lossy().to_string();
+  let entry = block(move || thumbnail::cache_entry(&file_root, &rel, resize)).await??;
+  Ok(entry.etag)
 }
 
 pub async fn stat(file_root: &PathBuf, user_root: &str, file: &str) -> Result<FileStat, AppError> {
@@ -123,24 +209,23 @@ pub async fn create(
   file: String,
   buffer: Vec<u8>,
 ) -> Result<(), AppError> {
-  let dir = normailze_path(&file_root, &user_root, &file)?;
+  let dir = writable_path(&file_root, &user_root, &file)?;
   let parent = Path::new(&file).parent().unwrap_or(dir.as_path());
   fs::create_dir_all(parent).await?;
   Ok(fs::write(file, buffer).await?)
 }
 
 pub async fn delete(file_root: &PathBuf, user_root: &str, file: &str) -> Result<(), AppError> {
-  let dir = normailze_path(file_root, &user_root, &file)?;
+  let dir = writable_path(file_root, user_root, file)?;
   let path_stat = stat(file_root, user_root, file).await?;
   if path_stat.is_dir {
     fs::remove_dir_all(&dir).await?;
   } else {
     fs::remove_file(&dir).await?;
   }
-  FS_HOOK.lock().unwrap().emit(
-    FSHookType::DeleteFile,
-    FSHookPayload(vec![rel_join(user_root, file)?]),
-  );
+  FS_EVENTS.emit(FsEvent::Delete {
+    files: vec![rel_join(user_root, file)?],
+  });
   Ok(())
 }
 
@@ -151,7 +236,7 @@ pub async fn delete_batch(
 ) -> Result<(), AppError> {
   let mut flist = vec![];
   for file in files {
-    let dir = normailze_path(&file_root, &user_root, &file)?;
+    let dir = writable_path(file_root, user_root, &file)?;
     let path_stat = stat(file_root, user_root, &file).await?;
     if path_stat.is_dir {
       fs::remove_dir_all(&dir).await?;
@@ -160,10 +245,66 @@ pub async fn delete_batch(
     }
     flist.push(rel_join(user_root, &file)?);
   }
-  FS_HOOK
-    .lock()
-    .unwrap()
-    .emit(FSHookType::DeleteFile, FSHookPayload(flist));
+  FS_EVENTS.emit(FsEvent::Delete { files: flist });
+  Ok(())
+}
+
+/// Move files into `.trash/<millis>/` under the user root, keeping their paths
+/// so they can be restored by moving them back. When a file fails, the files moved
+/// before it stay in the trash and are reported as deleted.
+pub async fn move_to_trash(
+  file_root: &PathBuf,
+  user_root: &str,
+  files: &[String],
+) -> Result<(), AppError> {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+  let trash = Path::new(TRASH_DIR).join(now.to_string());
+  let mut flist = vec![];
+  let mut result = Ok(());
+  for file in files {
+    match trash_file(file_root, user_root, &trash, file).await {
+      Ok(rel_path) => flist.push(rel_path),
+      Err(err) => {
+        result = Err(err);
+        break;
+      }
+    }
+  }
+  if !flist.is_empty() {
+    FS_EVENTS.emit(FsEvent::Delete { files: flist });
+  }
+  result
+}
+
+/// returns the path of the moved file relative to file root
+async fn trash_file(file_root: &PathBuf, user_root: &str, trash: &Path, file: &str) -> Result<String, AppError> {
+  let rel_path = rel_join(user_root, file)?;
+  let src = writable_path(file_root, user_root, file)?;
+  let dst = normailze_path(file_root, user_root, &trash.join(file).to_string_lossy())?;
+  if let Some(parent) = dst.parent() {
+    fs::create_dir_all(parent).await?;
+  }
+  fs::rename(&src, &dst).await?;
+  Ok(rel_path)
+}
+
+/// Rename or move `from` to `to`, both relative to the user root. Missing parent
+/// directories of `to` are created, an existing `to` is not replaced.
+pub async fn rename(file_root: &PathBuf, user_root: &str, from: &str, to: &str) -> Result<(), AppError> {
+  let src = writable_path(file_root, user_root, from)?;
+  let dst = writable_path(file_root, user_root, to)?;
+  fs::metadata(&src).await?;
+  if fs::metadata(&dst).await.is_ok() {
+    return Err(AppError::new("target already exists").with_status(StatusCode::CONFLICT));
+  }
+  if let Some(parent) = dst.parent() {
+    fs::create_dir_all(parent).await?;
+  }
+  fs::rename(&src, &dst).await?;
+  FS_EVENTS.emit(FsEvent::Rename {
+    from: rel_join(user_root, from)?,
+    to: rel_join(user_root, to)?,
+  });
   Ok(())
 }
 
@@ -173,39 +314,34 @@ pub async fn read_video_transform_stream(
   file: String,
   resize: Option<u32>,
   bitrate: Option<u32>,
-) -> Result<impl AsyncRead, AppError> {
+  username: &str,
+) -> Result<TranscodeStream, AppError> {
   let dir = normailze_path(&file_root, &user_root, &file)?;
   let resize = resize.map_or(720, |v| v);
   let bitrate = bitrate.map_or(2000, |v| v);
-  let stream = ffmpeg_scale(&dir, resize, bitrate).await;
+  let stream = transcode::scale(&dir, resize, bitrate, username).await?;
   Ok(stream)
 }
 
 pub async fn create_dir(file_root: &PathBuf, user_root: &str, file: &str) -> Result<(), AppError> {
-  let dir = normailze_path(&file_root, &user_root, &file)?;
+  let dir = writable_path(file_root, user_root, file)?;
   let result = fs::create_dir(&dir).await?;
-  FS_HOOK.lock().unwrap().emit(
-    FSHookType::AddFile,
-    FSHookPayload(vec![rel_join(user_root, file)?]),
-  );
+  FS_EVENTS.emit(FsEvent::Create {
+    files: vec![rel_join(user_root, file)?],
+  });
   Ok(result)
 }
 
-pub async fn search_in_index(kw: &str, limit: i64) -> Result<Vec<FileIndex>, AppError> {
-  use crate::schema::file_index::dsl::*;
-  use diesel::prelude::*;
-
-  let mut conn = SHARED_DB_CONN.lock().unwrap();
-  let conn = &mut *conn;
-  let result = file_index
-    .filter(file_name.like(format!("%{kw}%")))
-    .limit(limit)
-    .load::<FileIndex>(conn)?;
+/// Filename search under `folder`, relative to file root.
+pub async fn search_in_index(kw: &str, folder: &str, limit: usize) -> Result<Vec<FilenameHit>, AppError> {
+  let kw = kw.to_owned();
+  let folder = folder.to_owned();
+  let result = block(move || filename_index::search(&kw, Some(&folder), limit)).await??;
   Ok(result)
 }
 
-pub fn search_in_tantivy(kw: &str) -> Result<Vec<Document>, AppError> {
-  let docs = search_docs(kw)?;
+pub fn search_in_tantivy(kw: &str, options: &SearchOptions) -> Result<SearchResult, AppError> {
+  let docs = search_docs(kw, options)?;
   Ok(docs)
 }
 
@@ -251,6 +387,13 @@ impl FileStatWithName {
   }
 }
 
+pub struct ImageData {
+  pub data: Vec<u8>,
+  pub mime: Option<String>,
+  /// set for thumbnails
+  pub etag: Option<String>,
+}
+
 pub fn convert_meta_to_struct(meta: Metadata) -> Result<FileStat, AppError> {
   Ok(FileStat {
     is_dir: meta.is_dir(),
@@ -282,8 +425,10 @@ pub async fn storage_info_group_by_file_mime(
 ) -> Result<Vec<FileIndexSizeCount>, AppError> {
   let conn = &mut *SHARED_DB_CONN.lock().unwrap();
 
-  let r = sql_query("select sum(size), size, format, is_dir, username from file_index where username = ? group by format;")
+  // entries of archives are counted by the archive itself
+  let r = sql_query("select sum(size), size, format, is_dir, username from file_index where username = ? and instr(file_path, ?) = 0 group by format;")
     .bind::<Text, _>(username_)
+    .bind::<Text, _>(ARCHIVE_SEPARATOR)
     .load::<FileIndexSizeCount>(conn)
     .unwrap();
   Ok(r)
@@ -322,9 +467,16 @@ pub fn ensure_parent_dir_sync(file: &PathBuf) -> Result<(), AppError> {
 
 fn normailze_path(file_root: &PathBuf, user_root: &str, file: &str) -> Result<PathBuf, AppError> {
   let user_abs_root = file_root.join(user_root);
+  let file = saved_search::resolve_virtual_path(file).unwrap_or_else(|| file.to_owned());
   Ok(secure_join(&user_abs_root, &PathBuf::from(file))?)
 }
 
+/// like `normailze_path`, but smart folders are read-only
+fn writable_path(file_root: &PathBuf, user_root: &str, file: &str) -> Result<PathBuf, AppError> {
+  saved_search::ensure_writable(file)?;
+  normailze_path(file_root, user_root, file)
+}
+
 pub async fn zip_path_to_stream(
   base: &PathBuf,
   file: &PathBuf,
@@ -0,0 +1,330 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use actix_web::http::StatusCode;
+use image::{imageops, DynamicImage, ImageFormat, RgbImage};
+use lazy_static::lazy_static;
+use mime_guess::mime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::config;
+
+use super::archive::ARCHIVE_SEPARATOR;
+use super::error::AppError;
+use super::ffprobe;
+use super::thumbnail::{self, MAX_DIMENSION, MIN_DIMENSION};
+use super::transcode;
+
+/// poster size when the client does not ask for one
+pub const DEFAULT_POSTER_DIMENSION: u32 = 720;
+/// part of the cache key, bump it when previews of the same video change
+const CACHE_VERSION: u32 = 1;
+/// the poster is taken at this part of the video, early frames are often black
+const POSTER_POSITION: f64 = 0.1;
+const POSTER_MAX_SECONDS: f64 = 30.0;
+const SPRITE_TILE_WIDTH: u32 = 160;
+const SPRITE_COLUMNS: u32 = 10;
+const SPRITE_MAX_TILES: u32 = 100;
+/// seconds between sprite tiles of short videos
+const SPRITE_MIN_INTERVAL: f64 = 2.0;
+
+lazy_static! {
+  static ref PREGENERATE: Mutex<Sender<(PathBuf, Vec<String>)>> = Mutex::new(spawn_pregenerate_worker());
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PreviewKind {
+  Poster,
+  Sprite,
+}
+
+pub struct PreviewEntry {
+  /// original video
+  pub source: PathBuf,
+  pub cache_file: PathBuf,
+  /// tile positions of a sprite, written next to it
+  pub layout_file: PathBuf,
+  pub etag: String,
+  pub mime: &'static str,
+  pub kind: PreviewKind,
+  pub dimension: u32,
+}
+
+/// Position of the tiles in a sprite sheet, needed to write the WebVTT track.
+#[derive(Serialize, Deserialize)]
+pub struct SpriteLayout {
+  pub duration: f64,
+  /// seconds between tiles
+  pub interval: f64,
+  pub count: u32,
+  pub columns: u32,
+  pub tile_width: u32,
+  pub tile_height: u32,
+}
+
+pub fn is_video(file: &str) -> bool {
+  mime_guess::from_path(file)
+    .first()
+    .is_some_and(|m| m.type_() == mime::VIDEO)
+}
+
+/// Locate the preview of `file` (relative to file root) in the thumbnail cache, keyed like
+/// image thumbnails so a changed video gets new previews. `dimension` only applies to posters.
+pub fn cache_entry(file_root: &Path, file: &str, kind: PreviewKind, dimension: u32) -> Result<PreviewEntry, AppError> {
+  let dimension = match kind {
+    PreviewKind::Poster => dimension.clamp(MIN_DIMENSION, MAX_DIMENSION),
+    PreviewKind::Sprite => SPRITE_TILE_WIDTH,
+  };
+  let source = file_root.join(file);
+  let meta = fs::metadata(&source)?;
+  let modified = meta.modified()?.duration_since(UNIX_EPOCH)?.as_millis();
+  let (ext, mime) = thumbnail::output_format();
+  let kind_name = match kind {
+    PreviewKind::Poster => "poster",
+    PreviewKind::Sprite => "sprite",
+  };
+  let mut hasher = Sha256::new();
+  hasher.update(format!(
+    "video-{CACHE_VERSION}\n{file}\n{modified}\n{}\n{kind_name}\n{dimension}\n{ext}",
+    meta.len()
+  ));
+  let key = hex::encode(hasher.finalize());
+  Ok(PreviewEntry {
+    source,
+    cache_file: thumbnail::cache_path(&key, ext),
+    layout_file: thumbnail::cache_path(&key, "json"),
+    etag: format!("\"{key}\""),
+    mime,
+    kind,
+    dimension,
+  })
+}
+
+fn run_ffmpeg(args: &[&str], source: &Path, output_args: &[&str]) -> Result<Vec<u8>, AppError> {
+  let output = Command::new(config!(ffmpeg_bin_path))
+    .args(["-hide_banner", "-loglevel", "error", "-nostdin"])
+    .args(args)
+    .arg("-i")
+    .arg(source)
+    .args(["-an", "-sn", "-dn"])
+    .args(output_args)
+    .arg("pipe:1")
+    .stdin(Stdio::null())
+    .output()?;
+  if !output.status.success() {
+    let err = String::from_utf8_lossy(&output.stderr);
+    return Err(AppError::new(&format!("ffmpeg failed: {}", err.trim())));
+  }
+  Ok(output.stdout)
+}
+
+fn extract_poster(source: &Path) -> Result<DynamicImage, AppError> {
+  let probe = ffprobe::probe(source)?;
+  let position = probe
+    .duration()
+    .map_or(0.0, |d| (d * POSTER_POSITION).min(POSTER_MAX_SECONDS));
+  let output_args = ["-frames:v", "1", "-f", "image2pipe", "-c:v", "png"];
+  let mut data = run_ffmpeg(&["-ss", &format!("{position:.3}")], source, &output_args)?;
+  // a wrong duration seeks past the end, fall back to the first frame
+  if data.is_empty() && position > 0.0 {
+    data = run_ffmpeg(&[], source, &output_args)?;
+  }
+  if data.is_empty() {
+    return Err(AppError::new("video has no frames"));
+  }
+  Ok(image::load_from_memory_with_format(&data, ImageFormat::Png)?)
+}
+
+fn extract_sprite(source: &Path) -> Result<(DynamicImage, SpriteLayout), AppError> {
+  let probe = ffprobe::probe(source)?;
+  let duration = probe
+    .duration()
+    .ok_or(AppError::new("can not read the duration of the video"))?;
+  let stream = probe
+    .video_stream()
+    .ok_or(AppError::new("file has no video stream"))?;
+  let (width, height) = (stream.width.unwrap_or(16), stream.height.unwrap_or(9));
+  let tile_width = SPRITE_TILE_WIDTH;
+  let tile_height = (tile_width as u64 * height as u64 / width.max(1) as u64) as u32 / 2 * 2;
+  let tile_height = tile_height.clamp(2, tile_width * 4);
+  let interval = (duration / SPRITE_MAX_TILES as f64).max(SPRITE_MIN_INTERVAL);
+  let count = ((duration / interval).ceil() as u32).clamp(1, SPRITE_MAX_TILES);
+
+  // rotated videos do not match the probed size, they are fitted into the tile
+  let filter = format!(
+    "fps=1/{interval:.3},scale={tile_width}:{tile_height}:force_original_aspect_ratio=decrease,\
+    pad={tile_width}:{tile_height}:(ow-iw)/2:(oh-ih)/2"
+  );
+  // only keyframes are decoded, close enough for seek previews and much faster
+  let data = run_ffmpeg(
+    &["-skip_frame", "nokey"],
+    source,
+    &["-vf", &filter, "-frames:v", &count.to_string(), "-f", "rawvideo", "-pix_fmt", "rgb24"],
+  )?;
+  let frame_size = (tile_width * tile_height * 3) as usize;
+  let frames: Vec<&[u8]> = data.chunks_exact(frame_size).collect();
+  if frames.is_empty() {
+    return Err(AppError::new("video has no frames"));
+  }
+  let count = frames.len() as u32;
+  let columns = SPRITE_COLUMNS.min(count);
+  let rows = count.div_ceil(columns);
+  let mut sprite = RgbImage::new(columns * tile_width, rows * tile_height);
+  for (i, frame) in frames.into_iter().enumerate() {
+    let tile = RgbImage::from_raw(tile_width, tile_height, frame.to_vec())
+      .ok_or(AppError::new("invalid frame from ffmpeg"))?;
+    let (x, y) = ((i as u32 % columns) * tile_width, (i as u32 / columns) * tile_height);
+    imageops::replace(&mut sprite, &tile, x as i64, y as i64);
+  }
+  let layout = SpriteLayout {
+    duration,
+    interval,
+    count,
+    columns,
+    tile_width,
+    tile_height,
+  };
+  Ok((DynamicImage::ImageRgb8(sprite), layout))
+}
+
+fn generate(entry: &PreviewEntry) -> Result<Vec<u8>, AppError> {
+  transcode::ensure_enabled()?;
+  let data = match entry.kind {
+    PreviewKind::Poster => {
+      let img = extract_poster(&entry.source)?;
+      thumbnail::encode(&img.thumbnail(entry.dimension, entry.dimension), entry.mime)?
+    }
+    PreviewKind::Sprite => {
+      let (img, layout) = extract_sprite(&entry.source)?;
+      let layout = serde_json::to_vec(&layout).map_err(|e| AppError::new(&e.to_string()))?;
+      thumbnail::store(&entry.layout_file, &layout)?;
+      thumbnail::encode(&img, entry.mime)?
+    }
+  };
+  thumbnail::store(&entry.cache_file, &data)?;
+  Ok(data)
+}
+
+/// whether `load` would have to run ffmpeg
+pub fn is_cached(entry: &PreviewEntry) -> bool {
+  entry.cache_file.exists() && (entry.kind == PreviewKind::Poster || entry.layout_file.exists())
+}
+
+/// Read the cached preview, it is created when missing.
+pub fn load(entry: &PreviewEntry) -> Result<Vec<u8>, AppError> {
+  if is_cached(entry) {
+    if let Ok(data) = fs::read(&entry.cache_file) {
+      return Ok(data);
+    }
+  }
+  generate(entry)
+}
+
+fn timestamp(seconds: f64) -> String {
+  let millis = (seconds * 1000.0).round() as u64;
+  format!(
+    "{:02}:{:02}:{:02}.{:03}",
+    millis / 3_600_000,
+    millis / 60_000 % 60,
+    millis / 1000 % 60,
+    millis % 1000
+  )
+}
+
+/// WebVTT thumbnails track pointing into the sprite sheet at `sprite_url`, the sprite
+/// is created when missing.
+pub fn thumbnails_vtt(entry: &PreviewEntry, sprite_url: &str) -> Result<String, AppError> {
+  if !is_cached(entry) {
+    generate(entry)?;
+  }
+  let layout = fs::read(&entry.layout_file)?;
+  let layout: SpriteLayout = serde_json::from_slice(&layout).map_err(|e| AppError::new(&e.to_string()))?;
+  let mut vtt = "WEBVTT\n\n".to_owned();
+  for i in 0..layout.count {
+    let start = i as f64 * layout.interval;
+    let end = if i + 1 == layout.count {
+      layout.duration.max(start)
+    } else {
+      start + layout.interval
+    };
+    let x = (i % layout.columns) * layout.tile_width;
+    let y = (i / layout.columns) * layout.tile_height;
+    let _ = write!(
+      vtt,
+      "{} --> {}\n{sprite_url}#xywh={x},{y},{},{}\n\n",
+      timestamp(start),
+      timestamp(end),
+      layout.tile_width,
+      layout.tile_height
+    );
+  }
+  Ok(vtt)
+}
+
+fn spawn_pregenerate_worker() -> Sender<(PathBuf, Vec<String>)> {
+  let (tx, rx) = channel::<(PathBuf, Vec<String>)>();
+  thread::Builder::new()
+    .name("video-preview-pregenerate".to_owned())
+    .spawn(move || {
+      for (file_root, files) in rx {
+        let sizes = config!(thumbnail_pregenerate_sizes);
+        for file in files {
+          let entries = sizes
+            .iter()
+            .map(|size| cache_entry(&file_root, &file, PreviewKind::Poster, *size))
+            .chain([cache_entry(&file_root, &file, PreviewKind::Sprite, 0)]);
+          for entry in entries {
+            let r = entry.and_then(|entry| {
+              if !is_cached(&entry) {
+                generate(&entry)?;
+              }
+              Ok(())
+            });
+            if let Err(err) = r {
+              warn!("fail to create preview of {file}: {err}");
+              break;
+            }
+          }
+        }
+      }
+    })
+    .unwrap();
+  tx
+}
+
+/// Create posters of the configured thumbnail sizes and sprite sheets for `files` (relative
+/// to file root) in the background, files which are not videos are skipped.
+pub fn pregenerate(file_root: &Path, files: &[String]) {
+  if !config!(video_preview_pregenerate) || transcode::ensure_enabled().is_err() {
+    return;
+  }
+  let videos: Vec<String> = files
+    .iter()
+    .filter(|f| !f.contains(ARCHIVE_SEPARATOR) && is_video(f))
+    .cloned()
+    .collect();
+  if videos.is_empty() {
+    return;
+  }
+  PREGENERATE
+    .lock()
+    .unwrap()
+    .send((file_root.to_path_buf(), videos))
+    .unwrap_or_else(|err| warn!("video preview worker stopped: {err}"));
+}
+
+/// Error for files the previews can not be made of.
+pub fn ensure_video(file: &str) -> Result<(), AppError> {
+  if !is_video(file) {
+    return Err(AppError::new("file is not a video").with_status(StatusCode::BAD_REQUEST));
+  }
+  Ok(())
+}
@@ -0,0 +1,517 @@
+use std::{
+  collections::HashMap,
+  net::{IpAddr, SocketAddr},
+  path::Path,
+  sync::{Arc, RwLock},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{http::StatusCode, web::block};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::{
+  conv_err,
+  db::SHARED_DB_CONN,
+  models::{NewWebhook, NewWebhookDelivery, Webhook, WebhookDelivery},
+};
+
+use super::{
+  error::AppError,
+  vfs::{FsEvent, FS_EVENTS},
+};
+
+pub const WEBHOOK_EVENT_TYPES: [&str; 5] = ["create", "modify", "rename", "delete", "read"];
+const MAX_ATTEMPTS: i32 = 5;
+const INITIAL_BACKOFF_SECS: u64 = 2;
+/// the tests deliver to a stub receiver on the loopback address
+const ALLOW_PRIVATE_TARGETS: bool = cfg!(test);
+
+lazy_static! {
+  // redirects could lead to addresses `validate_url` rejects
+  static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+    .timeout(Duration::from_secs(10))
+    .redirect(reqwest::redirect::Policy::none())
+    .dns_resolver(Arc::new(PublicResolver))
+    .build()
+    .unwrap();
+  /// cached webhooks, loaded on the first event after a change
+  static ref HOOKS: RwLock<Option<Hooks>> = RwLock::new(None);
+}
+
+conv_err!(reqwest::Error);
+
+type HmacSha256 = Hmac<Sha256>;
+/// webhooks with the root of their owner
+type Hooks = Arc<Vec<(Webhook, String)>>;
+
+/// JSON body posted to the webhook url, paths are relative to the owner's root
+#[derive(Serialize)]
+pub struct WebhookPayload<'a> {
+  pub delivery_id: String,
+  pub webhook_id: i32,
+  pub event: &'a str,
+  pub files: Vec<String>,
+  pub timestamp: u128,
+}
+
+impl Webhook {
+  fn accepts(&self, event: &str) -> bool {
+    self.event_types.is_empty() || self.event_types.split(',').any(|t| t == event)
+  }
+}
+
+pub fn sign(secret: &str, body: &str) -> String {
+  let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac accepts any key size");
+  mac.update(body.as_bytes());
+  hex::encode(mac.finalize().into_bytes())
+}
+
+fn now_millis() -> u128 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_millis())
+}
+
+/// Register the webhook dispatcher on [`FS_EVENTS`], called once at startup.
+pub fn subscribe_webhooks() {
+  FS_EVENTS.subscribe("webhook", |ev| async move { dispatch(ev).await });
+}
+
+/// Drop the cached webhooks, called whenever one is created or deleted.
+fn invalidate_hooks() {
+  *HOOKS.write().unwrap() = None;
+}
+
+/// Load the webhooks into the cache. Stored under the db lock, which create and delete
+/// hold while invalidating, so an older list never replaces a newer invalidation.
+fn load_hooks() -> Result<Hooks, AppError> {
+  use crate::schema::{users, webhooks};
+  use diesel::prelude::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let hooks = webhooks::table.load::<Webhook>(conn)?;
+  let roots: HashMap<String, String> = users::table
+    .select((users::username, users::user_root))
+    .load::<(String, String)>(conn)?
+    .into_iter()
+    .collect();
+  let hooks: Hooks = Arc::new(
+    hooks
+      .into_iter()
+      .filter_map(|h| roots.get(&h.username).cloned().map(|r| (h, r)))
+      .collect(),
+  );
+  *HOOKS.write().unwrap() = Some(hooks.clone());
+  Ok(hooks)
+}
+
+async fn cached_hooks() -> Result<Hooks, AppError> {
+  let cached = HOOKS.read().unwrap().clone();
+  match cached {
+    Some(hooks) => Ok(hooks),
+    None => block(load_hooks).await?,
+  }
+}
+
+async fn dispatch(ev: FsEvent) -> Result<(), AppError> {
+  let event = ev.kind();
+  let hooks = cached_hooks().await?;
+  // most events, e.g. every read, have no webhook at all
+  if !hooks.iter().any(|(h, _)| h.accepts(event)) {
+    return Ok(());
+  }
+  let files = ev.files();
+
+  for (hook, user_root) in hooks.iter() {
+    if !hook.accepts(event) {
+      continue;
+    }
+    let matched: Vec<String> = files
+      .iter()
+      .filter_map(|f| Path::new(f).strip_prefix(user_root).ok())
+      .filter(|f| f.starts_with(&hook.path_prefix))
+      .map(|f| f.to_string_lossy().to_string())
+      .collect();
+    if matched.is_empty() {
+      continue;
+    }
+    let payload = WebhookPayload {
+      delivery_id: uuid::Uuid::new_v4().to_string(),
+      webhook_id: hook.id,
+      event,
+      files: matched,
+      timestamp: now_millis(),
+    };
+    let body = serde_json::to_string(&payload).map_err(|e| AppError::new(&e.to_string()))?;
+    let delivery_id = payload.delivery_id;
+    let hook = hook.clone();
+    tokio::spawn(async move {
+      let record = deliver(&hook, event, &delivery_id, body, MAX_ATTEMPTS).await;
+      if let Err(e) = save_delivery(record).await {
+        warn!("fail to save webhook delivery: {e}");
+      }
+    });
+  }
+  Ok(())
+}
+
+fn is_private_address(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+    IpAddr::V6(ip) => {
+      // fe80::/10, and v4 addresses mapped to v6
+      ip.is_loopback()
+        || ip.is_unspecified()
+        || (ip.segments()[0] & 0xffc0) == 0xfe80
+        || ip.to_ipv4_mapped().is_some_and(|v4| is_private_address(IpAddr::V4(v4)))
+    }
+  }
+}
+
+/// Resolves hosts for deliveries, dropping loopback and link-local addresses. The host
+/// is resolved again on every send, so a name that passed [`validate_url`] can not be
+/// rebound to the server itself later.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+  fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+    Box::pin(async move {
+      let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+        .await?
+        .filter(|a| ALLOW_PRIVATE_TARGETS || !is_private_address(a.ip()))
+        .collect();
+      if addrs.is_empty() {
+        return Err(format!("{} has no public address", name.as_str()).into());
+      }
+      let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+      Ok(addrs)
+    })
+  }
+}
+
+/// The ip of a url whose host is an ip literal, such hosts never reach the resolver.
+fn literal_ip(url: &reqwest::Url) -> Option<IpAddr> {
+  let host = url.host_str()?;
+  host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Webhook urls must be http or https and must not resolve to a loopback or
+/// link-local address, e.g. the server itself or a cloud metadata service.
+pub async fn validate_url(url: &str) -> Result<(), AppError> {
+  let invalid = |msg: &str| AppError::new(msg).with_status(StatusCode::BAD_REQUEST);
+  let url = reqwest::Url::parse(url).map_err(|_| invalid("invalid webhook url"))?;
+  if url.scheme() != "http" && url.scheme() != "https" {
+    return Err(invalid("webhook url must be http or https"));
+  }
+  let host = url.host_str().ok_or_else(|| invalid("webhook url has no host"))?;
+  let port = url.port_or_known_default().unwrap_or(80);
+  let addrs: Vec<IpAddr> = match literal_ip(&url) {
+    Some(ip) => vec![ip],
+    None => tokio::net::lookup_host((host, port))
+      .await
+      .map_err(|_| invalid("can not resolve the webhook host"))?
+      .map(|a| a.ip())
+      .collect(),
+  };
+  if addrs.iter().any(|ip| is_private_address(*ip)) {
+    return Err(invalid("webhook url must not point to a loopback or link-local address"));
+  }
+  Ok(())
+}
+
+async fn send_once(
+  hook: &Webhook,
+  event: &str,
+  delivery_id: &str,
+  body: &str,
+) -> Result<u16, AppError> {
+  let url = reqwest::Url::parse(&hook.url).map_err(|_| AppError::new("invalid webhook url"))?;
+  if literal_ip(&url).is_some_and(is_private_address) && !ALLOW_PRIVATE_TARGETS {
+    return Err(AppError::new("webhook url points to a loopback or link-local address"));
+  }
+  let signature = sign(&hook.secret, body);
+  let resp = HTTP_CLIENT
+    .post(url)
+    .header("Content-Type", "application/json")
+    .header("X-Filego-Event", event)
+    .header("X-Filego-Delivery", delivery_id)
+    .header("X-Filego-Signature", format!("sha256={signature}"))
+    .body(body.to_owned())
+    .send()
+    .await?;
+  Ok(resp.status().as_u16())
+}
+
+/// Post the payload, retrying with exponential backoff on network errors,
+/// 5xx and 429 responses. Returns the delivery record to be logged.
+pub async fn deliver(
+  hook: &Webhook,
+  event: &str,
+  delivery_id: &str,
+  body: String,
+  max_attempts: i32,
+) -> NewWebhookDelivery {
+  let mut attempts = 0;
+  let mut status_code = None;
+  let mut error = None;
+  let mut success = false;
+  let mut backoff = Duration::from_secs(INITIAL_BACKOFF_SECS);
+  while attempts < max_attempts {
+    attempts += 1;
+    let retryable = match send_once(hook, event, delivery_id, &body).await {
+      Ok(status) => {
+        status_code = Some(status as i32);
+        if (200..300).contains(&status) {
+          success = true;
+          error = None;
+          break;
+        }
+        error = Some(format!("unexpected http status {status}"));
+        status >= 500 || status == 429
+      }
+      Err(e) => {
+        error = Some(e.to_string());
+        true
+      }
+    };
+    if !retryable || attempts >= max_attempts {
+      break;
+    }
+    tokio::time::sleep(backoff).await;
+    backoff *= 2;
+  }
+  NewWebhookDelivery {
+    webhook_id: hook.id,
+    event: event.to_owned(),
+    payload: body,
+    status_code,
+    success,
+    attempts,
+    error,
+    created_at: now_millis().to_string(),
+  }
+}
+
+pub async fn save_delivery(record: NewWebhookDelivery) -> Result<(), AppError> {
+  block(move || -> Result<(), AppError> {
+    use crate::schema::webhook_deliveries::table;
+    use diesel::prelude::*;
+    let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+    diesel::insert_into(table).values(record).execute(conn)?;
+    Ok(())
+  })
+  .await?
+}
+
+pub fn list_webhooks(username_: &str) -> Result<Vec<Webhook>, AppError> {
+  use crate::schema::webhooks::dsl::*;
+  use diesel::prelude::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = webhooks
+    .filter(username.eq(username_))
+    .order(id.asc())
+    .load::<Webhook>(conn)?;
+  Ok(r)
+}
+
+pub fn get_webhook(username_: &str, id_: i32) -> Result<Webhook, AppError> {
+  use crate::schema::webhooks::dsl::*;
+  use diesel::prelude::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = webhooks
+    .filter(username.eq(username_).and(id.eq(id_)))
+    .first::<Webhook>(conn)
+    .optional()?;
+  r.ok_or(AppError::new("webhook not found").with_status(StatusCode::NOT_FOUND))
+}
+
+pub fn create_webhook(hook: NewWebhook) -> Result<Webhook, AppError> {
+  use crate::schema::webhooks::table;
+  use diesel::prelude::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = diesel::insert_into(table)
+    .values(hook)
+    .get_result::<Webhook>(conn)?;
+  invalidate_hooks();
+  Ok(r)
+}
+
+pub fn delete_webhook(username_: &str, id_: i32) -> Result<(), AppError> {
+  use crate::schema::webhook_deliveries::dsl as deliveries;
+  use crate::schema::webhooks::dsl::*;
+  use diesel::prelude::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let effect = diesel::delete(webhooks.filter(username.eq(username_).and(id.eq(id_)))).execute(conn)?;
+  if effect == 0 {
+    return Err(AppError::new("webhook not found").with_status(StatusCode::NOT_FOUND));
+  }
+  diesel::delete(deliveries::webhook_deliveries.filter(deliveries::webhook_id.eq(id_)))
+    .execute(conn)?;
+  invalidate_hooks();
+  Ok(())
+}
+
+pub fn list_deliveries(webhook_id_: i32, limit: i64) -> Result<Vec<WebhookDelivery>, AppError> {
+  use crate::schema::webhook_deliveries::dsl::*;
+  use diesel::prelude::*;
+  let conn = &mut *SHARED_DB_CONN.lock().unwrap();
+  let r = webhook_deliveries
+    .filter(webhook_id.eq(webhook_id_))
+    .order(id.desc())
+    .limit(limit)
+    .load::<WebhookDelivery>(conn)?;
+  Ok(r)
+}
+
+/// Send a single `ping` delivery without retries, the result is logged like any other delivery.
+pub async fn test_delivery(hook: &Webhook) -> Result<NewWebhookDelivery, AppError> {
+  let payload = WebhookPayload {
+    delivery_id: uuid::Uuid::new_v4().to_string(),
+    webhook_id: hook.id,
+    event: "ping",
+    files: vec![],
+    timestamp: now_millis(),
+  };
+  let body = serde_json::to_string(&payload).map_err(|e| AppError::new(&e.to_string()))?;
+  let record = deliver(hook, "ping", &payload.delivery_id, body, 1).await;
+  save_delivery(record.clone()).await?;
+  Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
+
+  struct Received {
+    headers: HashMap<String, String>,
+    body: String,
+  }
+
+  /// Answer one request per status in `statuses`, recording what was posted.
+  async fn stub_receiver(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Received>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let received = Arc::new(Mutex::new(vec![]));
+    let log = received.clone();
+    tokio::spawn(async move {
+      for status in statuses {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 4096];
+        let request = loop {
+          let n = socket.read(&mut buf).await.unwrap();
+          assert!(n > 0, "connection closed before the request was complete");
+          data.extend_from_slice(&buf[..n]);
+          let text = String::from_utf8_lossy(&data).to_string();
+          let Some((head, body)) = text.split_once("\r\n\r\n") else {
+            continue;
+          };
+          let headers: HashMap<String, String> = head
+            .lines()
+            .skip(1)
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_owned()))
+            .collect();
+          let len: usize = headers.get("content-length").map_or(0, |l| l.parse().unwrap());
+          if body.len() >= len {
+            break Received {
+              body: body[..len].to_owned(),
+              headers,
+            };
+          }
+        };
+        log.lock().unwrap().push(request);
+        let resp = format!("HTTP/1.1 {status} Stub\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+        socket.write_all(resp.as_bytes()).await.unwrap();
+      }
+    });
+    (url, received)
+  }
+
+  fn test_hook(url: &str) -> Webhook {
+    Webhook {
+      id: 1,
+      username: "test".to_owned(),
+      url: url.to_owned(),
+      path_prefix: "".to_owned(),
+      event_types: "".to_owned(),
+      secret: "s3cret".to_owned(),
+      created_at: "0".to_owned(),
+    }
+  }
+
+  #[tokio::test]
+  async fn signs_and_retries_until_delivered() {
+    let (url, received) = stub_receiver(vec![503, 200]).await;
+    let body = r#"{"event":"create","files":["a.txt"]}"#.to_owned();
+    let record = deliver(&test_hook(&url), "create", "d1", body.clone(), 3).await;
+    assert!(record.success);
+    assert_eq!(record.attempts, 2);
+    assert_eq!(record.status_code, Some(200));
+    assert_eq!(record.error, None);
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 2);
+    for r in received.iter() {
+      assert_eq!(r.body, body);
+      assert_eq!(r.headers["x-filego-event"], "create");
+      assert_eq!(r.headers["x-filego-delivery"], "d1");
+      let signature = r.headers["x-filego-signature"].strip_prefix("sha256=").unwrap();
+      let mut mac = HmacSha256::new_from_slice(b"s3cret").unwrap();
+      mac.update(r.body.as_bytes());
+      mac.verify_slice(&hex::decode(signature).unwrap()).unwrap();
+    }
+  }
+
+  #[tokio::test]
+  async fn does_not_retry_client_errors() {
+    let (url, received) = stub_receiver(vec![400]).await;
+    let record = deliver(&test_hook(&url), "delete", "d2", "{}".to_owned(), 3).await;
+    assert!(!record.success);
+    assert_eq!(record.attempts, 1);
+    assert_eq!(record.status_code, Some(400));
+    assert_eq!(received.lock().unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn gives_up_after_max_attempts() {
+    let (url, received) = stub_receiver(vec![500, 500]).await;
+    let record = deliver(&test_hook(&url), "modify", "d3", "{}".to_owned(), 2).await;
+    assert!(!record.success);
+    assert_eq!(record.attempts, 2);
+    assert_eq!(record.status_code, Some(500));
+    assert!(record.error.is_some());
+    assert_eq!(received.lock().unwrap().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn rejects_internal_urls() {
+    for url in [
+      "http://127.0.0.1:8080/hook",
+      "http://localhost/hook",
+      "http://[::1]/hook",
+      "http://169.254.169.254/latest/meta-data",
+      "http://[fe80::1]/hook",
+      "http://[::ffff:127.0.0.1]/hook",
+      "http://0.0.0.0/hook",
+      "ftp://example.com/hook",
+    ] {
+      assert!(validate_url(url).await.is_err(), "{url} should be rejected");
+    }
+    assert!(validate_url("https://93.184.216.34/hook").await.is_ok());
+  }
+
+  #[test]
+  fn reads_literal_hosts() {
+    let ip = |url: &str| literal_ip(&reqwest::Url::parse(url).unwrap());
+    assert_eq!(ip("http://127.0.0.1:8080/hook"), Some("127.0.0.1".parse().unwrap()));
+    assert_eq!(ip("http://[fe80::1]/hook"), Some("fe80::1".parse().unwrap()));
+    assert_eq!(ip("http://example.com/hook"), None);
+  }
+}